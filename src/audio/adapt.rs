@@ -0,0 +1,174 @@
+//! Congestion control for the outgoing stream, driven by the receiver reports
+//! peers send back. Sustained loss first trades bitrate for in-band FEC, then
+//! moves to longer frames (fewer packets, less header overhead); clean reports
+//! walk the changes back one step at a time.
+
+use crate::audio::codec::{EncoderControl, FRAME_DURATIONS_MS};
+use crate::network::report::{REPORT_INTERVAL, ReceiverReport};
+use log::info;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Loss at or above this is congestion (5%).
+const LOSS_HIGH: f32 = 0.05;
+/// Loss below this counts as a clean report (1%).
+const LOSS_LOW: f32 = 0.01;
+/// Consecutive lossy report intervals before backing off.
+const BACKOFF_REPORTS: u32 = 2;
+/// Consecutive clean report intervals before stepping back up.
+const RECOVER_REPORTS: u32 = 5;
+/// Adaptation never goes below this bitrate on its own.
+const ADAPT_MIN_BITRATE: i32 = 12_000;
+/// Reports older than this no longer count (peer stopped listening).
+const REPORT_TIMEOUT_INTERVALS: u32 = 5;
+
+#[derive(Default)]
+struct AdaptState {
+    reports: HashMap<SocketAddr, (ReceiverReport, Instant)>,
+    /// When the streaks last advanced; once per report interval however
+    /// many peers report.
+    evaluated: Option<Instant>,
+    /// Consecutive report intervals in which the worst listener was lossy.
+    lossy_streak: u32,
+    /// Consecutive report intervals in which every listener was clean.
+    clean_streak: u32,
+}
+
+/// Adjusts the shared [`EncoderControl`] from receiver reports.
+pub struct RateController {
+    enabled: bool,
+    control: Arc<EncoderControl>,
+    /// Frame duration from the config; lengthened frames return to it.
+    base_frame_ms: f32,
+    state: Mutex<AdaptState>,
+}
+
+impl RateController {
+    pub fn new(enabled: bool, control: Arc<EncoderControl>, base_frame_ms: f32) -> Self {
+        Self {
+            enabled,
+            control,
+            base_frame_ms,
+            state: Mutex::new(AdaptState::default()),
+        }
+    }
+
+    /// Latest report from each peer that still reports.
+    pub fn reports(&self) -> Vec<(SocketAddr, ReceiverReport)> {
+        self.state
+            .lock()
+            .unwrap()
+            .reports
+            .iter()
+            .filter(|(_, (_, at))| at.elapsed() < REPORT_INTERVAL * REPORT_TIMEOUT_INTERVALS)
+            .map(|(peer, (report, _))| (*peer, *report))
+            .collect()
+    }
+
+    pub fn on_report(&self, from: SocketAddr, report: ReceiverReport) {
+        let mut state = self.state.lock().unwrap();
+        state.reports.insert(from, (report, Instant::now()));
+        if !self.enabled {
+            return;
+        }
+        // Peers report out of step with each other: allow a little early
+        if state
+            .evaluated
+            .is_some_and(|at| at.elapsed() < REPORT_INTERVAL * 9 / 10)
+        {
+            return;
+        }
+        let previous = state.evaluated.replace(Instant::now());
+
+        // Streaks count consecutive intervals: a silent one breaks them
+        if previous.is_some_and(|at| at.elapsed() >= REPORT_INTERVAL * 2) {
+            state.lossy_streak = 0;
+            state.clean_streak = 0;
+        }
+
+        // Adapt to the worst listener among this interval's reports; older
+        // ones were already counted
+        let worst = state
+            .reports
+            .values()
+            .filter(|(_, at)| match previous {
+                Some(previous) => *at > previous,
+                None => at.elapsed() < REPORT_INTERVAL * REPORT_TIMEOUT_INTERVALS,
+            })
+            .map(|(report, _)| report.loss())
+            .fold(0.0, f32::max);
+
+        if worst >= LOSS_HIGH {
+            state.lossy_streak += 1;
+            state.clean_streak = 0;
+        } else if worst < LOSS_LOW {
+            state.clean_streak += 1;
+            state.lossy_streak = 0;
+        } else {
+            state.lossy_streak = 0;
+            state.clean_streak = 0;
+        }
+
+        if state.lossy_streak >= BACKOFF_REPORTS {
+            state.lossy_streak = 0;
+            self.back_off(worst);
+        } else if state.clean_streak >= RECOVER_REPORTS {
+            state.clean_streak = 0;
+            self.recover();
+        }
+    }
+
+    fn back_off(&self, loss: f32) {
+        let target = self.control.target_bitrate();
+        let floor = ADAPT_MIN_BITRATE.min(target);
+
+        self.control.update(|settings| {
+            // Tell FEC how much loss to protect against
+            settings.fec_loss_perc = ((loss * 100.0).ceil() as i32).clamp(1, 100);
+
+            if settings.bitrate > floor {
+                settings.bitrate = (settings.bitrate * 3 / 4).max(floor);
+            } else if let Some(&longer) = FRAME_DURATIONS_MS
+                .iter()
+                .find(|&&ms| ms > settings.frame_ms)
+            {
+                settings.frame_ms = longer;
+            }
+
+            info!(
+                "Loss {:.1}% reported - backing off to {}",
+                loss * 100.0,
+                settings.describe()
+            );
+        });
+    }
+
+    fn recover(&self) {
+        let target = self.control.target_bitrate();
+        let base_frame_ms = self.base_frame_ms;
+
+        self.control.update(|settings| {
+            let before = *settings;
+
+            if settings.frame_ms > base_frame_ms {
+                settings.frame_ms = FRAME_DURATIONS_MS
+                    .iter()
+                    .rev()
+                    .copied()
+                    .find(|&ms| ms < settings.frame_ms)
+                    .unwrap_or(base_frame_ms)
+                    .max(base_frame_ms);
+            } else if settings.bitrate < target {
+                settings.bitrate = (settings.bitrate * 5 / 4).min(target);
+            } else {
+                settings.fec_loss_perc = 0;
+            }
+
+            if *settings != before {
+                info!("Link clean - recovering to {}", settings.describe());
+            }
+        });
+    }
+}
@@ -0,0 +1,277 @@
+use crate::audio::sad::OPUS_SAMPLE_RATE;
+use anyhow::{Context, Result, anyhow};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use log::error;
+use std::collections::VecDeque;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+pub type InputCallback = Box<dyn FnMut(&[f32]) + Send + 'static>;
+pub type OutputCallback = Box<dyn FnMut(&mut [f32]) + Send + 'static>;
+/// Called from the device's own thread when a running stream fails.
+pub type ErrorCallback = Box<dyn FnMut(String) + Send + 'static>;
+
+/// A running capture or playback stage. Dropping it stops the stream.
+pub trait AudioStream {
+    fn play(&self) -> Result<()>;
+}
+
+/// Where samples come from and go to. Both stages always run at the Opus
+/// rate (48kHz) with interleaved f32 samples.
+pub trait AudioBackend: Send + Sync {
+    fn input_channels(&self) -> Result<u16>;
+    fn output_channels(&self) -> Result<u16>;
+    fn build_input(
+        &self,
+        channels: u16,
+        callback: InputCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Box<dyn AudioStream>>;
+    fn build_output(
+        &self,
+        channels: u16,
+        callback: OutputCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Box<dyn AudioStream>>;
+}
+
+/// The system's default input/output devices through cpal.
+pub struct CpalBackend;
+
+struct CpalStream(cpal::Stream);
+
+impl AudioStream for CpalStream {
+    fn play(&self) -> Result<()> {
+        self.0.play().context("Failed to play cpal stream")
+    }
+}
+
+fn stream_config(channels: u16) -> cpal::StreamConfig {
+    // ✅ FIX: Force stream config to 48kHz
+    cpal::StreamConfig {
+        channels,
+        sample_rate: OPUS_SAMPLE_RATE,
+        buffer_size: cpal::BufferSize::Default,
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn input_channels(&self) -> Result<u16> {
+        let device = cpal::default_host()
+            .default_input_device()
+            .ok_or_else(|| anyhow!("No input device found"))?;
+        Ok(device.default_input_config()?.channels())
+    }
+
+    fn output_channels(&self) -> Result<u16> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| anyhow!("No output device found"))?;
+        Ok(device.default_output_config()?.channels())
+    }
+
+    fn build_input(
+        &self,
+        channels: u16,
+        mut callback: InputCallback,
+        mut on_error: ErrorCallback,
+    ) -> Result<Box<dyn AudioStream>> {
+        let device = cpal::default_host()
+            .default_input_device()
+            .ok_or_else(|| anyhow!("No input device found"))?;
+
+        let stream = device.build_input_stream(
+            &stream_config(channels),
+            move |input: &[f32], _| callback(input),
+            move |err| {
+                error!("Input stream error: {err}");
+                on_error(err.to_string());
+            },
+            None,
+        )?;
+
+        Ok(Box::new(CpalStream(stream)))
+    }
+
+    fn build_output(
+        &self,
+        channels: u16,
+        mut callback: OutputCallback,
+        mut on_error: ErrorCallback,
+    ) -> Result<Box<dyn AudioStream>> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| anyhow!("No output device found"))?;
+
+        let stream = device.build_output_stream(
+            &stream_config(channels),
+            move |output: &mut [f32], _| callback(output),
+            move |err| {
+                error!("Audio error: {err}");
+                on_error(err.to_string());
+            },
+            None,
+        )?;
+
+        Ok(Box::new(CpalStream(stream)))
+    }
+}
+
+/// Period of the virtual device clock, like a 10ms hardware buffer.
+const MEMORY_PERIOD: Duration = Duration::from_millis(10);
+/// Playback kept for [`MemoryBackend::output`] unless recording everything.
+const MEMORY_OUTPUT_KEPT: Duration = Duration::from_secs(10);
+
+/// Virtual sound card for machines without one (CI, tests, headless boxes).
+///
+/// Capture drains samples queued with [`MemoryBackend::push_input`] (silence
+/// once empty) and playback is kept for reading back with
+/// [`MemoryBackend::output`]: the last few seconds, or everything with
+/// [`MemoryBackend::with_recording`]. Both are clocked in real time so pacing
+/// behaves like a real device.
+pub struct MemoryBackend {
+    channels: u16,
+    input: Arc<Mutex<VecDeque<f32>>>,
+    output: Arc<Mutex<VecDeque<f32>>>,
+    /// Most output samples kept; `None` keeps all of them.
+    output_limit: Option<usize>,
+}
+
+impl MemoryBackend {
+    pub fn new(channels: u16) -> Self {
+        let kept = OPUS_SAMPLE_RATE as usize * MEMORY_OUTPUT_KEPT.as_secs() as usize;
+        Self {
+            channels,
+            input: Arc::new(Mutex::new(VecDeque::new())),
+            output: Arc::new(Mutex::new(VecDeque::new())),
+            output_limit: Some(kept * channels as usize),
+        }
+    }
+
+    /// Keep all playback, e.g. to write it to a WAV on exit. Memory grows
+    /// for as long as the stream runs.
+    pub fn with_recording(mut self) -> Self {
+        self.output_limit = None;
+        self
+    }
+
+    /// Queue interleaved samples to be "captured" by the input stage.
+    pub fn push_input(&self, samples: &[f32]) {
+        self.input.lock().unwrap().extend(samples.iter().copied());
+    }
+
+    /// What the output stage has played: everything when recording,
+    /// otherwise the most recent seconds.
+    pub fn output(&self) -> Vec<f32> {
+        self.output.lock().unwrap().iter().copied().collect()
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+}
+
+/// A thread standing in for a device clock, ticking once per period.
+struct MemoryStream {
+    started: Arc<AtomicBool>,
+    alive: Arc<AtomicBool>,
+}
+
+impl MemoryStream {
+    fn spawn(mut tick: impl FnMut() + Send + 'static) -> Self {
+        let started = Arc::new(AtomicBool::new(false));
+        let alive = Arc::new(AtomicBool::new(true));
+        let started_clone = started.clone();
+        let alive_clone = alive.clone();
+
+        spawn(move || {
+            let mut next_tick = Instant::now();
+            while alive_clone.load(Ordering::Relaxed) {
+                if started_clone.load(Ordering::Relaxed) {
+                    tick();
+                }
+
+                next_tick += MEMORY_PERIOD;
+                if let Some(wait) = next_tick.checked_duration_since(Instant::now()) {
+                    sleep(wait);
+                }
+            }
+        });
+
+        Self { started, alive }
+    }
+}
+
+impl AudioStream for MemoryStream {
+    fn play(&self) -> Result<()> {
+        self.started.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::Relaxed);
+    }
+}
+
+fn period_samples(channels: u16) -> usize {
+    (OPUS_SAMPLE_RATE as usize * MEMORY_PERIOD.as_millis() as usize / 1000) * channels as usize
+}
+
+impl AudioBackend for MemoryBackend {
+    fn input_channels(&self) -> Result<u16> {
+        Ok(self.channels)
+    }
+
+    fn output_channels(&self) -> Result<u16> {
+        Ok(self.channels)
+    }
+
+    fn build_input(
+        &self,
+        channels: u16,
+        mut callback: InputCallback,
+        _on_error: ErrorCallback,
+    ) -> Result<Box<dyn AudioStream>> {
+        let input = self.input.clone();
+        let mut period = vec![0f32; period_samples(channels)];
+
+        Ok(Box::new(MemoryStream::spawn(move || {
+            let mut queued = input.lock().unwrap();
+            for sample in period.iter_mut() {
+                *sample = queued.pop_front().unwrap_or(0.0);
+            }
+            drop(queued);
+
+            callback(&period);
+        })))
+    }
+
+    fn build_output(
+        &self,
+        channels: u16,
+        mut callback: OutputCallback,
+        _on_error: ErrorCallback,
+    ) -> Result<Box<dyn AudioStream>> {
+        let output = self.output.clone();
+        let limit = self.output_limit;
+        let mut period = vec![0f32; period_samples(channels)];
+
+        Ok(Box::new(MemoryStream::spawn(move || {
+            callback(&mut period);
+            let mut output = output.lock().unwrap();
+            output.extend(period.iter().copied());
+            if let Some(limit) = limit
+                && output.len() > limit
+            {
+                let excess = output.len() - limit;
+                output.drain(..excess);
+            }
+        })))
+    }
+}
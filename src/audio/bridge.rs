@@ -0,0 +1,199 @@
+//! Mixes audio for browser clients. Each browser hears everyone else - native
+//! peers, this instance's own mic and the other browsers - as one mono Opus
+//! stream on its own WebRTC track.
+
+use crate::audio::codec::{CodecConfig, frame_samples};
+use crate::audio::sad::OPUS_SAMPLE_RATE;
+use crate::network::protocol::AudioHeader;
+use crate::network::webrtc::WebRtcTransport;
+use log::{debug, warn};
+use opus::{Channels, Decoder, Encoder};
+use std::collections::{HashMap, VecDeque, hash_map::Entry};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+
+/// Browsers get 20ms mono frames regardless of our own codec settings.
+const BRIDGE_FRAME_MS: f32 = 20.0;
+/// Decoded audio kept per source; anything older is dropped.
+const MAX_QUEUED_MS: usize = 200;
+/// Sources silent this long lose their decoder.
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A decoded stream from one talker (peer, browser or ourselves).
+struct Source {
+    decoder: Decoder,
+    pcm: VecDeque<f32>,
+    last_heard: Instant,
+}
+
+/// A connected browser.
+struct Client {
+    name: String,
+    track: Arc<TrackLocalStaticSample>,
+    pc: Arc<RTCPeerConnection>,
+    encoder: Encoder,
+}
+
+pub struct BrowserBridge {
+    /// Key of this instance's own stream.
+    local: SocketAddr,
+    sources: Mutex<HashMap<SocketAddr, Source>>,
+    clients: Mutex<HashMap<SocketAddr, Client>>,
+}
+
+impl BrowserBridge {
+    pub fn new(local: SocketAddr) -> Arc<Self> {
+        Arc::new(Self {
+            local,
+            sources: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn has_clients(&self) -> bool {
+        !self.clients.lock().unwrap().is_empty()
+    }
+
+    /// A browser joined; `key` identifies its stream everywhere else.
+    pub fn add_client(
+        &self,
+        key: SocketAddr,
+        name: String,
+        track: Arc<TrackLocalStaticSample>,
+        pc: Arc<RTCPeerConnection>,
+    ) -> anyhow::Result<()> {
+        let encoder = CodecConfig::default().build_encoder(1)?;
+        self.clients.lock().unwrap().insert(
+            key,
+            Client {
+                name,
+                track,
+                pc,
+                encoder,
+            },
+        );
+        Ok(())
+    }
+
+    /// One Opus packet from this instance's own transmit path.
+    pub fn on_local(&self, header: &AudioHeader, payload: &[u8]) {
+        self.on_packet(self.local, header, payload);
+    }
+
+    /// One Opus packet from `from`; only decoded while browsers are listening.
+    pub fn on_packet(&self, from: SocketAddr, header: &AudioHeader, payload: &[u8]) {
+        if !self.has_clients() {
+            return;
+        }
+
+        let mut sources = self.sources.lock().unwrap();
+        let source = match sources.entry(from) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match Decoder::new(OPUS_SAMPLE_RATE, Channels::Mono) {
+                Ok(decoder) => entry.insert(Source {
+                    decoder,
+                    pcm: VecDeque::new(),
+                    last_heard: Instant::now(),
+                }),
+                Err(e) => {
+                    warn!("Failed to create bridge decoder: {e}");
+                    return;
+                }
+            },
+        };
+
+        let mut decoded = vec![0.0f32; header.frame_samples as usize];
+        match source.decoder.decode_float(payload, &mut decoded, false) {
+            Ok(samples) => source.pcm.extend(&decoded[..samples]),
+            Err(e) => debug!("Bridge decode error from {from}: {e}"),
+        }
+        source.last_heard = Instant::now();
+
+        let max = OPUS_SAMPLE_RATE as usize * MAX_QUEUED_MS / 1000;
+        let excess = source.pcm.len().saturating_sub(max);
+        source.pcm.drain(..excess);
+    }
+
+    /// Drop browsers whose connection died; returns their keys and names.
+    pub fn prune(&self, transport: &WebRtcTransport) -> Vec<(SocketAddr, String)> {
+        let mut gone = Vec::new();
+        self.clients.lock().unwrap().retain(|key, client| {
+            let alive = !matches!(
+                client.pc.connection_state(),
+                RTCPeerConnectionState::Disconnected
+                    | RTCPeerConnectionState::Failed
+                    | RTCPeerConnectionState::Closed
+            );
+            if !alive {
+                transport.hang_up(client.pc.clone());
+                gone.push((*key, client.name.clone()));
+            }
+            alive
+        });
+        gone
+    }
+
+    /// Mix and send one frame to every browser every 20ms.
+    pub fn start(self: &Arc<Self>, transport: Arc<WebRtcTransport>) {
+        let bridge = self.clone();
+        spawn(move || {
+            let frame_len = frame_samples(BRIDGE_FRAME_MS);
+            let frame_duration = Duration::from_secs_f32(BRIDGE_FRAME_MS / 1000.0);
+            let mut next_tick = Instant::now();
+            loop {
+                next_tick += frame_duration;
+                sleep(next_tick.saturating_duration_since(Instant::now()));
+                bridge.tick(&transport, frame_len, frame_duration);
+            }
+        });
+    }
+
+    fn tick(&self, transport: &WebRtcTransport, frame_len: usize, frame_duration: Duration) {
+        // One frame from every source that has audio queued
+        let frames: Vec<(SocketAddr, Vec<f32>)> = {
+            let mut sources = self.sources.lock().unwrap();
+            sources.retain(|_, source| source.last_heard.elapsed() < SOURCE_TIMEOUT);
+            sources
+                .iter_mut()
+                .filter(|(_, source)| !source.pcm.is_empty())
+                .map(|(key, source)| {
+                    let take = frame_len.min(source.pcm.len());
+                    let mut frame: Vec<f32> = source.pcm.drain(..take).collect();
+                    frame.resize(frame_len, 0.0);
+                    (*key, frame)
+                })
+                .collect()
+        };
+        if frames.is_empty() {
+            return;
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        for (key, client) in clients.iter_mut() {
+            // Nobody hears themselves
+            let mut talkers = frames.iter().filter(|(source, _)| source != key).peekable();
+            if talkers.peek().is_none() {
+                continue;
+            }
+
+            let mut mix = vec![0.0f32; frame_len];
+            for (_, frame) in talkers {
+                for (out, sample) in mix.iter_mut().zip(frame) {
+                    *out += sample;
+                }
+            }
+            mix.iter_mut().for_each(|s| *s = s.clamp(-1.0, 1.0));
+
+            let mut encoded = vec![0u8; 4000];
+            match client.encoder.encode_float(&mix, &mut encoded) {
+                Ok(len) => transport.write(&client.track, &encoded[..len], frame_duration),
+                Err(e) => debug!("Bridge encode error for {}: {e}", client.name),
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use crate::audio::sad::OPUS_SAMPLE_RATE;
+use anyhow::{Result, bail};
+use log::{info, warn};
+use opus::{Application, Bandwidth, Bitrate, Channels, Encoder};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Instant;
+
+/// Frame durations Opus can encode, in milliseconds.
+pub const FRAME_DURATIONS_MS: [f32; 6] = [2.5, 5.0, 10.0, 20.0, 40.0, 60.0];
+
+/// Opus bitrate limits (bits/second).
+pub const MIN_BITRATE: i32 = 6_000;
+pub const MAX_BITRATE: i32 = 510_000;
+/// Step used by the TUI bitrate keys.
+pub const BITRATE_STEP: i32 = 8_000;
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// Speech: VoIP tuning, follows the mic's channel count
+    #[default]
+    Voice,
+    /// Sharing music: full-band audio tuning in stereo
+    Music,
+}
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioBandwidth {
+    /// Let the encoder pick
+    #[default]
+    Auto,
+    /// 4 kHz
+    Nb,
+    /// 6 kHz
+    Mb,
+    /// 8 kHz
+    Wb,
+    /// 12 kHz
+    Swb,
+    /// 20 kHz
+    Fb,
+}
+
+impl From<AudioBandwidth> for Bandwidth {
+    fn from(bandwidth: AudioBandwidth) -> Self {
+        match bandwidth {
+            AudioBandwidth::Auto => Bandwidth::Auto,
+            AudioBandwidth::Nb => Bandwidth::Narrowband,
+            AudioBandwidth::Mb => Bandwidth::Mediumband,
+            AudioBandwidth::Wb => Bandwidth::Wideband,
+            AudioBandwidth::Swb => Bandwidth::Superwideband,
+            AudioBandwidth::Fb => Bandwidth::Fullband,
+        }
+    }
+}
+
+/// Opus encoder settings, from the `[codec]` config section and CLI flags.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CodecConfig {
+    pub profile: Profile,
+    /// Target bitrate in bits/second; `None` picks a default for the profile.
+    pub bitrate: Option<i32>,
+    /// 0 (fastest) to 10 (best quality).
+    pub complexity: i32,
+    /// Variable bitrate; constant bitrate when false.
+    pub vbr: bool,
+    /// Discontinuous transmission: send almost nothing during silence.
+    pub dtx: bool,
+    pub bandwidth: AudioBandwidth,
+    /// One of 2.5, 5, 10, 20, 40 or 60.
+    pub frame_ms: f32,
+    /// Adapt bitrate, FEC and frame length to the loss peers report.
+    pub adaptive: bool,
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        Self {
+            profile: Profile::Voice,
+            bitrate: None,
+            complexity: 9,
+            vbr: true,
+            dtx: false,
+            bandwidth: AudioBandwidth::Auto,
+            frame_ms: 20.0,
+            adaptive: true,
+        }
+    }
+}
+
+impl CodecConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !FRAME_DURATIONS_MS.contains(&self.frame_ms) {
+            bail!(
+                "Invalid frame duration {}ms (Opus supports {:?})",
+                self.frame_ms,
+                FRAME_DURATIONS_MS
+            );
+        }
+        if !(0..=10).contains(&self.complexity) {
+            bail!("Invalid complexity {} (0-10)", self.complexity);
+        }
+        if let Some(bitrate) = self.bitrate
+            && !(MIN_BITRATE..=MAX_BITRATE).contains(&bitrate)
+        {
+            bail!("Invalid bitrate {bitrate} ({MIN_BITRATE}-{MAX_BITRATE} bits/s)");
+        }
+        Ok(())
+    }
+
+    pub fn application(&self) -> Application {
+        match self.profile {
+            Profile::Voice => Application::Voip,
+            Profile::Music => Application::Audio,
+        }
+    }
+
+    /// Channels to encode, given the capture device's channel count.
+    pub fn encode_channels(&self, input_channels: usize) -> usize {
+        match self.profile {
+            Profile::Voice => input_channels.clamp(1, 2),
+            Profile::Music => 2,
+        }
+    }
+
+    pub fn default_bitrate(&self) -> i32 {
+        match self.profile {
+            Profile::Voice => 32_000,
+            Profile::Music => 128_000,
+        }
+    }
+
+    pub fn bitrate(&self) -> i32 {
+        self.bitrate.unwrap_or_else(|| self.default_bitrate())
+    }
+
+    pub fn build_encoder(&self, channels: usize) -> Result<Encoder> {
+        let opus_channels = if channels == 1 {
+            Channels::Mono
+        } else {
+            Channels::Stereo
+        };
+
+        let mut encoder = Encoder::new(OPUS_SAMPLE_RATE, opus_channels, self.application())?;
+        encoder.set_bitrate(Bitrate::Bits(self.bitrate()))?;
+        encoder.set_complexity(self.complexity)?;
+        encoder.set_vbr(self.vbr)?;
+        encoder.set_dtx(self.dtx)?;
+        encoder.set_bandwidth(self.bandwidth.into())?;
+
+        info!(
+            "Opus encoder: {:?} {}ch {}bps complexity={} vbr={} dtx={} bandwidth={:?} frame={}ms",
+            self.profile,
+            channels,
+            self.bitrate(),
+            self.complexity,
+            self.vbr,
+            self.dtx,
+            self.bandwidth,
+            self.frame_ms
+        );
+
+        Ok(encoder)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{:?} {} {} {}ms{}",
+            self.profile,
+            if self.vbr { "VBR" } else { "CBR" },
+            match self.bandwidth {
+                AudioBandwidth::Auto => "auto-bw".to_string(),
+                bw => format!("{bw:?}").to_uppercase(),
+            },
+            self.frame_ms,
+            if self.dtx { " DTX" } else { "" }
+        )
+    }
+}
+
+/// Samples per channel in one frame of `frame_ms`.
+pub fn frame_samples(frame_ms: f32) -> usize {
+    (OPUS_SAMPLE_RATE as f32 * frame_ms / 1000.0) as usize
+}
+
+/// Encoder parameters that may change while running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncoderSettings {
+    pub bitrate: i32,
+    /// Expected loss for in-band FEC, in percent; 0 turns FEC off.
+    pub fec_loss_perc: i32,
+    pub frame_ms: f32,
+}
+
+impl EncoderSettings {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} kbps {}ms{}",
+            self.bitrate / 1000,
+            self.frame_ms,
+            match self.fec_loss_perc {
+                0 => String::new(),
+                perc => format!(" FEC {perc}%"),
+            }
+        )
+    }
+}
+
+/// Sequence and timestamp of the outgoing stream.
+struct StreamClock {
+    sequence: u16,
+    next_timestamp: u32,
+}
+
+/// Live encoder settings and stream clock shared by every encoder (mic, file
+/// playback), adjusted from the TUI and by the rate controller.
+pub struct EncoderControl {
+    /// Bitrate asked for by the config or the user; adaptation stays below it.
+    target_bitrate: AtomicI32,
+    settings: Mutex<EncoderSettings>,
+    clock: Mutex<StreamClock>,
+    epoch: Instant,
+}
+
+impl EncoderControl {
+    pub fn new(codec: &CodecConfig) -> Self {
+        Self {
+            target_bitrate: AtomicI32::new(codec.bitrate()),
+            settings: Mutex::new(EncoderSettings {
+                bitrate: codec.bitrate(),
+                fec_loss_perc: 0,
+                frame_ms: codec.frame_ms,
+            }),
+            clock: Mutex::new(StreamClock {
+                sequence: 0,
+                next_timestamp: 0,
+            }),
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn settings(&self) -> EncoderSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    pub fn update(&self, update: impl FnOnce(&mut EncoderSettings)) {
+        update(&mut self.settings.lock().unwrap());
+    }
+
+    pub fn target_bitrate(&self) -> i32 {
+        self.target_bitrate.load(Ordering::Relaxed)
+    }
+
+    /// Change the target; the encoder jumps straight to it.
+    pub fn set_target_bitrate(&self, bitrate: i32) {
+        let bitrate = bitrate.clamp(MIN_BITRATE, MAX_BITRATE);
+        self.target_bitrate.store(bitrate, Ordering::Relaxed);
+        self.update(|settings| settings.bitrate = bitrate);
+    }
+
+    /// Push changed settings into `encoder`; `applied` tracks what it has.
+    pub fn apply(&self, encoder: &mut Encoder, applied: &mut EncoderSettings) {
+        let settings = self.settings();
+        if settings == *applied {
+            return;
+        }
+
+        if settings.bitrate != applied.bitrate {
+            match encoder.set_bitrate(Bitrate::Bits(settings.bitrate)) {
+                Ok(()) => applied.bitrate = settings.bitrate,
+                Err(e) => warn!("Failed to set bitrate {}: {e}", settings.bitrate),
+            }
+        }
+        if settings.fec_loss_perc != applied.fec_loss_perc {
+            let result = encoder
+                .set_inband_fec(settings.fec_loss_perc > 0)
+                .and_then(|()| encoder.set_packet_loss_perc(settings.fec_loss_perc));
+            match result {
+                Ok(()) => applied.fec_loss_perc = settings.fec_loss_perc,
+                Err(e) => warn!("Failed to set FEC {}%: {e}", settings.fec_loss_perc),
+            }
+        }
+        // Opus takes any valid frame size per call, nothing to configure
+        applied.frame_ms = settings.frame_ms;
+    }
+
+    /// Sequence number and timestamp for the next packet of `frame_samples`.
+    ///
+    /// Timestamps advance by one frame per packet while talking and jump to
+    /// the wall clock after a pause, like an RTP sampling clock.
+    pub fn next_packet(&self, frame_samples: usize) -> (u16, u32) {
+        let now = (self.epoch.elapsed().as_secs_f64() * OPUS_SAMPLE_RATE as f64) as u32;
+        let mut clock = self.clock.lock().unwrap();
+
+        let behind = now.wrapping_sub(clock.next_timestamp) as i32;
+        let timestamp = if behind > frame_samples as i32 {
+            now
+        } else {
+            clock.next_timestamp
+        };
+
+        let sequence = clock.sequence;
+        clock.sequence = clock.sequence.wrapping_add(1);
+        clock.next_timestamp = timestamp.wrapping_add(frame_samples as u32);
+        (sequence, timestamp)
+    }
+}
@@ -0,0 +1,161 @@
+use crate::audio::codec::CodecConfig;
+use crate::audio::sad::{OPUS_SAMPLE_RATE, OpusSender, StreamOutput, remix};
+use crate::network::floor::FloorControl;
+use crate::network::udp::NetSender;
+use anyhow::{Context, Result, bail};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use log::info;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use std::thread::{JoinHandle, sleep, spawn};
+use std::time::Instant;
+
+/// Decode a WAV file into mono f32 samples at the Opus rate (48kHz).
+pub fn load_wav(path: &Path) -> Result<Vec<f32>> {
+    let mut reader =
+        WavReader::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let spec = reader.spec();
+
+    let interleaved: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        SampleFormat::Int => {
+            // A malformed header must not reach the shift below
+            if !(1..=32).contains(&spec.bits_per_sample) {
+                bail!(
+                    "{}: unsupported {} bits per sample",
+                    path.display(),
+                    spec.bits_per_sample
+                );
+            }
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|v| v as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+
+    if interleaved.is_empty() {
+        bail!("{} contains no samples", path.display());
+    }
+
+    let mono = downmix(&interleaved, spec.channels as usize);
+    let samples = resample(&mono, spec.sample_rate, OPUS_SAMPLE_RATE);
+
+    info!(
+        "Loaded {}: {}Hz {}ch -> {} samples at {}Hz",
+        path.display(),
+        spec.sample_rate,
+        spec.channels,
+        samples.len(),
+        OPUS_SAMPLE_RATE
+    );
+
+    Ok(samples)
+}
+
+fn downmix(interleaved: &[f32], channels: usize) -> Vec<f32> {
+    if channels <= 1 {
+        return interleaved.to_vec();
+    }
+
+    interleaved
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect()
+}
+
+/// Linear interpolation resampler - good enough for speech and test tones.
+fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || samples.len() < 2 {
+        return samples.to_vec();
+    }
+
+    let ratio = from_rate as f64 / to_rate as f64;
+    let out_len = ((samples.len() as f64) / ratio).floor() as usize;
+
+    (0..out_len)
+        .map(|i| {
+            let pos = i as f64 * ratio;
+            let idx = pos.floor() as usize;
+            let frac = (pos - idx as f64) as f32;
+            let a = samples[idx];
+            let b = samples.get(idx + 1).copied().unwrap_or(a);
+            a + (b - a) * frac
+        })
+        .collect()
+}
+
+/// Transmit pre-decoded samples through the same encoder/sender path as the
+/// mic, one frame at a time paced against the wall clock.
+///
+/// `playing` is held high for the duration so the mic stays quiet.
+pub fn start_file_playback(
+    net: &NetSender,
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+    samples: Vec<f32>,
+    playing: Arc<AtomicBool>,
+    floor: Arc<FloorControl>,
+    codec: CodecConfig,
+    output: StreamOutput,
+) -> JoinHandle<()> {
+    let net = net.clone();
+
+    playing.store(true, Ordering::Relaxed);
+
+    spawn(move || {
+        let mut sender = OpusSender::new(&codec, codec.encode_channels(1), output);
+        let samples = remix(&samples, 1, sender.channels());
+        let mut position = 0;
+        let mut next_frame = Instant::now();
+
+        while position < samples.len() {
+            // Frame length can change between frames when the bitrate adapts
+            sender.refresh();
+            let frame_size = sender.frame_len();
+
+            // Opus needs full frames - pad the tail with silence
+            let end = (position + frame_size).min(samples.len());
+            let mut frame = samples[position..end].to_vec();
+            frame.resize(frame_size, 0.0);
+            position = end;
+
+            // Losing a simultaneous grab mutes the rest of the file
+            if floor.may_transmit() {
+                let peers_list = peers.lock().unwrap().clone();
+                sender.send_frame(&frame, &net, &peers_list);
+            }
+
+            next_frame += sender.frame_duration();
+            if let Some(wait) = next_frame.checked_duration_since(Instant::now()) {
+                sleep(wait);
+            }
+        }
+
+        playing.store(false, Ordering::Relaxed);
+        info!("File playback finished");
+    })
+}
+
+/// Write interleaved f32 samples at the Opus rate to a 16-bit WAV file.
+pub fn write_wav(path: &Path, samples: &[f32], channels: u16) -> Result<()> {
+    let spec = WavSpec {
+        channels,
+        sample_rate: OPUS_SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+
+    let mut writer = WavWriter::create(path, spec)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    for sample in samples {
+        writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+    }
+    writer.finalize()?;
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use crate::audio::volume::AtomicF32;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Samples at or above this magnitude count as clipping.
+const CLIP_THRESHOLD: f32 = 0.999;
+
+/// Floor of the dBFS scale used for display.
+pub const METER_FLOOR_DB: f32 = -60.0;
+
+pub type PeerLevels = Arc<Mutex<HashMap<SocketAddr, Arc<LevelMeter>>>>;
+
+/// Peak/RMS of the most recent audio block, written from the realtime
+/// callbacks and read by the UI without locking.
+#[derive(Default)]
+pub struct LevelMeter {
+    peak: AtomicF32,
+    rms: AtomicF32,
+    clipped: AtomicBool,
+}
+
+impl LevelMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&self, samples: &[f32]) {
+        if samples.is_empty() {
+            self.clear();
+            return;
+        }
+
+        let mut peak = 0f32;
+        let mut sum_sq = 0f32;
+        for &s in samples {
+            peak = peak.max(s.abs());
+            sum_sq += s * s;
+        }
+        let rms = (sum_sq / samples.len() as f32).sqrt();
+
+        self.peak.store(peak);
+        self.rms.store(rms);
+        if peak >= CLIP_THRESHOLD {
+            self.clipped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn clear(&self) {
+        self.peak.store(0.0);
+        self.rms.store(0.0);
+    }
+
+    pub fn peak(&self) -> f32 {
+        self.peak.load()
+    }
+
+    pub fn rms(&self) -> f32 {
+        self.rms.load()
+    }
+
+    /// Whether a clipped block was seen since the last call.
+    pub fn take_clipped(&self) -> bool {
+        self.clipped.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Linear amplitude to dBFS, clamped to the meter floor.
+pub fn to_dbfs(amplitude: f32) -> f32 {
+    if amplitude <= 0.0 {
+        return METER_FLOOR_DB;
+    }
+    (20.0 * amplitude.log10()).max(METER_FLOOR_DB)
+}
+
+/// Position of an amplitude on the meter scale, 0.0..=1.0.
+pub fn meter_fraction(amplitude: f32) -> f32 {
+    ((to_dbfs(amplitude) - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0)
+}
@@ -1,2 +1,11 @@
+pub mod adapt;
+pub mod backend;
+pub mod bridge;
+pub mod codec;
+pub mod file;
+pub mod meter;
 pub mod rad;
+pub mod replay;
+pub mod ring;
 pub mod sad;
+pub mod volume;
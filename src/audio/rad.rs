@@ -1,107 +1,347 @@
-use cpal::traits::{DeviceTrait, HostTrait};
+use crate::audio::backend::{AudioBackend, AudioStream};
+use crate::audio::meter::{LevelMeter, PeerLevels};
+use crate::audio::ring::{Producer, WORKER_POLL, ring};
+use crate::audio::sad::OPUS_SAMPLE_RATE;
+use crate::audio::volume::VolumeControls;
+use crate::event::{Event, EventBus, Stream};
+use crate::network::floor::{FloorControl, TALK_TIMEOUT};
+use crate::network::mdns::{PeerNames, peer_identity};
+use crate::network::stats::SessionStats;
+use crate::network::udp::AudioBuffer;
 use log::{error, info};
 use opus::{Channels, Decoder};
 use std::{
-    collections::VecDeque,
-    sync::{Arc, Mutex},
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
 };
 
-pub type AudioBuffer = Arc<Mutex<VecDeque<u8>>>;
-
-pub fn start_audio_output(buffer: AudioBuffer) -> cpal::Stream {
-    let host = cpal::default_host();
-    let device = host.default_output_device().unwrap();
-    let config = device.default_output_config().unwrap();
-    
-    let channels = config.channels() as usize;
-    
-    // ✅ FIX: Force 48kHz - Opus only supports 8k/12k/16k/24k/48k
-    let opus_sample_rate: u32 = 48000;
-    
-    info!("Output device: channels={}, forcing 48kHz for Opus", channels);
-
-    let opus_channels = if channels == 1 {
-        Channels::Mono
-    } else {
-        Channels::Stereo
-    };
-
-    let decoder = Arc::new(Mutex::new(
-        Decoder::new(opus_sample_rate, opus_channels)
-            .expect("Failed to create Opus decoder"),
-    ));
-
-    let pcm_buffer: Arc<Mutex<VecDeque<f32>>> = Arc::new(Mutex::new(VecDeque::new()));
-    let frame_count = Arc::new(Mutex::new(0usize));
-    let frame_count_clone = Arc::clone(&frame_count);
-
-    // ✅ FIX: Frame size based on Opus rate (48kHz), not device rate
-    let frame_size = (opus_sample_rate as usize / 1000) * 20 * channels;
-
-    // ✅ FIX: Force stream config to 48kHz
-    let stream_config = cpal::StreamConfig {
-        channels: config.channels(),
-        sample_rate: opus_sample_rate,
-        buffer_size: cpal::BufferSize::Default,
-    };
-
-    device
-        .build_output_stream(
-            &stream_config,
-            move |output: &mut [f32], _| {
-                let mut count = frame_count_clone.lock().unwrap();
-                *count += 1;
+/// Decoder state and decoded-but-unplayed PCM for one sender.
+struct PeerStream {
+    decoder: Decoder,
+    pcm: VecDeque<f32>,
+    meter: Arc<LevelMeter>,
+    last_sequence: Option<u16>,
+    /// Whether the last block was filled completely from this stream.
+    playing: bool,
+    /// When playback ran dry; audio arriving soon after means an underrun.
+    dry_since: Option<Instant>,
+    /// When the last packet arrived; quiet streams are dropped.
+    last_packet: Instant,
+}
 
-                {
-                    let mut opus_buf = buffer.lock().unwrap();
-                    let mut dec = decoder.lock().unwrap();
-                    let mut pcm = pcm_buffer.lock().unwrap();
-
-                    while opus_buf.len() >= 2 {
-                        let len = {
-                            let b0 = opus_buf[0] as u16;
-                            let b1 = opus_buf[1] as u16;
-                            (b0 | (b1 << 8)) as usize
-                        };
-
-                        if opus_buf.len() < 2 + len {
-                            break;
-                        }
-
-                        opus_buf.drain(..2);
-                        let packet: Vec<u8> = opus_buf.drain(..len).collect();
-
-                        let mut decoded = vec![0f32; frame_size];
-                        match dec.decode_float(&packet, &mut decoded, false) {
-                            Ok(samples) => {
-                                pcm.extend(decoded[..samples * channels].iter().copied());
-                            }
-                            Err(e) => error!("Opus decode error: {e}"),
-                        }
-                    }
+/// A stream that resumes within this long after running dry had a dropout,
+/// rather than a pause between talk spurts.
+const UNDERRUN_GAP: Duration = Duration::from_millis(200);
+
+/// Shared state the mixer reads and updates.
+pub struct OutputControls {
+    pub speaker_level: Arc<LevelMeter>,
+    pub peer_levels: PeerLevels,
+    pub peer_names: PeerNames,
+    pub volume: Arc<VolumeControls>,
+    pub floor: Arc<FloorControl>,
+    pub stats: Arc<SessionStats>,
+    pub events: EventBus,
+}
+
+/// Busy tone played while PTT is blocked by another peer holding the floor:
+/// 425 Hz, 0.5 s on / 0.5 s off.
+const BUSY_TONE_HZ: f32 = 425.0;
+const BUSY_TONE_CADENCE: usize = OPUS_SAMPLE_RATE as usize / 2;
+const BUSY_TONE_GAIN: f32 = 0.2;
+
+fn add_busy_tone(output: &mut [f32], channels: usize, position: &mut usize) {
+    for frame in output.chunks_mut(channels) {
+        if (*position / BUSY_TONE_CADENCE).is_multiple_of(2) {
+            let t = *position as f32 / OPUS_SAMPLE_RATE as f32;
+            let v = (t * BUSY_TONE_HZ * std::f32::consts::TAU).sin() * BUSY_TONE_GAIN;
+            for sample in frame.iter_mut() {
+                *sample += v;
+            }
+        }
+        *position += 1;
+    }
+}
+
+/// The mixer produces audio in blocks of this many frames (5ms).
+pub const MIX_BLOCK_FRAMES: usize = OPUS_SAMPLE_RATE as usize / 200;
+/// Mixed audio the ring can hold ahead of the device (200ms).
+const MIX_RING_FRAMES: usize = OPUS_SAMPLE_RATE as usize / 5;
+
+/// Start playback. The device callback only copies mixed audio out of a
+/// ring; decoding and mixing run on a worker thread that keeps the ring two
+/// device periods ahead of it.
+pub fn start_audio_output(
+    backend: &dyn AudioBackend,
+    buffer: AudioBuffer,
+    controls: OutputControls,
+) -> Box<dyn AudioStream> {
+    let output_channels = backend.output_channels().expect("No output device found");
+
+    let channels = output_channels as usize;
+
+    info!(
+        "Output device: channels={}, forcing 48kHz for Opus",
+        channels
+    );
+
+    let (mixed, mut playback) = ring(MIX_RING_FRAMES * channels);
+    let period = Arc::new(AtomicUsize::new(0));
+    let period_clone = period.clone();
+    let events = controls.events.clone();
+
+    let stream = backend
+        .build_output(
+            output_channels,
+            Box::new(move |output: &mut [f32]| {
+                let played = playback.pop_slice(output);
+                output[played..].fill(0.0);
+                period_clone.fetch_max(output.len(), Ordering::Relaxed);
+            }),
+            Box::new(move |message| {
+                events.publish(Event::DeviceError {
+                    stream: Stream::Output,
+                    message,
+                })
+            }),
+        )
+        .expect("Failed to build output stream");
+
+    spawn(move || run_mixer(buffer, controls, mixed, period, channels));
+    stream
+}
+
+/// Per-sender decoders and the mix of their output: the jitter buffer's
+/// consuming end. Shared by the playback worker and offline replay.
+pub struct Mixer {
+    channels: usize,
+    streams: HashMap<SocketAddr, PeerStream>,
+    peer_levels: PeerLevels,
+    peer_block: Vec<f32>,
+}
+
+impl Mixer {
+    pub fn new(channels: usize, peer_levels: PeerLevels) -> Self {
+        Self {
+            channels,
+            streams: HashMap::new(),
+            peer_levels,
+            peer_block: Vec::new(),
+        }
+    }
+
+    /// Decode everything that arrived in `buffer`, one decoder per sender.
+    /// `now` is the mixer's clock, for telling underruns from pauses.
+    pub fn decode(&mut self, buffer: &AudioBuffer, stats: &SessionStats, now: Instant) {
+        let channels = self.channels;
+        let opus_channels = if channels == 1 {
+            Channels::Mono
+        } else {
+            Channels::Stereo
+        };
+        let mut opus_bufs = buffer.lock().unwrap();
+
+        for (from, opus_buf) in opus_bufs.iter_mut() {
+            let stream = self.streams.entry(*from).or_insert_with(|| {
+                let meter = Arc::new(LevelMeter::new());
+                self.peer_levels
+                    .lock()
+                    .unwrap()
+                    .insert(*from, meter.clone());
+                PeerStream {
+                    decoder: Decoder::new(OPUS_SAMPLE_RATE, opus_channels)
+                        .expect("Failed to create Opus decoder"),
+                    pcm: VecDeque::new(),
+                    meter,
+                    last_sequence: None,
+                    playing: false,
+                    dry_since: None,
+                    last_packet: now,
                 }
+            });
+            if !opus_buf.is_empty() {
+                stream.last_packet = now;
+            }
+
+            if !opus_buf.is_empty()
+                && stream
+                    .dry_since
+                    .take()
+                    .is_some_and(|since| now.duration_since(since) < UNDERRUN_GAP)
+            {
+                stats.pipeline.underruns.fetch_add(1, Ordering::Relaxed);
+            }
 
-                let mut pcm = pcm_buffer.lock().unwrap();
-                let mut samples_written = 0;
+            while let Some(packet) = opus_buf.pop_front() {
+                // ✅ FIX: Decode buffer sized from the sender's header, not our own settings
+                let mut decoded = vec![0f32; packet.frame_samples * channels];
 
-                for sample in output.iter_mut() {
-                    if let Some(v) = pcm.pop_front() {
-                        *sample = v;
-                        samples_written += 1;
-                    } else {
-                        *sample = 0.0;
+                // A single lost packet is rebuilt from the FEC data carried in the next one
+                let lost_one = stream
+                    .last_sequence
+                    .is_some_and(|last| packet.sequence.wrapping_sub(last) == 2);
+                stream.last_sequence = Some(packet.sequence);
+                if lost_one
+                    && let Ok(samples) =
+                        stream
+                            .decoder
+                            .decode_float(&packet.payload, &mut decoded, true)
+                {
+                    stream
+                        .pcm
+                        .extend(decoded[..samples * channels].iter().copied());
+                }
+
+                match stream
+                    .decoder
+                    .decode_float(&packet.payload, &mut decoded, false)
+                {
+                    Ok(samples) => {
+                        stream
+                            .pcm
+                            .extend(decoded[..samples * channels].iter().copied());
+                    }
+                    Err(e) => {
+                        error!("Opus decode error from {from}: {e}");
+                        stats.pipeline.decode_errors.fetch_add(1, Ordering::Relaxed);
                     }
                 }
+            }
+        }
 
-                if *count % 100 == 0 {
-                    info!(
-                        "Output callback #{}: wrote {} samples, pcm buffer: {} samples",
-                        count, samples_written, pcm.len()
-                    );
+        // Senders that stopped talking, or left, give up their decoder and
+        // meter; the next talk spurt starts a fresh stream
+        let idle: Vec<SocketAddr> = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| {
+                stream.pcm.is_empty()
+                    && now.saturating_duration_since(stream.last_packet) > TALK_TIMEOUT
+            })
+            .map(|(from, _)| *from)
+            .collect();
+        if !idle.is_empty() {
+            let mut peer_levels = self.peer_levels.lock().unwrap();
+            for from in idle {
+                self.streams.remove(&from);
+                peer_levels.remove(&from);
+                if opus_bufs.get(&from).is_some_and(|queue| queue.is_empty()) {
+                    opus_bufs.remove(&from);
                 }
-            },
-            |err| error!("Audio error: {err}"),
-            None,
-        )
-        .unwrap()
-}
\ No newline at end of file
+            }
+        }
+    }
+
+    /// Mix the next block of every sender's decoded audio into `output`
+    /// (zeroed first), each scaled by `gain`; returns the most samples any
+    /// sender had.
+    pub fn mix(
+        &mut self,
+        output: &mut [f32],
+        now: Instant,
+        gain: impl Fn(&SocketAddr) -> f32,
+    ) -> usize {
+        output.fill(0.0);
+        self.peer_block.resize(output.len(), 0.0);
+        let mut samples_written = 0;
+
+        for (from, stream) in self.streams.iter_mut() {
+            let gain = gain(from);
+            let available = stream.pcm.len().min(output.len());
+            for (i, v) in stream.pcm.drain(..available).enumerate() {
+                self.peer_block[i] = v;
+                output[i] += v * gain;
+            }
+            // Per-peer meter shows the peer as received, before mute/volume
+            stream.meter.update(&self.peer_block[..available]);
+            if available == output.len() {
+                stream.playing = true;
+            } else if stream.playing {
+                stream.playing = false;
+                stream.dry_since = Some(now);
+            }
+            samples_written = samples_written.max(available);
+        }
+        samples_written
+    }
+
+    /// Decoded samples not yet mixed, over all senders.
+    pub fn pending(&self) -> usize {
+        self.streams.values().map(|s| s.pcm.len()).sum()
+    }
+}
+
+/// Worker side of playback: decode every sender's packets and mix them,
+/// with per-peer and master volume, into the ring the device plays from.
+fn run_mixer(
+    buffer: AudioBuffer,
+    controls: OutputControls,
+    mut mixed: Producer,
+    period: Arc<AtomicUsize>,
+    channels: usize,
+) {
+    let OutputControls {
+        speaker_level,
+        peer_levels,
+        peer_names,
+        volume,
+        floor,
+        stats,
+        ..
+    } = controls;
+
+    let mut mixer = Mixer::new(channels, peer_levels);
+    let mut busy_tone_position = 0usize;
+    let mut block_count = 0usize;
+    let mut output = vec![0f32; MIX_BLOCK_FRAMES * channels];
+
+    while !mixed.abandoned() {
+        sleep(WORKER_POLL);
+
+        // Stay two device periods ahead of the callback
+        let target = (2 * period.load(Ordering::Relaxed)).clamp(
+            output.len(),
+            (MIX_RING_FRAMES - MIX_BLOCK_FRAMES) * channels,
+        );
+        while mixed.queued() < target {
+            block_count += 1;
+            let now = Instant::now();
+
+            mixer.decode(&buffer, &stats, now);
+
+            // Mix all peers into the block
+            let names = peer_names.lock().unwrap().clone();
+            let samples_written = mixer.mix(&mut output, now, |from| {
+                volume.peer_gain(&peer_identity(&names, from))
+            });
+
+            if floor.busy() {
+                add_busy_tone(&mut output, channels, &mut busy_tone_position);
+            } else {
+                busy_tone_position = 0;
+            }
+
+            let master = volume.master.load();
+            for sample in output.iter_mut() {
+                *sample = (*sample * master).clamp(-1.0, 1.0);
+            }
+            speaker_level.update(&output);
+            mixed.push_slice(&output);
+
+            if block_count.is_multiple_of(200) {
+                info!(
+                    "Mixer block #{}: wrote {} samples, pcm buffer: {} samples",
+                    block_count,
+                    samples_written,
+                    mixer.pending()
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Offline replay of a debug capture (`vl replay`). The recorded datagrams
+//! go through the same jitter buffer, decoders and mixer as live audio,
+//! clocked by their arrival times instead of a sound card, so a field
+//! problem plays back identically every time.
+
+use crate::audio::rad::{MIX_BLOCK_FRAMES, Mixer};
+use crate::audio::sad::OPUS_SAMPLE_RATE;
+use crate::network::capture::{CaptureHeader, read_capture};
+use crate::network::protocol::{self, Packet};
+use crate::network::report::ReceptionStats;
+use crate::network::stats::{PeerStats, STATS_INTERVAL, SessionStats};
+use crate::network::udp::{AudioBuffer, push_opus_packet};
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// What happened while replaying; written next to the WAV.
+#[derive(Serialize, Debug)]
+pub struct ReplayReport {
+    pub capture: CaptureHeader,
+    /// Time from the capture's start to its last datagram.
+    pub duration_s: f32,
+    pub datagrams: u64,
+    pub audio_packets: u64,
+    pub control_messages: u64,
+    pub rtcp_packets: u64,
+    /// Datagrams the protocol decoder rejected.
+    pub unknown: u64,
+    /// Length of the produced audio.
+    pub output_s: f32,
+    pub underruns: u64,
+    pub decode_errors: u64,
+    pub peers: Vec<PeerStats>,
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Capture started {} on port {}, {:.1} s",
+            self.capture.started, self.capture.port, self.duration_s
+        )?;
+        writeln!(
+            f,
+            "Datagrams: {} ({} audio, {} control, {} RTCP, {} unknown)",
+            self.datagrams,
+            self.audio_packets,
+            self.control_messages,
+            self.rtcp_packets,
+            self.unknown
+        )?;
+        writeln!(
+            f,
+            "Output: {:.1} s, {} underruns, {} decode errors",
+            self.output_s, self.underruns, self.decode_errors
+        )?;
+        for peer in &self.peers {
+            writeln!(
+                f,
+                "  {}: {} packets, {} lost, {} late, {} duplicate, jitter {:.1} ms",
+                peer.peer,
+                peer.packets_received,
+                peer.lost,
+                peer.late,
+                peer.duplicates,
+                peer.jitter_ms
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Replay the capture at `path`, mixing every sender into `channels`
+/// interleaved channels at 48kHz. Returns the audio and the report.
+pub fn replay(path: &Path, channels: usize) -> Result<(Vec<f32>, ReplayReport)> {
+    let (header, records) = read_capture(path)?;
+
+    let buffer = AudioBuffer::default();
+    let stats = SessionStats::new();
+    let reception = ReceptionStats::new();
+    let mut mixer = Mixer::new(channels, Default::default());
+
+    let mut report = ReplayReport {
+        capture: header,
+        duration_s: records
+            .last()
+            .map_or(0.0, |record| record.at_us as f32 / 1_000_000.0),
+        datagrams: records.len() as u64,
+        audio_packets: 0,
+        control_messages: 0,
+        rtcp_packets: 0,
+        unknown: 0,
+        output_s: 0.0,
+        underruns: 0,
+        decode_errors: 0,
+        peers: Vec::new(),
+    };
+
+    // The mixer's clock: one block per step, as the device would pull them
+    let block_time = Duration::from_secs_f64(MIX_BLOCK_FRAMES as f64 / OPUS_SAMPLE_RATE as f64);
+    let epoch = Instant::now();
+    let mut clock = Duration::ZERO;
+    let mut next_tick = STATS_INTERVAL;
+    let mut block = vec![0f32; MIX_BLOCK_FRAMES * channels];
+    let mut samples = Vec::new();
+    let mut records = records.iter().peekable();
+
+    loop {
+        while let Some(record) =
+            records.next_if(|record| Duration::from_micros(record.at_us) <= clock)
+        {
+            let arrival = Duration::from_micros(record.at_us);
+            match protocol::decode(&record.datagram) {
+                Some(Packet::Audio {
+                    header, payload, ..
+                }) => {
+                    report.audio_packets += 1;
+                    push_opus_packet(&buffer, record.from, &header, payload);
+                    stats.on_received(record.from, header.sequence, payload.len());
+                    reception.on_packet_at(record.from, &header, arrival);
+                }
+                Some(Packet::Control(_)) => report.control_messages += 1,
+                Some(Packet::Rtcp(_)) => report.rtcp_packets += 1,
+                None => report.unknown += 1,
+            }
+        }
+
+        let now = epoch + clock;
+        mixer.decode(&buffer, &stats, now);
+        mixer.mix(&mut block, now, |_| 1.0);
+        samples.extend_from_slice(&block);
+        clock += block_time;
+
+        // Same per-second reports and history as a live session
+        if clock >= next_tick {
+            for (from, reception_report) in reception.take_reports() {
+                stats.on_report(from, &reception_report);
+            }
+            stats.tick_after(STATS_INTERVAL);
+            next_tick += STATS_INTERVAL;
+        }
+
+        if records.peek().is_none() && mixer.pending() == 0 {
+            break;
+        }
+    }
+
+    for (from, reception_report) in reception.take_reports() {
+        stats.on_report(from, &reception_report);
+    }
+    report.output_s = clock.as_secs_f32();
+    report.underruns = stats.pipeline.underruns.load(Ordering::Relaxed);
+    report.decode_errors = stats.pipeline.decode_errors.load(Ordering::Relaxed);
+    report.peers = stats.snapshot();
+
+    Ok((samples, report))
+}
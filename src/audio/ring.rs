@@ -0,0 +1,210 @@
+//! Wait-free single-producer/single-consumer sample ring: the only thing the
+//! realtime audio callbacks share with the worker threads. Neither side ever
+//! locks or allocates; a full ring drops what doesn't fit, an empty one
+//! yields nothing.
+
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// How often worker threads service their rings.
+pub const WORKER_POLL: Duration = Duration::from_millis(2);
+
+struct Shared {
+    slots: Box<[UnsafeCell<f32>]>,
+    /// Read position; only the consumer writes it.
+    head: AtomicUsize,
+    /// Write position; only the producer writes it.
+    tail: AtomicUsize,
+}
+
+impl Shared {
+    // Positions run modulo twice the capacity: a full ring is then told apart
+    // from an empty one, and they wrap without a jump in the slot index.
+    fn advance(&self, position: usize, count: usize) -> usize {
+        (position + count) % (2 * self.slots.len())
+    }
+
+    fn queued(&self, head: usize, tail: usize) -> usize {
+        (tail + 2 * self.slots.len() - head) % (2 * self.slots.len())
+    }
+}
+
+// Each slot is written by the producer only while it's outside
+// `head..tail`, and read by the consumer only while inside it.
+unsafe impl Sync for Shared {}
+
+/// A ring holding up to `capacity` samples.
+pub fn ring(capacity: usize) -> (Producer, Consumer) {
+    let shared = Arc::new(Shared {
+        slots: (0..capacity.max(1)).map(|_| UnsafeCell::new(0.0)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+impl Producer {
+    /// Samples waiting to be read.
+    pub fn queued(&self) -> usize {
+        let shared = &*self.shared;
+        shared.queued(
+            shared.head.load(Ordering::Acquire),
+            shared.tail.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Append as much of `samples` as fits; returns how many were taken.
+    pub fn push_slice(&mut self, samples: &[f32]) -> usize {
+        let shared = &*self.shared;
+        let capacity = shared.slots.len();
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let free = capacity - shared.queued(shared.head.load(Ordering::Acquire), tail);
+        let count = samples.len().min(free);
+
+        for (i, &sample) in samples[..count].iter().enumerate() {
+            let slot = &shared.slots[(tail + i) % capacity];
+            // SAFETY: the slot is free, the consumer won't read it before the store below
+            unsafe { *slot.get() = sample };
+        }
+        shared
+            .tail
+            .store(shared.advance(tail, count), Ordering::Release);
+        count
+    }
+
+    /// The consumer was dropped; nobody will read any more.
+    pub fn abandoned(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+pub struct Consumer {
+    shared: Arc<Shared>,
+}
+
+impl Consumer {
+    /// Largest number of samples the ring can hold.
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    /// Fill the start of `out` with the oldest samples; returns how many.
+    pub fn pop_slice(&mut self, out: &mut [f32]) -> usize {
+        let shared = &*self.shared;
+        let capacity = shared.slots.len();
+        let head = shared.head.load(Ordering::Relaxed);
+        let available = shared.queued(head, shared.tail.load(Ordering::Acquire));
+        let count = out.len().min(available);
+
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            let slot = &shared.slots[(head + i) % capacity];
+            // SAFETY: the slot was published by the producer's release store of `tail`
+            *sample = unsafe { *slot.get() };
+        }
+        shared
+            .head
+            .store(shared.advance(head, count), Ordering::Release);
+        count
+    }
+
+    /// The producer was dropped; nothing more will arrive.
+    pub fn abandoned(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::spawn;
+
+    #[test]
+    fn wraps_around_at_capacity() {
+        let (mut producer, mut consumer) = ring(4);
+        let mut out = [0.0; 4];
+        // Enough rounds to wrap both the slots and the positions several times
+        for round in 0..20 {
+            let samples = [round as f32, 0.5, 1.5];
+            assert_eq!(producer.push_slice(&samples), 3);
+            assert_eq!(producer.queued(), 3);
+            assert_eq!(consumer.pop_slice(&mut out), 3);
+            assert_eq!(out[..3], samples);
+            assert_eq!(producer.queued(), 0);
+        }
+    }
+
+    #[test]
+    fn full_ring_takes_only_what_fits() {
+        let (mut producer, mut consumer) = ring(4);
+        assert_eq!(producer.push_slice(&[1.0, 2.0, 3.0]), 3);
+        assert_eq!(producer.push_slice(&[4.0, 5.0, 6.0]), 1);
+        assert_eq!(producer.push_slice(&[7.0]), 0);
+        assert_eq!(producer.queued(), 4);
+
+        let mut out = [0.0; 8];
+        assert_eq!(consumer.pop_slice(&mut out), 4);
+        assert_eq!(out[..4], [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn empty_ring_yields_nothing() {
+        let (_producer, mut consumer) = ring(4);
+        let mut out = [9.0; 2];
+        assert_eq!(consumer.pop_slice(&mut out), 0);
+        assert_eq!(out, [9.0; 2]);
+    }
+
+    #[test]
+    fn each_side_sees_the_other_go() {
+        let (producer, consumer) = ring(4);
+        assert!(!producer.abandoned());
+        assert!(!consumer.abandoned());
+        drop(consumer);
+        assert!(producer.abandoned());
+
+        let (producer, consumer) = ring(4);
+        drop(producer);
+        assert!(consumer.abandoned());
+    }
+
+    #[test]
+    fn samples_cross_threads_in_order() {
+        const TOTAL: usize = 200_000;
+        let (mut producer, mut consumer) = ring(1000);
+
+        let writer = spawn(move || {
+            let mut block = [0.0; 37];
+            let mut next = 0;
+            while next < TOTAL {
+                let len = block.len().min(TOTAL - next);
+                for (i, sample) in block[..len].iter_mut().enumerate() {
+                    *sample = (next + i) as f32;
+                }
+                next += producer.push_slice(&block[..len]);
+            }
+        });
+
+        let mut out = [0.0; 53];
+        let mut expected = 0;
+        while expected < TOTAL {
+            let count = consumer.pop_slice(&mut out);
+            for &sample in &out[..count] {
+                // f32 holds every integer up to 2^24 exactly
+                assert_eq!(sample, expected as f32);
+                expected += 1;
+            }
+        }
+        writer.join().unwrap();
+    }
+}
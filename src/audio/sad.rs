@@ -1,89 +1,273 @@
-use crate::network::udp::udp_send_audio;
-use cpal::traits::{DeviceTrait, HostTrait};
+use crate::audio::backend::{AudioBackend, AudioStream};
+use crate::audio::bridge::BrowserBridge;
+use crate::audio::codec::{CodecConfig, EncoderControl, EncoderSettings, frame_samples};
+use crate::audio::meter::LevelMeter;
+use crate::audio::ring::{Consumer, WORKER_POLL, ring};
+use crate::audio::volume::VolumeControls;
+use crate::event::{Event, EventBus, Stream};
+use crate::network::floor::FloorControl;
+use crate::network::protocol::AudioHeader;
+use crate::network::rtp::RtpSession;
+use crate::network::stats::SessionStats;
+use crate::network::udp::NetSender;
+use crate::network::webrtc::WebRtcTransport;
 use log::info;
-use opus::{Application, Channels, Encoder};
-use std::net::{SocketAddr, UdpSocket};
+use opus::Encoder;
+use std::net::SocketAddr;
 use std::sync::{
     Arc, Mutex,
     atomic::{AtomicBool, Ordering},
 };
+use std::thread::{sleep, spawn};
+use std::time::Duration;
 
-pub fn start_mic_capture(
-    udp_socket: &UdpSocket,
-    peers: Arc<Mutex<Vec<SocketAddr>>>,
-    ptt_enabled: Arc<AtomicBool>,
-) -> cpal::Stream {
-    let host = cpal::default_host();
-    let device = host.default_input_device().expect("No input device found");
-    let config = device.default_input_config().unwrap();
-
-    let socket = udp_socket.try_clone().unwrap();
-
-    let channels = config.channels() as usize;
-
-    // ✅ FIX: Force 48kHz - must match decoder
-    let opus_sample_rate: u32 = 48000;
-
-    info!("Input device: channels={}, forcing 48kHz for Opus", channels);
-
-    let opus_channels = if channels == 1 {
-        Channels::Mono
-    } else {
-        Channels::Stereo
-    };
-
-    let encoder = Arc::new(Mutex::new(
-        Encoder::new(opus_sample_rate, opus_channels, Application::Voip)
-            .expect("Failed to create Opus encoder"),
-    ));
-
-    let sample_buffer: Arc<Mutex<Vec<f32>>> = Arc::new(Mutex::new(Vec::new()));
-
-    // ✅ FIX: Frame size based on Opus rate (48kHz)
-    let frame_size = (opus_sample_rate as usize / 1000) * 20 * channels;
-
-    // ✅ FIX: Force stream config to 48kHz
-    let stream_config = cpal::StreamConfig {
-        channels: config.channels(),
-        sample_rate: opus_sample_rate,
-        buffer_size: cpal::BufferSize::Default,
-    };
-
-    device
-        .build_input_stream(
-            &stream_config,
-            move |input: &[f32], _| {
-                if !ptt_enabled.load(Ordering::Relaxed) {
-                    return;
-                }
+// Opus only supports 8k/12k/16k/24k/48k - everything is encoded at 48kHz
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
 
-                let peers_list = peers.lock().unwrap().clone();
-                if peers_list.is_empty() {
-                    return;
+/// Convert interleaved audio between mono and stereo.
+pub fn remix(input: &[f32], from_channels: usize, to_channels: usize) -> Vec<f32> {
+    if from_channels == to_channels {
+        return input.to_vec();
+    }
+
+    input
+        .chunks(from_channels)
+        .flat_map(|frame| {
+            let mono = frame.iter().sum::<f32>() / frame.len() as f32;
+            // Downmix to mono, duplicate mono when widening, otherwise keep the first channels
+            (0..to_channels).map(move |c| {
+                if from_channels == 1 || to_channels == 1 {
+                    mono
+                } else {
+                    frame.get(c).copied().unwrap_or(mono)
                 }
+            })
+        })
+        .collect()
+}
+
+/// Where encoded frames go: the live encoder settings and stream clock plus
+/// the transports beyond plain UDP.
+#[derive(Clone)]
+pub struct StreamOutput {
+    pub encoder: Arc<EncoderControl>,
+    pub rtp: Arc<RtpSession>,
+    pub webrtc: Option<Arc<WebRtcTransport>>,
+    pub bridge: Option<Arc<BrowserBridge>>,
+    pub stats: Arc<SessionStats>,
+}
+
+impl StreamOutput {
+    /// Whether anyone would receive a frame sent to `peers`.
+    pub fn has_listeners(&self, peers: &[SocketAddr]) -> bool {
+        !peers.is_empty()
+            || self.rtp.forward().is_some()
+            || self
+                .bridge
+                .as_ref()
+                .is_some_and(|bridge| bridge.has_clients())
+    }
+}
+
+/// Opus encoder plus stream framing, shared by every transmit source (mic,
+/// file playback) so they all hit the wire the same way.
+pub struct OpusSender {
+    encoder: Encoder,
+    channels: usize,
+    output: StreamOutput,
+    applied: EncoderSettings,
+}
+
+impl OpusSender {
+    pub fn new(codec: &CodecConfig, channels: usize, output: StreamOutput) -> Self {
+        let mut sender = Self {
+            encoder: codec
+                .build_encoder(channels)
+                .expect("Failed to create Opus encoder"),
+            channels,
+            output,
+            applied: EncoderSettings {
+                bitrate: codec.bitrate(),
+                fec_loss_perc: 0,
+                frame_ms: codec.frame_ms,
+            },
+        };
+        // Pick up whatever the TUI or rate controller changed since startup
+        sender.refresh();
+        sender
+    }
 
-                let mut buffer = sample_buffer.lock().unwrap();
-                buffer.extend_from_slice(input);
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
 
-                while buffer.len() >= frame_size {
-                    let frame: Vec<f32> = buffer.drain(..frame_size).collect();
+    /// Apply live setting changes; call between frames, never mid-frame.
+    pub fn refresh(&mut self) {
+        self.output
+            .encoder
+            .apply(&mut self.encoder, &mut self.applied);
+    }
 
-                    let mut enc = encoder.lock().unwrap();
-                    let mut encoded = vec![0u8; 4000];
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f32(self.applied.frame_ms / 1000.0)
+    }
 
-                    match enc.encode_float(&frame, &mut encoded) {
-                        Ok(len) => {
-                            encoded.truncate(len);
-                            udp_send_audio(&socket, &encoded, &peers_list);
-                        }
-                        Err(e) => {
-                            info!("Opus encode error: {e}");
-                        }
+    /// Number of interleaved samples in one Opus frame.
+    pub fn frame_len(&self) -> usize {
+        frame_samples(self.applied.frame_ms) * self.channels
+    }
+
+    /// Encode one PCM frame of `frame_len()` samples and send it to every peer.
+    pub fn send_frame(&mut self, frame: &[f32], net: &NetSender, peers: &[SocketAddr]) {
+        let mut encoded = vec![0u8; 4000];
+
+        match self.encoder.encode_float(frame, &mut encoded) {
+            Ok(len) => {
+                encoded.truncate(len);
+                let frame_samples = frame.len() / self.channels;
+                let (sequence, timestamp) = self.output.encoder.next_packet(frame_samples);
+                let header = AudioHeader {
+                    channels: self.channels as u8,
+                    frame_samples: frame_samples as u16,
+                    sequence,
+                    timestamp,
+                };
+                if let Some(bridge) = &self.output.bridge {
+                    bridge.on_local(&header, &encoded);
+                }
+                for peer in peers {
+                    self.output.stats.on_sent(*peer, encoded.len());
+                }
+                match &self.output.webrtc {
+                    Some(webrtc) => {
+                        webrtc.send(&encoded, self.frame_duration());
+                        let udp_peers = webrtc.udp_peers(peers);
+                        net.send_audio(&self.output.rtp, &header, &encoded, &udp_peers);
                     }
+                    None => net.send_audio(&self.output.rtp, &header, &encoded, peers),
                 }
-            },
-            |err| info!("Stream error: {err}"),
-            None,
+            }
+            Err(e) => {
+                info!("Opus encode error: {e}");
+                self.output
+                    .stats
+                    .pipeline
+                    .encode_errors
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Shared state the capture worker consults on every block.
+pub struct MicControls {
+    pub ptt_enabled: Arc<AtomicBool>,
+    /// A file is being transmitted - don't interleave mic frames with it
+    pub file_playing: Arc<AtomicBool>,
+    pub level: Arc<LevelMeter>,
+    pub volume: Arc<VolumeControls>,
+    pub floor: Arc<FloorControl>,
+    pub codec: CodecConfig,
+    pub output: StreamOutput,
+    pub events: EventBus,
+}
+
+/// Captured audio the ring can hold before the encoder falls behind (500ms).
+const CAPTURE_RING_FRAMES: usize = OPUS_SAMPLE_RATE as usize / 2;
+
+/// Start capture. The device callback only copies samples into a ring;
+/// gain, metering, encoding and sending run on a worker thread.
+pub fn start_mic_capture(
+    backend: &dyn AudioBackend,
+    net: &NetSender,
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+    controls: MicControls,
+) -> Box<dyn AudioStream> {
+    let input_channels = backend.input_channels().expect("No input device found");
+
+    let net = net.clone();
+
+    let channels = input_channels as usize;
+    let encode_channels = controls.codec.encode_channels(channels);
+
+    info!(
+        "Input device: channels={}, encoding {} channel(s), forcing 48kHz for Opus",
+        channels, encode_channels
+    );
+
+    let (mut captured, pending) = ring(CAPTURE_RING_FRAMES * channels);
+    let events = controls.events.clone();
+
+    let stream = backend
+        .build_input(
+            input_channels,
+            Box::new(move |input: &[f32]| {
+                captured.push_slice(input);
+            }),
+            Box::new(move |message| {
+                events.publish(Event::DeviceError {
+                    stream: Stream::Input,
+                    message,
+                })
+            }),
         )
-        .unwrap()
-}
\ No newline at end of file
+        .expect("Failed to build input stream");
+
+    spawn(move || run_mic_encoder(pending, net, peers, controls, channels));
+    stream
+}
+
+/// Worker side of capture: everything the input callback used to do.
+fn run_mic_encoder(
+    mut pending: Consumer,
+    net: NetSender,
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+    controls: MicControls,
+    channels: usize,
+) {
+    let encode_channels = controls.codec.encode_channels(channels);
+    let mut sender = OpusSender::new(&controls.codec, encode_channels, controls.output.clone());
+
+    let mut sample_buffer: Vec<f32> = Vec::new();
+    let mut block = vec![0f32; pending.capacity()];
+
+    while !pending.abandoned() {
+        sleep(WORKER_POLL);
+
+        let len = pending.pop_slice(&mut block);
+        if len == 0 {
+            continue;
+        }
+        let input = &mut block[..len];
+        let gain = controls.volume.mic_gain.load();
+        for sample in input.iter_mut() {
+            *sample *= gain;
+        }
+
+        // Meter the mic even in standby so levels can be checked before talking
+        controls.level.update(input);
+
+        if !controls.ptt_enabled.load(Ordering::Relaxed)
+            || controls.volume.mic_muted.load(Ordering::Relaxed)
+            || controls.file_playing.load(Ordering::Relaxed)
+            || !controls.floor.may_transmit()
+        {
+            continue;
+        }
+
+        let peers_list = peers.lock().unwrap().clone();
+        if !controls.output.has_listeners(&peers_list) {
+            continue;
+        }
+
+        sample_buffer.extend(remix(input, channels, encode_channels));
+
+        // ✅ FIX: Frame size based on Opus rate (48kHz) and the current frame duration
+        sender.refresh();
+        let frame_size = sender.frame_len();
+        while sample_buffer.len() >= frame_size {
+            let frame: Vec<f32> = sample_buffer.drain(..frame_size).collect();
+            sender.send_frame(&frame, &net, &peers_list);
+        }
+    }
+}
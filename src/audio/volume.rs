@@ -0,0 +1,100 @@
+use crate::config::{AudioConfig, Config, PeerConfig};
+use std::collections::BTreeMap;
+use std::sync::{
+    Mutex,
+    atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+/// Upper bound for every gain control (+6 dB).
+pub const MAX_GAIN: f32 = 2.0;
+/// Step used by the TUI volume keys.
+pub const GAIN_STEP: f32 = 0.1;
+
+/// An f32 stored as bits so the realtime callbacks can read it without locking.
+#[derive(Default)]
+pub struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    pub fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    pub fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+pub fn clamp_gain(value: f32) -> f32 {
+    // Round to the key step so repeated +/- doesn't drift
+    ((value / GAIN_STEP).round() * GAIN_STEP).clamp(0.0, MAX_GAIN)
+}
+
+/// Master output volume, mic gain/mute and per-peer volume/mute/solo.
+///
+/// Peer settings are keyed by identity (mDNS instance name) rather than
+/// address so they survive a peer restarting on another port.
+pub struct VolumeControls {
+    pub master: AtomicF32,
+    pub mic_gain: AtomicF32,
+    pub mic_muted: AtomicBool,
+    peers: Mutex<BTreeMap<String, PeerConfig>>,
+}
+
+impl VolumeControls {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            master: AtomicF32::new(config.audio.master_volume),
+            mic_gain: AtomicF32::new(config.audio.mic_gain),
+            mic_muted: AtomicBool::new(config.audio.mic_muted),
+            peers: Mutex::new(config.peers.clone()),
+        }
+    }
+
+    pub fn peer(&self, identity: &str) -> PeerConfig {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(identity)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn update_peer(&self, identity: &str, update: impl FnOnce(&mut PeerConfig)) {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(identity.to_string()).or_default();
+        update(peer);
+        peer.volume = clamp_gain(peer.volume);
+
+        // Nothing worth remembering
+        if *peer == PeerConfig::default() {
+            peers.remove(identity);
+        }
+    }
+
+    /// Effective gain for a peer, honouring mute and solo.
+    pub fn peer_gain(&self, identity: &str) -> f32 {
+        let peers = self.peers.lock().unwrap();
+        let any_solo = peers.values().any(|p| p.solo);
+
+        match peers.get(identity) {
+            Some(peer) if peer.muted || (any_solo && !peer.solo) => 0.0,
+            Some(peer) => peer.volume,
+            None if any_solo => 0.0,
+            None => 1.0,
+        }
+    }
+
+    /// Copy the current settings into `config` for saving.
+    pub fn write_to(&self, config: &mut Config) {
+        config.audio = AudioConfig {
+            master_volume: self.master.load(),
+            mic_gain: self.mic_gain.load(),
+            mic_muted: self.mic_muted.load(Ordering::Relaxed),
+        };
+        config.peers = self.peers.lock().unwrap().clone();
+    }
+}
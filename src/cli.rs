@@ -0,0 +1,324 @@
+use chrono::{NaiveDate, NaiveTime};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use videolan::audio::codec::{AudioBandwidth, CodecConfig, Profile};
+use videolan::network::impair::{Impairment, Impairments};
+use videolan::network::mdns::DEFAULT_CHANNEL;
+use videolan::ui::tui::PttMode;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "vl",
+    about = "Walkie-talkie over the local network",
+    subcommand_negates_reqs = true
+)]
+pub struct Cli {
+    /// Name announced to peers over mDNS
+    #[arg(required = true)]
+    pub instance_name: Option<String>,
+
+    /// UDP port to listen on
+    #[arg(required = true)]
+    pub port: Option<u16>,
+
+    /// Channel to join; only peers on the same channel are discovered
+    #[arg(long, default_value = DEFAULT_CHANNEL)]
+    pub channel: String,
+
+    /// Where received files are saved (defaults to ~/.local/share/vl/received)
+    #[arg(long, value_name = "DIR")]
+    pub download_dir: Option<PathBuf>,
+
+    /// Config file (defaults to ~/.config/vl/config.toml)
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Half-duplex walkie-talkie mode: one talker at a time, others get a busy tone
+    #[arg(long)]
+    pub floor_control: bool,
+
+    /// How SPACE drives push-to-talk
+    #[arg(long, value_enum, default_value_t = PttMode::Auto)]
+    pub ptt_mode: PttMode,
+
+    /// Global PTT key read from /dev/input, e.g. KEY_RIGHTCTRL or BTN_SIDE
+    /// (Linux, needs the `global-hotkey` feature)
+    #[arg(long, value_name = "KEY")]
+    pub global_ptt: Option<String>,
+
+    /// Use a virtual sound card instead of the system devices (no mic/speaker needed)
+    #[arg(long)]
+    pub virtual_audio: bool,
+
+    /// Feed this WAV file into the virtual microphone (implies --virtual-audio)
+    #[arg(long, value_name = "FILE")]
+    pub input_wav: Option<PathBuf>,
+
+    /// Record the virtual speaker to this WAV file on exit (implies --virtual-audio)
+    #[arg(long, value_name = "FILE")]
+    pub output_wav: Option<PathBuf>,
+
+    /// Send audio as RTP (RFC 3550/7587) with RTCP reports instead of the native format
+    #[arg(long)]
+    pub rtp: bool,
+
+    /// Also send our stream as RTP to this address, e.g. a recording ffmpeg
+    /// (RTCP goes to the next port up)
+    #[arg(long, value_name = "ADDR", value_parser = rtp_forward)]
+    pub rtp_forward: Option<SocketAddr>,
+
+    /// Write an SDP file describing the forwarded stream (`ffmpeg -i FILE`)
+    #[arg(long, value_name = "FILE", requires = "rtp_forward")]
+    pub sdp: Option<PathBuf>,
+
+    /// Carry audio over WebRTC (ICE + DTLS-SRTP) to peers that also use it
+    #[arg(long)]
+    pub webrtc: bool,
+
+    /// STUN/TURN server for WebRTC, e.g. stun:stun.example.org:3478 (repeatable)
+    #[arg(long, value_name = "URL")]
+    pub ice_server: Vec<String>,
+
+    /// Serve the browser client here, e.g. 0.0.0.0:8443 (HTTPS, self-signed certificate)
+    #[arg(long, value_name = "ADDR")]
+    pub web: Option<SocketAddr>,
+
+    /// Serve Prometheus metrics at http://ADDR/metrics, e.g. 0.0.0.0:9184
+    #[arg(long, value_name = "ADDR")]
+    pub metrics: Option<SocketAddr>,
+
+    /// Debug: record every received datagram for `vl replay`
+    /// (defaults to ~/.local/share/vl/captures/capture-<time>.jsonl)
+    #[arg(long, value_name = "FILE", require_equals = true)]
+    pub capture: Option<Option<PathBuf>>,
+
+    /// Don't keep a history of sessions, peers and talk spurts for `vl history`
+    #[arg(long)]
+    pub no_history: bool,
+
+    #[command(flatten)]
+    pub codec: CodecArgs,
+
+    #[command(flatten)]
+    pub impair: ImpairArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Opus settings; each one overrides the `[codec]` config section for this run.
+#[derive(Args, Debug)]
+#[command(next_help_heading = "Codec")]
+pub struct CodecArgs {
+    /// Encoder tuning: `voice` for speech, `music` for full-band stereo
+    #[arg(long, value_enum)]
+    pub profile: Option<Profile>,
+
+    /// Target bitrate in bits/second (6000-510000)
+    #[arg(long, value_name = "BPS")]
+    pub bitrate: Option<i32>,
+
+    /// Encoder complexity, 0 (fastest) to 10 (best)
+    #[arg(long)]
+    pub complexity: Option<i32>,
+
+    /// Constant bitrate instead of VBR
+    #[arg(long)]
+    pub cbr: bool,
+
+    /// Discontinuous transmission: almost no packets during silence
+    #[arg(long)]
+    pub dtx: bool,
+
+    /// Audio bandwidth limit
+    #[arg(long, value_enum)]
+    pub bandwidth: Option<AudioBandwidth>,
+
+    /// Frame duration in ms: 2.5, 5, 10, 20, 40 or 60
+    #[arg(long, value_name = "MS")]
+    pub frame_ms: Option<f32>,
+
+    /// Keep bitrate, FEC and frame length fixed regardless of reported loss
+    #[arg(long)]
+    pub no_adaptive: bool,
+}
+
+impl CodecArgs {
+    pub fn apply(&self, codec: &mut CodecConfig) {
+        if let Some(profile) = self.profile {
+            codec.profile = profile;
+        }
+        if let Some(bitrate) = self.bitrate {
+            codec.bitrate = Some(bitrate);
+        }
+        if let Some(complexity) = self.complexity {
+            codec.complexity = complexity;
+        }
+        if self.cbr {
+            codec.vbr = false;
+        }
+        if self.dtx {
+            codec.dtx = true;
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            codec.bandwidth = bandwidth;
+        }
+        if let Some(frame_ms) = self.frame_ms {
+            codec.frame_ms = frame_ms;
+        }
+        if self.no_adaptive {
+            codec.adaptive = false;
+        }
+    }
+}
+
+/// Which way impaired datagrams travel.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImpairDirection {
+    Send,
+    Receive,
+    Both,
+}
+
+/// A percentage on the command line, as a 0.0-1.0 probability.
+fn percent(value: &str) -> Result<f64, String> {
+    let percent: f64 = value.parse().map_err(|e| format!("{e}"))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err("must be between 0 and 100".to_string());
+    }
+    Ok(percent / 100.0)
+}
+
+/// An RTP target with room for RTCP on the port after it.
+fn rtp_forward(value: &str) -> Result<SocketAddr, String> {
+    let addr: SocketAddr = value.parse().map_err(|e| format!("{e}"))?;
+    if addr.port() == u16::MAX {
+        return Err("port must be below 65535, RTCP goes to the next one".to_string());
+    }
+    Ok(addr)
+}
+
+/// Simulated bad network, for testing the jitter buffer and FEC.
+#[derive(Args, Debug)]
+#[command(next_help_heading = "Network impairment (testing)")]
+pub struct ImpairArgs {
+    /// Drop this percentage of datagrams at random
+    #[arg(long, value_name = "PCT", value_parser = percent, default_value = "0")]
+    pub loss: f64,
+
+    /// Start a loss burst at this percentage of datagrams
+    #[arg(long, value_name = "PCT", value_parser = percent, default_value = "0")]
+    pub burst_loss: f64,
+
+    /// Datagrams dropped in a row per burst
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub burst_len: u32,
+
+    /// Delay every datagram by this many ms
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub delay: u64,
+
+    /// Add up to this many ms of random delay
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub jitter: u64,
+
+    /// Send this percentage of datagrams twice
+    #[arg(long, value_name = "PCT", value_parser = percent, default_value = "0")]
+    pub duplicate: f64,
+
+    /// Hold this percentage of datagrams back so later ones overtake them
+    #[arg(long, value_name = "PCT", value_parser = percent, default_value = "0")]
+    pub reorder: f64,
+
+    /// Where to impair datagrams
+    #[arg(long, value_enum, default_value_t = ImpairDirection::Receive)]
+    pub impair: ImpairDirection,
+
+    /// Seed the impairment for a reproducible run
+    #[arg(long, value_name = "N")]
+    pub impair_seed: Option<u64>,
+}
+
+impl ImpairArgs {
+    pub fn impairments(&self) -> Impairments {
+        let impairment = Impairment {
+            loss: self.loss,
+            burst_loss: self.burst_loss,
+            burst_len: self.burst_len,
+            delay: Duration::from_millis(self.delay),
+            jitter: Duration::from_millis(self.jitter),
+            duplicate: self.duplicate,
+            reorder: self.reorder,
+            seed: self.impair_seed,
+        };
+        if !impairment.is_active() {
+            return Impairments::default();
+        }
+        // Each direction gets its own random stream
+        let send = Impairment {
+            seed: self.impair_seed.map(|seed| seed.wrapping_add(1)),
+            ..impairment.clone()
+        };
+        Impairments {
+            send: (self.impair != ImpairDirection::Receive).then_some(send),
+            receive: (self.impair != ImpairDirection::Send).then_some(impairment),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Transmit a WAV file into the channel once peers are up ('P' in the TUI replays it)
+    Play {
+        /// WAV file to transmit
+        file: PathBuf,
+    },
+    /// Run a `--capture` file through the jitter buffer and decoder offline
+    Replay {
+        /// Capture file to replay
+        capture: PathBuf,
+
+        /// WAV file to write (defaults to the capture with a .wav extension)
+        #[arg(long, short, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// JSON statistics report (defaults to the capture with a .report.json extension)
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+
+        /// Channels in the output WAV
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u16).range(1..=2))]
+        channels: u16,
+    },
+    /// Who was on the channel and who talked, from the recorded history
+    History {
+        /// Day to look at, e.g. 2026-10-19 (defaults to today)
+        #[arg(long, value_name = "DATE")]
+        date: Option<NaiveDate>,
+
+        /// Only from this time of day, e.g. 08:00
+        #[arg(long, value_name = "HH:MM", value_parser = time_of_day)]
+        from: Option<NaiveTime>,
+
+        /// Only until this time of day, e.g. 12:00
+        #[arg(long, value_name = "HH:MM", value_parser = time_of_day)]
+        to: Option<NaiveTime>,
+
+        /// Only this peer, by name or address
+        #[arg(long, value_name = "PEER")]
+        peer: Option<String>,
+
+        /// Print the matching records as JSON lines instead
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// `HH:MM` or `HH:MM:SS`.
+fn time_of_day(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M:%S"))
+        .map_err(|e| format!("{e}"))
+}
@@ -0,0 +1,167 @@
+use crate::audio::codec::CodecConfig;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Settings persisted between runs in `config.toml`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    pub audio: AudioConfig,
+    pub codec: CodecConfig,
+    pub network: NetworkConfig,
+    /// Per-peer mixer settings, keyed by the peer's mDNS instance name.
+    pub peers: BTreeMap<String, PeerConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AudioConfig {
+    pub master_volume: f32,
+    pub mic_gain: f32,
+    pub mic_muted: bool,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            master_volume: 1.0,
+            mic_gain: 1.0,
+            mic_muted: false,
+        }
+    }
+}
+
+/// Socket options for the audio/control socket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Kernel receive buffer, in bytes.
+    pub recv_buffer: usize,
+    /// Kernel send buffer, in bytes.
+    pub send_buffer: usize,
+    /// DSCP for audio and the control traffic that times it (floor, reports, pings).
+    pub audio_dscp: u8,
+    /// DSCP for chat and file transfer.
+    pub data_dscp: u8,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            recv_buffer: 1 << 20,
+            send_buffer: 1 << 20,
+            // EF for voice, AF11 so bulk transfers never compete with it
+            audio_dscp: 46,
+            data_dscp: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PeerConfig {
+    pub volume: f32,
+    pub muted: bool,
+    pub solo: bool,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            muted: false,
+            solo: false,
+        }
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/vl/config.toml`, `~/.config/vl/config.toml` or `%APPDATA%\vl\config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(base.join("vl").join("config.toml"))
+    }
+
+    /// Load the config, falling back to defaults when the file doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config in {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let text = toml::to_string_pretty(self)?;
+        fs::write(path, text).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// `$XDG_DATA_HOME/vl`, `~/.local/share/vl` or `%APPDATA%\vl`: chat
+/// history, received files.
+pub fn data_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+        })?;
+    Some(base.join("vl"))
+}
+
+/// The loaded config plus where it lives, shared by everything that edits it.
+pub struct ConfigStore {
+    path: Option<PathBuf>,
+    config: Mutex<Config>,
+}
+
+impl ConfigStore {
+    /// Open the config at `path`; `None` keeps settings in memory only.
+    pub fn open(path: Option<PathBuf>) -> Result<Self> {
+        let config = match &path {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        Ok(Self {
+            path,
+            config: Mutex::new(config),
+        })
+    }
+
+    /// Settings that are never written anywhere (tests, embedding).
+    pub fn in_memory(config: Config) -> Self {
+        Self {
+            path: None,
+            config: Mutex::new(config),
+        }
+    }
+
+    pub fn get(&self) -> Config {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Apply `update` and write the result back to disk.
+    pub fn update(&self, update: impl FnOnce(&mut Config)) -> Result<()> {
+        let mut config = self.config.lock().unwrap();
+        update(&mut config);
+
+        match &self.path {
+            Some(path) => config.save(path),
+            None => Ok(()),
+        }
+    }
+}
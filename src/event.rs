@@ -0,0 +1,456 @@
+//! Typed session events on a broadcast bus. Everything worth telling the
+//! user is published here once; the TUI's log, `log` output and anything
+//! embedding a [`Session`](crate::session::Session) subscribe to it.
+
+use crate::network::transfer::format_bytes;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use tokio::sync::broadcast;
+
+/// Events a subscriber may fall behind by before missing some.
+const FEED_LEN: usize = 256;
+/// Recent events kept for subscribers that join late (the TUI's backlog).
+const RECENT_LEN: usize = 500;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Error,
+}
+
+/// What an event is about, for filtering.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Peers,
+    Talk,
+    Audio,
+    Network,
+    Transfers,
+    App,
+}
+
+impl Category {
+    pub const ALL: [Category; 6] = [
+        Category::Peers,
+        Category::Talk,
+        Category::Audio,
+        Category::Network,
+        Category::Transfers,
+        Category::App,
+    ];
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Category::Peers => "peers",
+            Category::Talk => "talk",
+            Category::Audio => "audio",
+            Category::Network => "network",
+            Category::Transfers => "transfers",
+            Category::App => "app",
+        })
+    }
+}
+
+/// What turned PTT on or off.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PttSource {
+    /// Held key in the TUI.
+    Spacebar,
+    /// Latching key in the TUI.
+    Latch,
+    /// No key repeat arrived in time, so the key was taken as released.
+    KeyRepeat,
+    GlobalHotkey,
+    /// A program driving the session.
+    Api,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stream {
+    Input,
+    Output,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// We joined `channel` as `name`.
+    SessionStarted {
+        name: String,
+        channel: String,
+    },
+    SessionEnded,
+    Listening {
+        port: u16,
+    },
+    Impairing {
+        /// "sent" or "received".
+        direction: String,
+        impairment: String,
+    },
+    PeerJoined {
+        peer: SocketAddr,
+        name: String,
+    },
+    PeerLeft {
+        peer: SocketAddr,
+        name: String,
+    },
+    BrowserJoined {
+        name: String,
+    },
+    BrowserLeft {
+        name: String,
+    },
+    WebRtcUp {
+        peer: SocketAddr,
+        name: String,
+    },
+    WebRtcDown {
+        peer: SocketAddr,
+        name: String,
+    },
+    TalkStarted {
+        peer: SocketAddr,
+        name: String,
+    },
+    TalkStopped {
+        peer: SocketAddr,
+        name: String,
+    },
+    Ptt {
+        active: bool,
+        source: PttSource,
+    },
+    FloorTaken,
+    ChannelBusy {
+        holder: String,
+    },
+    MicMuted {
+        muted: bool,
+    },
+    StreamStarted {
+        stream: Stream,
+    },
+    DeviceError {
+        stream: Stream,
+        message: String,
+    },
+    /// Packets that failed to decode since the last report.
+    DecodeErrors {
+        count: u64,
+    },
+    /// A peer's stream started losing packets.
+    LossSpike {
+        peer: SocketAddr,
+        name: String,
+        loss_pct: f32,
+    },
+    Bitrate {
+        kbps: i32,
+    },
+    PlaybackQueued {
+        path: PathBuf,
+    },
+    PlaybackStarted {
+        path: PathBuf,
+    },
+    PlaybackFinished,
+    TransferOffered {
+        peer: String,
+        file: String,
+        size: u64,
+    },
+    TransferSending {
+        peer: String,
+        path: PathBuf,
+    },
+    TransferAccepted {
+        file: String,
+        dir: PathBuf,
+    },
+    TransferRejected {
+        file: String,
+    },
+    TransferDone {
+        peer: String,
+        file: String,
+        incoming: bool,
+    },
+    TransferFailed {
+        file: String,
+        reason: String,
+    },
+    TransfersCancelled,
+    /// Anything else worth showing, e.g. where a server is listening.
+    Notice {
+        message: String,
+    },
+    /// A failure without an event of its own.
+    Failure {
+        message: String,
+    },
+}
+
+impl Event {
+    pub fn notice(message: impl Into<String>) -> Self {
+        Event::Notice {
+            message: message.into(),
+        }
+    }
+
+    pub fn failure(message: impl Into<String>) -> Self {
+        Event::Failure {
+            message: message.into(),
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Event::DeviceError { .. } | Event::TransferFailed { .. } | Event::Failure { .. } => {
+                Severity::Error
+            }
+            Event::Impairing { .. }
+            | Event::PeerLeft { .. }
+            | Event::BrowserLeft { .. }
+            | Event::WebRtcDown { .. }
+            | Event::ChannelBusy { .. }
+            | Event::DecodeErrors { .. }
+            | Event::LossSpike { .. } => Severity::Warning,
+            _ => Severity::Info,
+        }
+    }
+
+    pub fn category(&self) -> Category {
+        match self {
+            Event::PeerJoined { .. }
+            | Event::PeerLeft { .. }
+            | Event::BrowserJoined { .. }
+            | Event::BrowserLeft { .. }
+            | Event::WebRtcUp { .. }
+            | Event::WebRtcDown { .. } => Category::Peers,
+            Event::TalkStarted { .. }
+            | Event::TalkStopped { .. }
+            | Event::Ptt { .. }
+            | Event::FloorTaken
+            | Event::ChannelBusy { .. }
+            | Event::MicMuted { .. } => Category::Talk,
+            Event::StreamStarted { .. }
+            | Event::DeviceError { .. }
+            | Event::DecodeErrors { .. }
+            | Event::Bitrate { .. }
+            | Event::PlaybackQueued { .. }
+            | Event::PlaybackStarted { .. }
+            | Event::PlaybackFinished => Category::Audio,
+            Event::Listening { .. } | Event::Impairing { .. } | Event::LossSpike { .. } => {
+                Category::Network
+            }
+            Event::TransferOffered { .. }
+            | Event::TransferSending { .. }
+            | Event::TransferAccepted { .. }
+            | Event::TransferRejected { .. }
+            | Event::TransferDone { .. }
+            | Event::TransferFailed { .. }
+            | Event::TransfersCancelled => Category::Transfers,
+            Event::SessionStarted { .. }
+            | Event::SessionEnded
+            | Event::Notice { .. }
+            | Event::Failure { .. } => Category::App,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::SessionStarted { name, channel } => {
+                write!(f, "▶ Joined {} as {}", channel, name)
+            }
+            Event::SessionEnded => write!(f, "⏹ Session ended"),
+            Event::Listening { port } => write!(f, "🎧 UDP listening on port {}", port),
+            Event::Impairing {
+                direction,
+                impairment,
+            } => write!(f, "🧪 Impairing {} datagrams: {}", direction, impairment),
+            Event::PeerJoined { peer, name } => write!(f, "✅ Found new peer: {} ({})", name, peer),
+            Event::PeerLeft { peer, name } => write!(f, "👋 {} ({}) left", name, peer),
+            Event::BrowserJoined { name } => write!(f, "🌐 {} joined from a browser", name),
+            Event::BrowserLeft { name } => write!(f, "🌐 {} left (browser)", name),
+            Event::WebRtcUp { name, .. } => write!(f, "🔐 WebRTC link up: {}", name),
+            Event::WebRtcDown { name, .. } => write!(f, "🔓 WebRTC link down: {}", name),
+            Event::TalkStarted { name, .. } => write!(f, "🗣 {} started talking", name),
+            Event::TalkStopped { name, .. } => write!(f, "🔇 {} stopped talking", name),
+            Event::Ptt {
+                active: true,
+                source,
+            } => match source {
+                PttSource::Latch => write!(f, "🔴 PTT LATCHED - Transmitting (SPACE to stop)"),
+                PttSource::GlobalHotkey => {
+                    write!(f, "🔴 PTT ACTIVE - Transmitting (global hotkey)")
+                }
+                PttSource::Api => write!(f, "🔴 PTT ACTIVE - Transmitting"),
+                _ => write!(f, "🔴 PTT ACTIVE - Transmitting (hold spacebar)"),
+            },
+            Event::Ptt {
+                active: false,
+                source,
+            } => match source {
+                PttSource::KeyRepeat => write!(f, "⚫ PTT OFF - Auto-deactivated"),
+                _ => write!(f, "⚫ PTT OFF - Not transmitting"),
+            },
+            Event::FloorTaken => write!(f, "🎙 You have the floor"),
+            Event::ChannelBusy { holder } => {
+                write!(f, "⛔ Channel busy - {} has the floor", holder)
+            }
+            Event::MicMuted { muted: true } => write!(f, "🔇 Mic muted"),
+            Event::MicMuted { muted: false } => write!(f, "🎤 Mic unmuted"),
+            Event::StreamStarted {
+                stream: Stream::Input,
+            } => write!(f, "🎤 Microphone stream is live"),
+            Event::StreamStarted {
+                stream: Stream::Output,
+            } => write!(f, "🔊 Audio output stream started"),
+            Event::DeviceError { stream, message } => {
+                let device = match stream {
+                    Stream::Input => "Microphone",
+                    Stream::Output => "Speaker",
+                };
+                write!(f, "❌ {} error: {}", device, message)
+            }
+            Event::DecodeErrors { count } => write!(f, "⚠ {} packets failed to decode", count),
+            Event::LossSpike { name, loss_pct, .. } => {
+                write!(f, "📉 {:.1}% packet loss from {}", loss_pct, name)
+            }
+            Event::Bitrate { kbps } => write!(f, "🎚 Bitrate {} kbps", kbps),
+            Event::PlaybackQueued { path } => {
+                write!(f, "📢 Queued {} for playback", path.display())
+            }
+            Event::PlaybackStarted { path } => write!(f, "📢 Playing {}", path.display()),
+            Event::PlaybackFinished => write!(f, "📢 File playback finished"),
+            Event::TransferOffered { peer, file, size } => write!(
+                f,
+                "📁 {} offers {} ({}) - 'y' accept / 'n' reject",
+                peer,
+                file,
+                format_bytes(*size)
+            ),
+            Event::TransferSending { peer, path } => {
+                write!(f, "📁 Offering {} to {}", path.display(), peer)
+            }
+            Event::TransferAccepted { file, dir } => {
+                write!(f, "📥 Receiving {} into {}", file, dir.display())
+            }
+            Event::TransferRejected { file } => write!(f, "🚫 Rejected {}", file),
+            Event::TransferDone {
+                peer,
+                file,
+                incoming: true,
+            } => write!(f, "✅ Received {} from {} (checksum OK)", file, peer),
+            Event::TransferDone { peer, file, .. } => write!(f, "✅ Sent {} to {}", file, peer),
+            Event::TransferFailed { file, reason } => {
+                write!(f, "❌ Transfer of {} failed: {}", file, reason)
+            }
+            Event::TransfersCancelled => write!(f, "🛑 Cancelled all file transfers"),
+            Event::Notice { message } => f.write_str(message),
+            Event::Failure { message } => write!(f, "❌ {}", message),
+        }
+    }
+}
+
+/// An event and when it happened.
+#[derive(Serialize, Debug, Clone)]
+pub struct Record {
+    pub at: DateTime<Local>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+struct Bus {
+    feed: broadcast::Sender<Record>,
+    recent: Mutex<VecDeque<Record>>,
+}
+
+/// Publishes events to every subscriber. Clones share one bus.
+#[derive(Clone)]
+pub struct EventBus {
+    bus: Arc<Bus>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            bus: Arc::new(Bus {
+                feed: broadcast::channel(FEED_LEN).0,
+                recent: Mutex::new(VecDeque::new()),
+            }),
+        }
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, event: Event) {
+        let record = Record {
+            at: Local::now(),
+            event,
+        };
+        let mut recent = self.bus.recent.lock().unwrap();
+        // No subscribers is fine
+        let _ = self.bus.feed.send(record.clone());
+        recent.push_back(record);
+        if recent.len() > RECENT_LEN {
+            recent.pop_front();
+        }
+    }
+
+    /// Every event from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Record> {
+        self.bus.feed.subscribe()
+    }
+
+    /// The most recent events and a subscription continuing right after them.
+    pub fn subscribe_with_recent(&self) -> (Vec<Record>, broadcast::Receiver<Record>) {
+        let recent = self.bus.recent.lock().unwrap();
+        (recent.iter().cloned().collect(), self.bus.feed.subscribe())
+    }
+}
+
+/// Mirror every event to the `log` crate at its severity.
+pub fn log_events(bus: &EventBus) {
+    let mut events = bus.subscribe();
+    spawn(move || {
+        loop {
+            let record = match events.blocking_recv() {
+                Ok(record) => record,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("Event log fell behind, {} events not logged", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let level = match record.event.severity() {
+                Severity::Info => log::Level::Info,
+                Severity::Warning => log::Level::Warn,
+                Severity::Error => log::Level::Error,
+            };
+            log::log!(level, "{}", record.event);
+        }
+    });
+}
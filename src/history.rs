@@ -0,0 +1,453 @@
+//! On-disk history of the channel: sessions, peers seen, talk spurts and
+//! errors, so "who was on this morning" can be answered after the TUI is
+//! gone (`vl history`).
+//!
+//! One JSON-lines file of [`HistoryRecord`]s per day, written from the event
+//! bus. Files older than [`KEEP_DAYS`] are deleted when a new day starts.
+
+use crate::config::data_dir;
+use crate::event::{Event, EventBus, Record, Severity};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDate, TimeDelta};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::thread::{JoinHandle, spawn};
+use tokio::sync::broadcast;
+
+/// Days of history kept.
+pub const KEEP_DAYS: i64 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Entry {
+    /// We joined `channel` as `name`.
+    SessionStarted {
+        name: String,
+        channel: String,
+    },
+    SessionEnded,
+    PeerJoined {
+        peer: SocketAddr,
+        name: String,
+    },
+    PeerLeft {
+        peer: SocketAddr,
+        name: String,
+    },
+    /// One talk spurt, written when it ends; `peer` is `None` for our own.
+    Talk {
+        talker: String,
+        peer: Option<SocketAddr>,
+        started: DateTime<Local>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// One line of a history file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryRecord {
+    /// When it happened; the end of a talk spurt.
+    pub at: DateTime<Local>,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+impl HistoryRecord {
+    /// When it started: the start of a talk spurt, otherwise `at`.
+    pub fn started(&self) -> DateTime<Local> {
+        match &self.entry {
+            Entry::Talk { started, .. } => *started,
+            _ => self.at,
+        }
+    }
+
+    /// Whether it concerns the peer called or at `peer`.
+    pub fn involves(&self, peer: &str) -> bool {
+        match &self.entry {
+            Entry::PeerJoined { peer: addr, name } | Entry::PeerLeft { peer: addr, name } => {
+                is_peer(peer, name, Some(*addr))
+            }
+            Entry::Talk {
+                talker, peer: addr, ..
+            } => is_peer(peer, talker, *addr),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for HistoryRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] ", self.started().format("%H:%M:%S"))?;
+        match &self.entry {
+            Entry::SessionStarted { name, channel } => {
+                write!(f, "▶ Joined {} as {}", channel, name)
+            }
+            Entry::SessionEnded => write!(f, "⏹ Session ended"),
+            Entry::PeerJoined { peer, name } => write!(f, "✅ {} ({}) joined", name, peer),
+            Entry::PeerLeft { peer, name } => write!(f, "👋 {} ({}) left", name, peer),
+            Entry::Talk {
+                talker, started, ..
+            } => write!(
+                f,
+                "🗣 {} talked for {}",
+                talker,
+                format_span(self.at - *started)
+            ),
+            Entry::Error { message } => write!(f, "❌ {}", message),
+        }
+    }
+}
+
+/// `peer` is a name (any case), an address or just an IP.
+fn is_peer(peer: &str, name: &str, addr: Option<SocketAddr>) -> bool {
+    name.eq_ignore_ascii_case(peer)
+        || addr.is_some_and(|addr| addr.to_string() == peer || addr.ip().to_string() == peer)
+}
+
+/// `1h02m`, `3m05s` or `4.2s`.
+pub fn format_span(span: TimeDelta) -> String {
+    let secs = span.num_milliseconds().max(0) as f64 / 1000.0;
+    let whole = secs as u64;
+    if whole >= 3600 {
+        format!("{}h{:02}m", whole / 3600, whole % 3600 / 60)
+    } else if whole >= 60 {
+        format!("{}m{:02}s", whole / 60, whole % 60)
+    } else {
+        format!("{:.1}s", secs)
+    }
+}
+
+/// Appends records to the file for their day.
+pub struct History {
+    dir: PathBuf,
+    day: Option<NaiveDate>,
+    file: Option<File>,
+}
+
+impl History {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            day: None,
+            file: None,
+        }
+    }
+
+    /// `history/` in the data directory.
+    pub fn default_dir() -> Option<PathBuf> {
+        Some(data_dir()?.join("history"))
+    }
+
+    /// `history-<date>.jsonl` in `dir`.
+    pub fn path(dir: &Path, day: NaiveDate) -> PathBuf {
+        dir.join(format!("history-{}.jsonl", day.format("%Y-%m-%d")))
+    }
+
+    pub fn append(&mut self, record: &HistoryRecord) -> Result<()> {
+        let day = record.at.date_naive();
+        if self.day != Some(day) || self.file.is_none() {
+            fs::create_dir_all(&self.dir)?;
+            let path = Self::path(&self.dir, day);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Cannot open {}", path.display()))?;
+            self.file = Some(file);
+            self.day = Some(day);
+            self.prune(day);
+        }
+        let file = self.file.as_mut().unwrap();
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    }
+
+    /// Delete files more than [`KEEP_DAYS`] before `today`.
+    fn prune(&self, today: NaiveDate) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let oldest = today - TimeDelta::days(KEEP_DAYS);
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(day) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("history-"))
+                .and_then(|name| name.strip_suffix(".jsonl"))
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+            else {
+                continue;
+            };
+            if day < oldest
+                && let Err(e) = fs::remove_file(entry.path())
+            {
+                warn!("Failed to remove old history {:?}: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Everything recorded on the days from `first` to `last`, oldest first.
+pub fn read_history(dir: &Path, first: NaiveDate, last: NaiveDate) -> Result<Vec<HistoryRecord>> {
+    let mut records = Vec::new();
+    for day in first.iter_days().take_while(|day| *day <= last) {
+        let path = History::path(dir, day);
+        if !path.exists() {
+            continue;
+        }
+        let file = File::open(&path).with_context(|| format!("Cannot open {}", path.display()))?;
+        for line in BufReader::new(file).lines() {
+            // Skip lines a crash may have cut short
+            if let Ok(record) = serde_json::from_str::<HistoryRecord>(&line?) {
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Turns events into history entries, pairing the start of each talk spurt
+/// with its end.
+#[derive(Default)]
+struct Recorder {
+    /// Our own name, once the session started.
+    name: String,
+    /// Peers talking now, by name, and since when.
+    talking: HashMap<SocketAddr, (String, DateTime<Local>)>,
+    transmitting: Option<DateTime<Local>>,
+}
+
+impl Recorder {
+    fn on_event(&mut self, record: &Record) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        match &record.event {
+            Event::SessionStarted { name, channel } => {
+                self.name = name.clone();
+                entries.push(Entry::SessionStarted {
+                    name: name.clone(),
+                    channel: channel.clone(),
+                });
+            }
+            Event::SessionEnded => {
+                if let Some(started) = self.transmitting.take() {
+                    entries.push(self.own_talk(started));
+                }
+                for (peer, (talker, started)) in self.talking.drain() {
+                    entries.push(Entry::Talk {
+                        talker,
+                        peer: Some(peer),
+                        started,
+                    });
+                }
+                entries.push(Entry::SessionEnded);
+            }
+            Event::PeerJoined { peer, name } => entries.push(Entry::PeerJoined {
+                peer: *peer,
+                name: name.clone(),
+            }),
+            Event::PeerLeft { peer, name } => {
+                if let Some((talker, started)) = self.talking.remove(peer) {
+                    entries.push(Entry::Talk {
+                        talker,
+                        peer: Some(*peer),
+                        started,
+                    });
+                }
+                entries.push(Entry::PeerLeft {
+                    peer: *peer,
+                    name: name.clone(),
+                });
+            }
+            Event::TalkStarted { peer, name } => {
+                self.talking
+                    .entry(*peer)
+                    .or_insert((name.clone(), record.at));
+            }
+            Event::TalkStopped { peer, .. } => {
+                if let Some((talker, started)) = self.talking.remove(peer) {
+                    entries.push(Entry::Talk {
+                        talker,
+                        peer: Some(*peer),
+                        started,
+                    });
+                }
+            }
+            Event::Ptt { active: true, .. } => {
+                self.transmitting.get_or_insert(record.at);
+            }
+            Event::Ptt { active: false, .. } => {
+                if let Some(started) = self.transmitting.take() {
+                    entries.push(self.own_talk(started));
+                }
+            }
+            event if event.severity() == Severity::Error => entries.push(Entry::Error {
+                message: event.to_string(),
+            }),
+            _ => {}
+        }
+        entries
+    }
+
+    fn own_talk(&self, started: DateTime<Local>) -> Entry {
+        Entry::Talk {
+            talker: self.name.clone(),
+            peer: None,
+            started,
+        }
+    }
+}
+
+/// Write the session's history into `dir` until it ends. Join the handle
+/// after shutdown so the last talk spurts are on disk before exiting.
+pub fn record_history(bus: &EventBus, dir: PathBuf) -> JoinHandle<()> {
+    // Picks up the session start and anything else published before now
+    let (recent, mut events) = bus.subscribe_with_recent();
+    spawn(move || {
+        let mut history = History::new(dir);
+        let mut recorder = Recorder::default();
+        let mut backlog = recent.into_iter();
+        loop {
+            let record = match backlog.next() {
+                Some(record) => record,
+                None => match events.blocking_recv() {
+                    Ok(record) => record,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("History fell behind, {} events not recorded", missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            };
+            for entry in recorder.on_event(&record) {
+                let record = HistoryRecord {
+                    at: record.at,
+                    entry,
+                };
+                // Stop rather than warn on every event when the disk is gone
+                if let Err(e) = history.append(&record) {
+                    warn!("History recording stopped: {:#}", e);
+                    return;
+                }
+            }
+            if record.event == Event::SessionEnded {
+                return;
+            }
+        }
+    })
+}
+
+/// Time on the channel and talking, per participant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Presence {
+    pub name: String,
+    /// Last address seen; `None` for ourselves.
+    pub peer: Option<SocketAddr>,
+    pub first_seen: DateTime<Local>,
+    pub last_seen: DateTime<Local>,
+    pub spurts: usize,
+    pub talking: TimeDelta,
+}
+
+impl Presence {
+    /// Whether this is the peer called or at `peer`.
+    pub fn is(&self, peer: &str) -> bool {
+        is_peer(peer, &self.name, self.peer)
+    }
+}
+
+/// Who was on the channel between `from` and `to`, and how much they said,
+/// in order of appearance. `records` must span whole days.
+pub fn presence(
+    records: &[HistoryRecord],
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+) -> Vec<Presence> {
+    let mut open: HashMap<String, (Option<SocketAddr>, DateTime<Local>)> = HashMap::new();
+    let mut stays = Vec::new();
+    for record in records {
+        match &record.entry {
+            Entry::SessionStarted { name, .. } => {
+                open.insert(name.clone(), (None, record.at));
+            }
+            Entry::PeerJoined { peer, name } => {
+                open.entry(name.clone()).or_insert((Some(*peer), record.at));
+            }
+            // Without a join it joined before the first file read
+            Entry::PeerLeft { peer, name } => {
+                let (_, since) = open.remove(name).unwrap_or((Some(*peer), from));
+                stays.push((name.clone(), Some(*peer), since, record.at));
+            }
+            // Nothing was watched after this, so everyone counts as gone
+            Entry::SessionEnded => {
+                stays.extend(
+                    open.drain()
+                        .map(|(name, (peer, since))| (name, peer, since, record.at)),
+                );
+            }
+            _ => {}
+        }
+    }
+    let end = to.min(Local::now());
+    stays.extend(
+        open.into_iter()
+            .map(|(name, (peer, since))| (name, peer, since, end)),
+    );
+
+    let mut people: Vec<Presence> = Vec::new();
+    // Count a stay, or a talk spurt, that overlaps the window
+    let mut join = |name: &str, peer, start: DateTime<Local>, stop: DateTime<Local>, talk| {
+        let (start, stop) = (start.max(from), stop.min(to));
+        if start > stop {
+            return;
+        }
+        let index = match people.iter().position(|p| p.name == name) {
+            Some(index) => index,
+            None => {
+                people.push(Presence {
+                    name: name.to_string(),
+                    peer,
+                    first_seen: start,
+                    last_seen: stop,
+                    spurts: 0,
+                    talking: TimeDelta::zero(),
+                });
+                people.len() - 1
+            }
+        };
+        let person = &mut people[index];
+        person.first_seen = person.first_seen.min(start);
+        person.last_seen = person.last_seen.max(stop);
+        if peer.is_some() {
+            person.peer = peer;
+        }
+        if talk {
+            person.spurts += 1;
+            person.talking += stop - start;
+        }
+    };
+
+    stays.sort_by_key(|(_, _, since, _)| *since);
+    for (name, peer, since, until) in stays {
+        join(&name, peer, since, until, false);
+    }
+    for record in records {
+        if let Entry::Talk {
+            talker,
+            peer,
+            started,
+        } = &record.entry
+        {
+            join(talker, *peer, *started, record.at, true);
+        }
+    }
+    people.sort_by_key(|person| person.first_seen);
+    people
+}
@@ -0,0 +1,16 @@
+//! The walkie-talkie as a library. [`session::Session`] is the entry point:
+//! build one from an identity, transport, discovery, audio I/O and codec
+//! settings, then drive it through its PTT, mute and volume handles and
+//! event stream. [`node::Node`] is the pipeline underneath and
+//! [`state::AppState`] what it shares with front-ends. The `vl` binary and
+//! its TUI are one front-end on top of it.
+
+pub mod audio;
+pub mod config;
+pub mod event;
+pub mod history;
+pub mod network;
+pub mod node;
+pub mod session;
+pub mod state;
+pub mod ui;
@@ -1,117 +1,323 @@
-use cpal::traits::StreamTrait;
+use anyhow::{Context, Result};
+use chrono::{Days, Local, NaiveDate, NaiveTime};
+use clap::{CommandFactory, Parser, error::ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
 
-use std::env;
-use std::net::{SocketAddr, UdpSocket};
-use std::sync::{Arc, Mutex};
-use std::thread::spawn;
-use std::time::Duration;
+mod cli;
 
-mod audio;
-mod network;
-mod ui;
+use cli::{Cli, Command};
+use videolan::audio::backend::{AudioBackend, CpalBackend, MemoryBackend};
+use videolan::audio::file::{load_wav, write_wav};
+use videolan::audio::replay::replay;
+use videolan::config::{Config, ConfigStore, data_dir};
+use videolan::event::Event;
+use videolan::history::{History, format_span, presence, read_history};
+use videolan::network::capture::Capture;
+use videolan::network::chat::Chat;
+use videolan::session::Session;
 
-use audio::rad::start_audio_output;
-use audio::sad::start_mic_capture;
-use network::mdns::Data;
-
-use network::udp::{AudioBuffer, audio_udp_recv};
-
-use ui::tui::{AppState, run_tui};
-
-type Peerlist = Arc<Mutex<Vec<SocketAddr>>>;
+use videolan::ui::hotkey::start_global_ptt;
+use videolan::ui::metrics::start_metrics_server;
+use videolan::ui::tui::run_tui;
+use videolan::ui::web::{WebClients, start_web_server};
 
 fn main() {
     // Don't initialize env_logger when using TUI
     // env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        eprintln!("Usage: vl <instance_name> <port>");
+    let cli = Cli::parse();
+
+    if let Some(Command::Replay {
+        capture,
+        output,
+        report,
+        channels,
+    }) = &cli.command
+    {
+        if let Err(e) = replay_capture(capture, output.as_deref(), report.as_deref(), *channels) {
+            eprintln!("Replay failed: {:#}", e);
+            std::process::exit(1);
+        }
         return;
     }
 
-    let instance_name = args[1].as_str();
-    let port: u16 = args[2].parse().expect("Port must be a number");
+    if let Some(Command::History {
+        date,
+        from,
+        to,
+        peer,
+        json,
+    }) = &cli.command
+    {
+        if let Err(e) = show_history(*date, *from, *to, peer.as_deref(), *json) {
+            eprintln!("History unavailable: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let mdns = Data::new(instance_name, port);
-    let local_ip = mdns.ip.to_string();
-    mdns.announce();
+    let (Some(instance_name), Some(port)) = (cli.instance_name.as_deref(), cli.port) else {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "<INSTANCE_NAME> and <PORT> are required",
+            )
+            .exit();
+    };
 
-    let peers: Peerlist = Arc::new(Mutex::new(Vec::new()));
-    let audio_buffer: AudioBuffer = Arc::new(Mutex::new(std::collections::VecDeque::new()));
-    let buffer_size_tracker = Arc::new(Mutex::new(0usize));
+    let config_store = ConfigStore::open(cli.config.clone().or_else(Config::default_path))
+        .expect("Failed to load config");
 
-    mdns.discovery(peers.clone());
+    let mut codec = config_store.get().codec;
+    cli.codec.apply(&mut codec);
+    if let Err(e) = codec.validate() {
+        eprintln!("Invalid codec settings: {:#}", e);
+        std::process::exit(2);
+    }
 
-    let udp_socket = UdpSocket::bind(("0.0.0.0", port)).expect("Failed to bind UDP socket");
-    udp_socket
-        .set_nonblocking(true)
-        .expect("Failed to set nonblocking");
+    let play_file = match &cli.command {
+        Some(Command::Play { file }) => Some(file.clone()),
+        _ => None,
+    };
 
-    // Create app state
-    let app_state = Arc::new(AppState::new(
-        instance_name.to_string(),
-        local_ip,
-        port,
-        peers.clone(),
-        buffer_size_tracker.clone(),
-    ));
+    // Virtual sound card for boxes without audio hardware
+    let memory_backend = (cli.virtual_audio || cli.input_wav.is_some() || cli.output_wav.is_some())
+        .then(|| {
+            let backend = MemoryBackend::new(1);
+            // Only --output-wav needs the whole run kept in memory
+            Arc::new(if cli.output_wav.is_some() {
+                backend.with_recording()
+            } else {
+                backend
+            })
+        });
+    if let (Some(backend), Some(path)) = (&memory_backend, &cli.input_wav) {
+        let samples = load_wav(path).expect("Failed to load input WAV");
+        backend.push_input(&samples);
+    }
+    let audio_backend: Arc<dyn AudioBackend> = match &memory_backend {
+        Some(backend) => backend.clone(),
+        None => Arc::new(CpalBackend),
+    };
 
-    app_state.add_event("🎧 UDP listening started".to_string());
+    // Debug capture of everything the socket receives, for `vl replay`
+    let capture = cli.capture.as_ref().and_then(|path| {
+        let path = path.clone().or_else(Capture::default_path)?;
+        Some(Capture::create(&path, port).map(|capture| (path, Arc::new(capture))))
+    });
 
-    audio_udp_recv(port, &udp_socket, audio_buffer.clone());
-    let stream = start_audio_output(audio_buffer.clone());
-    stream.play().expect("Failed to play audio stream");
+    let mut builder = Session::builder(instance_name);
+    if !cli.no_history
+        && let Some(dir) = History::default_dir()
+    {
+        builder = builder.with_history(dir);
+    }
+    let session = builder
+        .with_channel(&cli.channel)
+        .with_port(port)
+        .with_audio(audio_backend)
+        .with_config(Arc::new(config_store))
+        .with_codec(codec)
+        .with_options(|options| {
+            options.floor_control = cli.floor_control;
+            options.rtp = cli.rtp;
+            options.rtp_forward = cli.rtp_forward;
+            options.webrtc = cli.webrtc;
+            options.ice_servers = cli.ice_server.clone();
+            options.web = cli.web.is_some();
+            options.impairments = cli.impair.impairments();
+            options.capture = capture
+                .as_ref()
+                .and_then(|capture| capture.as_ref().ok())
+                .map(|(_, capture)| capture.clone());
+            // The binary keeps chat and received files in the data directory
+            options.chat_history = Chat::default_history_path(&cli.channel);
+            options.download_dir = cli
+                .download_dir
+                .clone()
+                .or_else(|| Some(data_dir()?.join("received")));
+            options.play_file = play_file;
+        })
+        .start()
+        .expect("Failed to start");
+    let app_state = session.state().clone();
+    let events = &app_state.events;
+    let node = session.node();
 
-    app_state.add_event("🔊 Audio output stream started".to_string());
+    match &capture {
+        Some(Ok((path, _))) => events.publish(Event::notice(format!(
+            "🎙 Capturing datagrams to {}",
+            path.display()
+        ))),
+        Some(Err(e)) => events.publish(Event::failure(format!("Capture unavailable: {:#}", e))),
+        None => {}
+    }
 
-    // Spawn a thread to monitor buffer size and update app state
-    let buf_monitor = audio_buffer.clone();
-    let buf_tracker = buffer_size_tracker.clone();
-    spawn(move || {
-        loop {
-            std::thread::sleep(Duration::from_millis(500));
-            let buf_size = buf_monitor.lock().unwrap().len();
-            *buf_tracker.lock().unwrap() = buf_size;
+    if let (Some(path), Some(dest)) = (&cli.sdp, cli.rtp_forward) {
+        let sdp = app_state
+            .rtp
+            .sdp(instance_name, &app_state.local_ip, dest, &app_state.codec);
+        match std::fs::write(path, sdp) {
+            Ok(()) => events.publish(Event::notice(format!("📝 Wrote SDP to {}", path.display()))),
+            Err(e) => events.publish(Event::failure(format!("Failed to write SDP: {}", e))),
         }
-    });
+    }
 
-    // Peer discovery event logger
-    let app_state_clone = app_state.clone();
-    let peers_clone = peers.clone();
-    let mut known_peers = std::collections::HashSet::new();
-    spawn(move || {
-        loop {
-            std::thread::sleep(Duration::from_secs(1));
-            let current_peers = peers_clone.lock().unwrap().clone();
-            for peer in current_peers {
-                if !known_peers.contains(&peer) {
-                    known_peers.insert(peer);
-                    app_state_clone.add_event(format!("✅ Found new peer: {}", peer));
-                }
-            }
+    if let (Some(addr), Some(bridge), Some(transport)) = (cli.web, &node.bridge, &node.webrtc) {
+        let clients = WebClients {
+            transport: transport.clone(),
+            bridge: bridge.clone(),
+            handlers: node.handlers.clone(),
+            audio_buffer: node.audio_buffer.clone(),
+            peers: node.peers.clone(),
+        };
+        match start_web_server(addr, clients, app_state.clone()) {
+            Ok(bound) => events.publish(Event::notice(format!(
+                "🌐 Browser client at https://{}:{}",
+                app_state.local_ip,
+                bound.port()
+            ))),
+            Err(e) => events.publish(Event::failure(format!(
+                "Browser client unavailable: {:#}",
+                e
+            ))),
         }
-    });
-
-    let peers_for_ptt = peers.clone();
-    let ptt_flag = app_state.ptt_active.clone();
-    let app_state_for_mic = app_state.clone();
-
-    spawn(move || {
-        // Start mic capture with PTT control
-        let mic = start_mic_capture(&udp_socket, peers_for_ptt.clone(), ptt_flag.clone());
-        mic.play().expect("Failed to start mic stream");
+    }
 
-        app_state_for_mic.add_event("🎤 Microphone stream is live".to_string());
+    if let Some(addr) = cli.metrics {
+        match start_metrics_server(addr, app_state.clone()) {
+            Ok(bound) => events.publish(Event::notice(format!(
+                "📈 Metrics at http://{}/metrics",
+                bound
+            ))),
+            Err(e) => events.publish(Event::failure(format!("Metrics unavailable: {:#}", e))),
+        }
+    }
 
-        // Keep the stream alive forever
-        loop {
-            std::thread::sleep(Duration::from_secs(60));
+    // Global hotkey feeds the same PTT flag as the spacebar
+    if let Some(key) = &cli.global_ptt {
+        match start_global_ptt(key, &session) {
+            Ok(devices) => events.publish(Event::notice(format!(
+                "⌨ Global PTT on {} via {}",
+                key,
+                devices.join(", ")
+            ))),
+            Err(e) => events.publish(Event::failure(format!("Global PTT unavailable: {:#}", e))),
         }
-    });
+    }
 
     // Run the TUI - this blocks until user quits
-    if let Err(e) = run_tui(app_state.clone()) {
+    if let Err(e) = run_tui(&session, cli.ptt_mode) {
         eprintln!("TUI error: {}", e);
     }
+
+    if let (Some(backend), Some(path)) = (&memory_backend, &cli.output_wav) {
+        match write_wav(path, &backend.output(), backend.channels()) {
+            Ok(()) => println!("Recorded output to {}", path.display()),
+            Err(e) => eprintln!("Failed to write {}: {:#}", path.display(), e),
+        }
+    }
+
+    session.shutdown();
+}
+
+/// The `replay` subcommand: decode a capture to a WAV and a JSON report.
+fn replay_capture(
+    capture: &Path,
+    output: Option<&Path>,
+    report_path: Option<&Path>,
+    channels: u16,
+) -> Result<()> {
+    let (samples, report) = replay(capture, channels as usize)?;
+
+    let output = output.map_or_else(|| capture.with_extension("wav"), Path::to_path_buf);
+    write_wav(&output, &samples, channels)?;
+    let report_path =
+        report_path.map_or_else(|| capture.with_extension("report.json"), Path::to_path_buf);
+    std::fs::write(&report_path, serde_json::to_string_pretty(&report)?)?;
+
+    print!("{report}");
+    println!("Wrote {} and {}", output.display(), report_path.display());
+    Ok(())
+}
+
+/// The `history` subcommand: who was on the channel on a day, then what
+/// happened, optionally narrowed to a time window and one peer.
+fn show_history(
+    date: Option<NaiveDate>,
+    from: Option<NaiveTime>,
+    to: Option<NaiveTime>,
+    peer: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let dir = History::default_dir().context("No data directory")?;
+    let day = date.unwrap_or_else(|| Local::now().date_naive());
+    let local = |day: NaiveDate, time: NaiveTime| {
+        day.and_time(time)
+            .and_local_timezone(Local)
+            .earliest()
+            .with_context(|| format!("{day} {time} does not exist here"))
+    };
+    let start = local(day, from.unwrap_or(NaiveTime::MIN))?;
+    let end = match to {
+        Some(time) => local(day, time)?,
+        None => local(day + Days::new(1), NaiveTime::MIN)?,
+    };
+
+    let records = read_history(&dir, day, day)?;
+    let timeline: Vec<_> = records
+        .iter()
+        .filter(|record| record.started() <= end && record.at >= start)
+        .filter(|record| peer.is_none_or(|peer| record.involves(peer)))
+        .collect();
+    if json {
+        for record in timeline {
+            println!("{}", serde_json::to_string(record)?);
+        }
+        return Ok(());
+    }
+
+    println!(
+        "{} {}-{}",
+        day,
+        start.format("%H:%M"),
+        if to.is_some() {
+            end.format("%H:%M").to_string()
+        } else {
+            "24:00".to_string()
+        }
+    );
+    let people: Vec<_> = presence(&records, start, end)
+        .into_iter()
+        .filter(|person| peer.is_none_or(|peer| person.is(peer)))
+        .collect();
+    if people.is_empty() && timeline.is_empty() {
+        println!("Nothing recorded in {}", dir.display());
+        return Ok(());
+    }
+
+    println!("\nOn the channel:");
+    for person in &people {
+        println!(
+            "  {:<16} {}-{}  {} spurts, {} talking{}",
+            person.name,
+            person.first_seen.format("%H:%M"),
+            person.last_seen.format("%H:%M"),
+            person.spurts,
+            format_span(person.talking),
+            person
+                .peer
+                .map(|peer| format!("  ({peer})"))
+                .unwrap_or_default()
+        );
+    }
+
+    let mut timeline = timeline;
+    timeline.sort_by_key(|record| record.started());
+    println!("\nTimeline:");
+    for record in timeline {
+        println!("  {record}");
+    }
+    Ok(())
 }
@@ -0,0 +1,143 @@
+//! Debug capture of every datagram the UDP socket receives, with arrival
+//! time and source, for replaying field problems offline (`vl replay`).
+//!
+//! The file is JSON lines: a [`CaptureHeader`], then one [`CaptureRecord`]
+//! per datagram with the payload hex-encoded.
+
+use crate::config::data_dir;
+use anyhow::{Context, Result, bail};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+const CAPTURE_VERSION: u32 = 1;
+
+/// First line of a capture file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CaptureHeader {
+    pub version: u32,
+    /// Wall-clock start of the capture, RFC 3339.
+    pub started: String,
+    /// Local UDP port the datagrams arrived on.
+    pub port: u16,
+}
+
+/// One received datagram.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CaptureRecord {
+    /// Arrival time in microseconds since the capture started.
+    pub at_us: u64,
+    pub from: SocketAddr,
+    #[serde(with = "hex_bytes")]
+    pub datagram: Vec<u8>,
+}
+
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        hex::decode(String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// Writer for a capture in progress.
+pub struct Capture {
+    path: PathBuf,
+    epoch: Instant,
+    /// `None` once a write failed; the capture stops rather than the receiver.
+    file: Mutex<Option<BufWriter<File>>>,
+}
+
+impl Capture {
+    /// Start a capture at `path`, overwriting any existing file.
+    pub fn create(path: &Path, port: u16) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = BufWriter::new(
+            File::create(path).with_context(|| format!("Cannot create {}", path.display()))?,
+        );
+        let header = CaptureHeader {
+            version: CAPTURE_VERSION,
+            started: chrono::Local::now().to_rfc3339(),
+            port,
+        };
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        file.flush()?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            epoch: Instant::now(),
+            file: Mutex::new(Some(file)),
+        })
+    }
+
+    /// `captures/capture-<time>.jsonl` in the data directory.
+    pub fn default_path() -> Option<PathBuf> {
+        let time = chrono::Local::now().format("%Y%m%d-%H%M%S");
+        Some(
+            data_dir()?
+                .join("captures")
+                .join(format!("capture-{time}.jsonl")),
+        )
+    }
+
+    /// Append one datagram, timestamped now.
+    pub fn record(&self, from: SocketAddr, datagram: &[u8]) {
+        let record = CaptureRecord {
+            at_us: self.epoch.elapsed().as_micros() as u64,
+            from,
+            datagram: datagram.to_vec(),
+        };
+        let mut file = self.file.lock().unwrap();
+        let Some(writer) = file.as_mut() else {
+            return;
+        };
+        // Flushed per datagram so a crash or kill keeps everything up to it
+        let written = serde_json::to_writer(&mut *writer, &record)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writeln!(writer))
+            .and_then(|()| writer.flush());
+        if let Err(e) = written {
+            warn!("Capture to {} stopped: {}", self.path.display(), e);
+            *file = None;
+        }
+    }
+}
+
+/// Read a whole capture, records in arrival order.
+pub fn read_capture(path: &Path) -> Result<(CaptureHeader, Vec<CaptureRecord>)> {
+    let file = File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let Some(first) = lines.next() else {
+        bail!("{} is empty", path.display());
+    };
+    let header: CaptureHeader =
+        serde_json::from_str(&first?).context("Not a capture file (bad header)")?;
+    if header.version != CAPTURE_VERSION {
+        bail!("Unsupported capture version {}", header.version);
+    }
+
+    let mut records = Vec::new();
+    for (number, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: CaptureRecord = serde_json::from_str(&line)
+            .with_context(|| format!("Bad record on line {}", number + 2))?;
+        records.push(record);
+    }
+    records.sort_by_key(|record| record.at_us);
+    Ok((header, records))
+}
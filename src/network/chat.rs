@@ -0,0 +1,290 @@
+//! Text chat over the control channel. Every message is retransmitted to each
+//! peer until that peer acknowledges it, and kept in a per-channel history
+//! file so the conversation survives restarts.
+
+use crate::config::data_dir;
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Unacknowledged messages are re-sent this often.
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// A peer that hasn't acknowledged after this many sends is given up on.
+const MAX_ATTEMPTS: u32 = 10;
+/// Longest message, in characters, so it always fits in one datagram.
+pub const MAX_CHAT_LEN: usize = 1000;
+/// Messages kept in memory (and loaded from the history file).
+const HISTORY_LEN: usize = 500;
+/// Message ids remembered per peer to drop retransmitted duplicates.
+const SEEN_LEN: usize = 256;
+
+/// One chat message as sent on the wire and stored in the history file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    /// Random id, echoed back in the acknowledgement.
+    pub id: u64,
+    pub sender: String,
+    /// Unix time in milliseconds, from the sender's clock.
+    pub sent_at: i64,
+    pub text: String,
+}
+
+impl ChatMessage {
+    /// Local time the message was sent, for display.
+    pub fn time(&self) -> String {
+        chrono::DateTime::from_timestamp_millis(self.sent_at)
+            .map(|time| {
+                time.with_timezone(&chrono::Local)
+                    .format("%H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Where an outgoing message stands.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Delivery {
+    /// Received from a peer, or loaded from history.
+    #[default]
+    Done,
+    /// Waiting for acknowledgements.
+    Sending,
+    /// Every peer acknowledged.
+    Delivered,
+    /// These peers never acknowledged.
+    Failed(Vec<SocketAddr>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatEntry {
+    pub message: ChatMessage,
+    /// Sent by us rather than received.
+    pub outgoing: bool,
+    #[serde(skip)]
+    pub delivery: Delivery,
+}
+
+/// An outgoing message still missing acknowledgements.
+struct Pending {
+    message: ChatMessage,
+    /// `None` until the first send, when the peer list is captured.
+    waiting: Option<HashSet<SocketAddr>>,
+    attempts: u32,
+    last_sent: Option<Instant>,
+}
+
+pub struct Chat {
+    name: String,
+    history_path: Option<PathBuf>,
+    entries: Mutex<VecDeque<ChatEntry>>,
+    pending: Mutex<HashMap<u64, Pending>>,
+    seen: Mutex<HashMap<SocketAddr, VecDeque<u64>>>,
+}
+
+impl Chat {
+    /// Chat as `name`; history is loaded from and appended to `history_path`.
+    pub fn new(name: String, history_path: Option<PathBuf>) -> Self {
+        let entries = match &history_path {
+            Some(path) => load_history(path).unwrap_or_else(|e| {
+                warn!("Failed to load chat history: {:#}", e);
+                VecDeque::new()
+            }),
+            None => VecDeque::new(),
+        };
+
+        Self {
+            name,
+            history_path,
+            entries: Mutex::new(entries),
+            pending: Mutex::new(HashMap::new()),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `chat/<channel>.jsonl` in the data directory.
+    pub fn default_history_path(channel: &str) -> Option<PathBuf> {
+        let file: String = channel
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        Some(data_dir()?.join("chat").join(format!("{file}.jsonl")))
+    }
+
+    /// Recent messages, oldest first.
+    pub fn entries(&self) -> Vec<ChatEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Queue a message; it goes out on the next `due` call.
+    pub fn send(&self, text: &str) {
+        let message = ChatMessage {
+            id: rand::random(),
+            sender: self.name.clone(),
+            sent_at: now_millis(),
+            text: text.chars().take(MAX_CHAT_LEN).collect(),
+        };
+
+        self.pending.lock().unwrap().insert(
+            message.id,
+            Pending {
+                message: message.clone(),
+                waiting: None,
+                attempts: 0,
+                last_sent: None,
+            },
+        );
+        self.record(ChatEntry {
+            message,
+            outgoing: true,
+            delivery: Delivery::Sending,
+        });
+    }
+
+    /// A message from `from`; retransmitted duplicates are dropped.
+    pub fn on_message(&self, from: SocketAddr, message: ChatMessage) {
+        {
+            let mut seen = self.seen.lock().unwrap();
+            let ids = seen.entry(from).or_default();
+            if ids.contains(&message.id) {
+                return;
+            }
+            ids.push_back(message.id);
+            if ids.len() > SEEN_LEN {
+                ids.pop_front();
+            }
+        }
+
+        self.record(ChatEntry {
+            message,
+            outgoing: false,
+            delivery: Delivery::Done,
+        });
+    }
+
+    pub fn on_ack(&self, from: SocketAddr, id: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(waiting) = pending.get_mut(&id).and_then(|p| p.waiting.as_mut()) else {
+            return;
+        };
+        waiting.remove(&from);
+        if waiting.is_empty() {
+            pending.remove(&id);
+            self.set_delivery(id, Delivery::Delivered);
+        }
+    }
+
+    /// Messages to (re)send now and who to send them to. Peers that left
+    /// are no longer waited for; peers that never answer are given up on.
+    pub fn due(&self, peers: &[SocketAddr]) -> Vec<(ChatMessage, Vec<SocketAddr>)> {
+        let mut outgoing = Vec::new();
+        let mut finished = Vec::new();
+
+        let mut pending = self.pending.lock().unwrap();
+        for (id, entry) in pending.iter_mut() {
+            if entry
+                .last_sent
+                .is_some_and(|at| at.elapsed() < RETRY_INTERVAL)
+            {
+                continue;
+            }
+
+            let waiting = entry
+                .waiting
+                .get_or_insert_with(|| peers.iter().copied().collect());
+            waiting.retain(|peer| peers.contains(peer));
+
+            if waiting.is_empty() {
+                // Nobody to send to in the first place counts as undelivered
+                let delivery = if entry.attempts == 0 {
+                    Delivery::Failed(Vec::new())
+                } else {
+                    Delivery::Delivered
+                };
+                finished.push((*id, delivery));
+            } else if entry.attempts >= MAX_ATTEMPTS {
+                finished.push((*id, Delivery::Failed(waiting.iter().copied().collect())));
+            } else {
+                entry.attempts += 1;
+                entry.last_sent = Some(Instant::now());
+                outgoing.push((entry.message.clone(), waiting.iter().copied().collect()));
+            }
+        }
+
+        for (id, delivery) in finished {
+            pending.remove(&id);
+            self.set_delivery(id, delivery);
+        }
+        outgoing
+    }
+
+    fn set_delivery(&self, id: u64, delivery: Delivery) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter_mut().rev().find(|e| e.message.id == id) {
+            entry.delivery = delivery;
+        }
+    }
+
+    fn record(&self, entry: ChatEntry) {
+        if let Some(path) = &self.history_path
+            && let Err(e) = append_history(path, &entry)
+        {
+            warn!("Failed to save chat history: {:#}", e);
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(entry);
+        if entries.len() > HISTORY_LEN {
+            entries.pop_front();
+        }
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn load_history(path: &Path) -> Result<VecDeque<ChatEntry>> {
+    if !path.exists() {
+        return Ok(VecDeque::new());
+    }
+
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut entries = VecDeque::new();
+    for line in BufReader::new(file).lines() {
+        // Skip lines a crash may have cut short
+        let Ok(entry) = serde_json::from_str::<ChatEntry>(&line?) else {
+            continue;
+        };
+        entries.push_back(entry);
+        if entries.len() > HISTORY_LEN {
+            entries.pop_front();
+        }
+    }
+    Ok(entries)
+}
+
+fn append_history(path: &Path, entry: &ChatEntry) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
@@ -0,0 +1,115 @@
+//! How a node finds its peers: mDNS on a real network (see `mdns`), or an
+//! in-process registry for tests and for embedding several nodes in one
+//! program.
+
+use crate::network::mdns::PeerNames;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::time::interval;
+
+/// Addresses of the peers on our channel, kept current by discovery.
+pub type Peerlist = Arc<Mutex<Vec<SocketAddr>>>;
+
+/// A source of peers.
+pub trait Discovery: Send + Sync {
+    /// Our own address as peers see it.
+    fn local_addr(&self) -> SocketAddr;
+
+    /// Announce ourselves and keep `peers` and `names` filled with the other
+    /// members of our channel, as tasks on `runtime`.
+    fn start(&self, runtime: &Handle, peers: Peerlist, names: PeerNames);
+}
+
+/// How often local members check the registry.
+const LOCAL_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+struct Member {
+    name: String,
+    channel: String,
+    addr: SocketAddr,
+}
+
+/// Discovery without a network: every member of the same registry sees the
+/// others on its channel, and members that leave disappear at once.
+#[derive(Clone, Default)]
+pub struct LocalRegistry {
+    members: Arc<Mutex<Vec<Member>>>,
+}
+
+impl LocalRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discovery for `name` on `channel`, reachable at `addr`. It joins the
+    /// registry when started and leaves it when dropped.
+    pub fn member(&self, name: &str, channel: &str, addr: SocketAddr) -> LocalDiscovery {
+        LocalDiscovery {
+            registry: self.clone(),
+            me: Member {
+                name: name.to_string(),
+                channel: channel.to_string(),
+                addr,
+            },
+        }
+    }
+}
+
+pub struct LocalDiscovery {
+    registry: LocalRegistry,
+    me: Member,
+}
+
+impl Discovery for LocalDiscovery {
+    fn local_addr(&self) -> SocketAddr {
+        self.me.addr
+    }
+
+    fn start(&self, runtime: &Handle, peers: Peerlist, names: PeerNames) {
+        self.registry.members.lock().unwrap().push(self.me.clone());
+
+        let members = self.registry.members.clone();
+        let me = self.me.clone();
+        runtime.spawn(async move {
+            let mut poll = interval(LOCAL_POLL);
+            loop {
+                poll.tick().await;
+                let others: Vec<Member> = members
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|member| member.channel == me.channel && member.addr != me.addr)
+                    .cloned()
+                    .collect();
+
+                let mut names = names.lock().unwrap();
+                for member in &others {
+                    names.insert(member.addr, member.name.clone());
+                }
+                drop(names);
+
+                let mut peers = peers.lock().unwrap();
+                peers.retain(|peer| others.iter().any(|member| member.addr == *peer));
+                for member in others {
+                    if !peers.contains(&member.addr) {
+                        peers.push(member.addr);
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Drop for LocalDiscovery {
+    fn drop(&mut self) {
+        let addr = self.me.addr;
+        self.registry
+            .members
+            .lock()
+            .unwrap()
+            .retain(|member| member.addr != addr);
+    }
+}
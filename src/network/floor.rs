@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{
+    Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use std::time::{Duration, Instant};
+
+/// A stream counts as talking if a packet arrived this recently.
+pub const TALK_TIMEOUT: Duration = Duration::from_millis(300);
+/// A floor claim expires if it isn't refreshed (lost FloorReleased, crashed peer).
+const FLOOR_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the floor holder re-announces its claim.
+pub const FLOOR_REFRESH: Duration = Duration::from_millis(500);
+
+/// Last time audio arrived from each sender.
+#[derive(Default)]
+pub struct Talkers {
+    last_heard: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl Talkers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn heard(&self, from: SocketAddr) {
+        self.last_heard.lock().unwrap().insert(from, Instant::now());
+    }
+
+    pub fn is_talking(&self, peer: &SocketAddr) -> bool {
+        self.last_heard
+            .lock()
+            .unwrap()
+            .get(peer)
+            .is_some_and(|at| at.elapsed() < TALK_TIMEOUT)
+    }
+
+    pub fn active(&self) -> Vec<SocketAddr> {
+        self.last_heard
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, at)| at.elapsed() < TALK_TIMEOUT)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
+
+/// Half-duplex walkie-talkie mode: only one peer may transmit at a time.
+///
+/// Whoever presses PTT on a free channel announces `FloorTaken` and holds the
+/// floor until release. Everyone else is blocked (and hears a busy tone) while
+/// a remote claim is live. When two peers grab a free floor at once, the lower
+/// address keeps it.
+pub struct FloorControl {
+    enabled: bool,
+    local_addr: SocketAddr,
+    remote_holder: Mutex<Option<(SocketAddr, Instant)>>,
+    holding: AtomicBool,
+    busy: AtomicBool,
+}
+
+impl FloorControl {
+    pub fn new(enabled: bool, local_addr: SocketAddr) -> Self {
+        Self {
+            enabled,
+            local_addr,
+            remote_holder: Mutex::new(None),
+            holding: AtomicBool::new(false),
+            busy: AtomicBool::new(false),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Peer currently holding the floor, if its claim hasn't expired.
+    pub fn remote_holder(&self) -> Option<SocketAddr> {
+        let mut holder = self.remote_holder.lock().unwrap();
+        if holder.is_some_and(|(_, at)| at.elapsed() > FLOOR_TIMEOUT) {
+            *holder = None;
+        }
+        holder.map(|(peer, _)| peer)
+    }
+
+    /// A peer announced or refreshed its claim (audio counts as a claim too).
+    pub fn on_remote_taken(&self, from: SocketAddr) {
+        if !self.enabled {
+            return;
+        }
+
+        // Simultaneous grab: the lower address wins
+        if self.holding.load(Ordering::Relaxed) && self.local_addr < from {
+            return;
+        }
+        if self.holding.swap(false, Ordering::Relaxed) {
+            self.busy.store(true, Ordering::Relaxed);
+        }
+
+        *self.remote_holder.lock().unwrap() = Some((from, Instant::now()));
+    }
+
+    pub fn on_remote_released(&self, from: SocketAddr) {
+        let mut holder = self.remote_holder.lock().unwrap();
+        if holder.is_some_and(|(peer, _)| peer == from) {
+            *holder = None;
+        }
+    }
+
+    /// PTT pressed: take the floor if it's free. Returns whether we got it.
+    pub fn acquire(&self) -> bool {
+        if !self.enabled {
+            return true;
+        }
+
+        let free = self.remote_holder().is_none();
+        self.holding.store(free, Ordering::Relaxed);
+        self.busy.store(!free, Ordering::Relaxed);
+        free
+    }
+
+    /// PTT released. Returns whether we were holding the floor.
+    pub fn release(&self) -> bool {
+        self.busy.store(false, Ordering::Relaxed);
+        self.holding.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn holding(&self) -> bool {
+        self.holding.load(Ordering::Relaxed)
+    }
+
+    /// Whether the mic may transmit right now.
+    pub fn may_transmit(&self) -> bool {
+        !self.enabled || self.holding()
+    }
+
+    /// PTT is held but someone else has the floor - play the busy tone.
+    pub fn busy(&self) -> bool {
+        self.busy.load(Ordering::Relaxed)
+    }
+}
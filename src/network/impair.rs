@@ -0,0 +1,319 @@
+//! Network impairment simulator: random and burst loss, delay, jitter,
+//! duplication and reordering applied to datagrams on their way out of or
+//! into the UDP socket, for testing the jitter buffer and FEC without
+//! special network tools.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+/// Extra delay of a reordered datagram, enough for the next couple of
+/// 20ms frames to overtake it.
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// What to do to datagrams. Probabilities are 0.0 to 1.0.
+#[derive(Debug, Clone, Default)]
+pub struct Impairment {
+    /// Chance of dropping any one datagram.
+    pub loss: f64,
+    /// Chance of a loss burst starting at any one datagram.
+    pub burst_loss: f64,
+    /// Datagrams dropped in a row once a burst starts.
+    pub burst_len: u32,
+    /// Fixed delay added to every datagram.
+    pub delay: Duration,
+    /// Random extra delay, uniform between zero and this.
+    pub jitter: Duration,
+    pub duplicate: f64,
+    /// Chance of a datagram being held back behind later ones.
+    pub reorder: f64,
+    /// Seed for reproducible runs; random if `None`.
+    pub seed: Option<u64>,
+}
+
+impl Impairment {
+    /// Whether any impairment is configured at all.
+    pub fn is_active(&self) -> bool {
+        self.loss > 0.0
+            || self.burst_loss > 0.0
+            || !self.delay.is_zero()
+            || !self.jitter.is_zero()
+            || self.duplicate > 0.0
+            || self.reorder > 0.0
+    }
+}
+
+impl fmt::Display for Impairment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if self.loss > 0.0 {
+            parts.push(format!("{:.1}% loss", self.loss * 100.0));
+        }
+        if self.burst_loss > 0.0 {
+            parts.push(format!(
+                "{:.1}% bursts of {}",
+                self.burst_loss * 100.0,
+                self.burst_len
+            ));
+        }
+        if !self.delay.is_zero() {
+            parts.push(format!("{} ms delay", self.delay.as_millis()));
+        }
+        if !self.jitter.is_zero() {
+            parts.push(format!("{} ms jitter", self.jitter.as_millis()));
+        }
+        if self.duplicate > 0.0 {
+            parts.push(format!("{:.1}% duplicated", self.duplicate * 100.0));
+        }
+        if self.reorder > 0.0 {
+            parts.push(format!("{:.1}% reordered", self.reorder * 100.0));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Impairments for each direction through the socket.
+#[derive(Debug, Clone, Default)]
+pub struct Impairments {
+    pub send: Option<Impairment>,
+    pub receive: Option<Impairment>,
+}
+
+/// Decides the fate of each datagram passing through.
+struct Impairer {
+    impairment: Impairment,
+    rng: StdRng,
+    /// Datagrams still to drop in the current burst.
+    burst_left: u32,
+}
+
+impl Impairer {
+    fn new(impairment: Impairment) -> Self {
+        let rng = match impairment.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            impairment,
+            rng,
+            burst_left: 0,
+        }
+    }
+
+    /// Copies of `item` to deliver and the delay before each; empty if lost.
+    fn apply<T: Clone>(&mut self, item: T) -> Vec<(Duration, T)> {
+        let impairment = &self.impairment;
+        if self.burst_left > 0 {
+            self.burst_left -= 1;
+            return Vec::new();
+        }
+        if self.rng.gen_bool(impairment.burst_loss) {
+            self.burst_left = impairment.burst_len.saturating_sub(1);
+            return Vec::new();
+        }
+        if self.rng.gen_bool(impairment.loss) {
+            return Vec::new();
+        }
+
+        let copies = if self.rng.gen_bool(impairment.duplicate) {
+            2
+        } else {
+            1
+        };
+        let mut delivered = Vec::with_capacity(copies);
+        for _ in 1..copies {
+            delivered.push((self.delay(), item.clone()));
+        }
+        delivered.push((self.delay(), item));
+        delivered
+    }
+
+    fn delay(&mut self) -> Duration {
+        let impairment = &self.impairment;
+        let mut delay = impairment.delay + impairment.jitter.mul_f64(self.rng.r#gen::<f64>());
+        if self.rng.gen_bool(impairment.reorder) {
+            delay += REORDER_DELAY;
+        }
+        delay
+    }
+}
+
+/// A channel stage that impairs everything passing from the returned
+/// sender to `output`. Delayed items are delivered by timers, so they may
+/// overtake each other just like on a real network.
+pub fn impaired<T: Clone + Send + 'static>(
+    runtime: &Handle,
+    impairment: Impairment,
+    output: mpsc::Sender<T>,
+    capacity: usize,
+) -> mpsc::Sender<T> {
+    let (input, mut pending) = mpsc::channel(capacity);
+    let mut impairer = Impairer::new(impairment);
+    runtime.spawn(async move {
+        while let Some(item) = pending.recv().await {
+            for (delay, item) in impairer.apply(item) {
+                if delay.is_zero() {
+                    let _ = output.try_send(item);
+                    continue;
+                }
+                let output = output.clone();
+                tokio::spawn(async move {
+                    sleep(delay).await;
+                    let _ = output.try_send(item);
+                });
+            }
+        }
+    });
+    input
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKETS: usize = 10_000;
+    /// Send interval of the simulated stream.
+    const FRAME: Duration = Duration::from_millis(20);
+
+    fn seeded(impairment: Impairment) -> Impairer {
+        Impairer::new(Impairment {
+            seed: Some(7),
+            ..impairment
+        })
+    }
+
+    /// Every copy delivered as `(arrival, sequence)`, in arrival order.
+    fn run(impairer: &mut Impairer) -> Vec<(Duration, usize)> {
+        let mut arrivals: Vec<_> = (0..PACKETS)
+            .flat_map(|sequence| {
+                let sent = FRAME * sequence as u32;
+                impairer
+                    .apply(sequence)
+                    .into_iter()
+                    .map(move |(delay, sequence)| (sent + delay, sequence))
+            })
+            .collect();
+        arrivals.sort();
+        arrivals
+    }
+
+    fn assert_near(count: usize, share: f64) {
+        let expected = PACKETS as f64 * share;
+        assert!(
+            (count as f64 - expected).abs() < expected * 0.1,
+            "{count} is not within 10% of {expected}"
+        );
+    }
+
+    #[test]
+    fn unimpaired_delivers_everything_in_order() {
+        let mut impairer = seeded(Impairment::default());
+        let arrivals = run(&mut impairer);
+        let sequences: Vec<_> = arrivals.iter().map(|&(_, sequence)| sequence).collect();
+        assert_eq!(sequences, (0..PACKETS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn random_loss_drops_its_share() {
+        let mut impairer = seeded(Impairment {
+            loss: 0.2,
+            ..Default::default()
+        });
+        assert_near(PACKETS - run(&mut impairer).len(), 0.2);
+    }
+
+    #[test]
+    fn bursts_drop_consecutive_datagrams() {
+        let mut impairer = seeded(Impairment {
+            burst_loss: 0.02,
+            burst_len: 5,
+            ..Default::default()
+        });
+        let delivered: Vec<_> = run(&mut impairer).into_iter().map(|(_, s)| s).collect();
+
+        // Every gap is at least one whole burst
+        let gaps: Vec<_> = delivered
+            .windows(2)
+            .map(|pair| pair[1] - pair[0] - 1)
+            .filter(|&gap| gap > 0)
+            .collect();
+        assert!(!gaps.is_empty());
+        assert!(gaps.iter().all(|&gap| gap >= 5), "{gaps:?}");
+    }
+
+    #[test]
+    fn duplicates_add_one_extra_copy() {
+        let mut impairer = seeded(Impairment {
+            duplicate: 0.1,
+            ..Default::default()
+        });
+        let arrivals = run(&mut impairer);
+        assert_near(arrivals.len() - PACKETS, 0.1);
+
+        let mut copies = vec![0; PACKETS];
+        for (_, sequence) in arrivals {
+            copies[sequence] += 1;
+        }
+        assert!(copies.iter().all(|&count| count == 1 || count == 2));
+    }
+
+    #[test]
+    fn reordered_datagrams_arrive_after_later_ones() {
+        let mut impairer = seeded(Impairment {
+            reorder: 0.1,
+            ..Default::default()
+        });
+        let arrivals = run(&mut impairer);
+        assert_eq!(arrivals.len(), PACKETS, "reordering loses nothing");
+
+        let held_back = arrivals
+            .iter()
+            .filter(|&&(arrival, sequence)| arrival > FRAME * sequence as u32)
+            .count();
+        assert_near(held_back, 0.1);
+
+        // Held back datagrams are overtaken by the following frames
+        let late = arrivals
+            .windows(2)
+            .filter(|pair| pair[0].1 > pair[1].1)
+            .count();
+        assert!(late > 0);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let delay = Duration::from_millis(30);
+        let jitter = Duration::from_millis(10);
+        let mut impairer = seeded(Impairment {
+            delay,
+            jitter,
+            ..Default::default()
+        });
+        for sequence in 0..PACKETS {
+            let delivered = impairer.apply(sequence);
+            assert_eq!(delivered.len(), 1);
+            let (extra, _) = delivered[0];
+            assert!(extra >= delay && extra <= delay + jitter, "{extra:?}");
+        }
+    }
+
+    #[test]
+    fn same_seed_same_fate() {
+        let impairment = Impairment {
+            loss: 0.1,
+            burst_loss: 0.01,
+            burst_len: 3,
+            jitter: Duration::from_millis(15),
+            duplicate: 0.05,
+            reorder: 0.05,
+            ..Default::default()
+        };
+        let first = run(&mut seeded(impairment.clone()));
+        let second = run(&mut seeded(impairment));
+        assert_eq!(first, second);
+    }
+}
@@ -1,27 +1,43 @@
+use crate::network::discovery::{Discovery, Peerlist};
 use local_ip_address::local_ip;
 use log::{info, warn};
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::thread::spawn;
 use std::{
     collections::HashMap,
     net::{IpAddr, Ipv4Addr},
 };
+use tokio::runtime::Handle;
 
-type Peerlist = Arc<Mutex<Vec<SocketAddr>>>;
+/// mDNS instance name of each discovered peer - the peer's stable identity.
+pub type PeerNames = Arc<Mutex<HashMap<SocketAddr, String>>>;
+
+/// Identity used for per-peer settings: the instance name, or the address
+/// for peers that haven't been resolved over mDNS.
+pub fn peer_identity(names: &HashMap<SocketAddr, String>, addr: &SocketAddr) -> String {
+    names.get(addr).cloned().unwrap_or_else(|| addr.to_string())
+}
+
+/// TXT property carrying the channel an instance joined.
+const CHANNEL_PROPERTY: &str = "channel";
+/// Channel assumed for instances that don't announce one.
+pub const DEFAULT_CHANNEL: &str = "default";
 
 pub struct Data {
     pub service_type: String,
     instance_name: String,
+    channel: String,
     pub ip: IpAddr,
     host_name: String,
     port: u16,
     properties: HashMap<String, String>,
+    /// Announcing and browsing daemons, shut down when we're dropped.
+    daemons: Mutex<Vec<ServiceDaemon>>,
 }
 
 impl Data {
-    pub fn new(instant_name: &str, port: u16) -> Self {
+    pub fn new(instant_name: &str, port: u16, channel: &str) -> Self {
         let service_type = "_walkietalkie._udp.local.".to_string();
         let instance_name = instant_name.to_string();
 
@@ -35,15 +51,17 @@ impl Data {
 
         // Generate hostname from instance name
         let host_name = format!("{}.local.", instant_name.replace(" ", "-").to_lowercase());
-        let properties = HashMap::new();
+        let properties = HashMap::from([(CHANNEL_PROPERTY.to_string(), channel.to_string())]);
 
         Data {
             service_type,
             instance_name,
+            channel: channel.to_string(),
             ip,
             host_name,
             port,
             properties,
+            daemons: Mutex::new(Vec::new()),
         }
     }
 
@@ -68,19 +86,24 @@ impl Data {
             self.instance_name, self.ip, self.port
         );
         info!("Keep this running... announce");
+        self.daemons.lock().unwrap().push(mdns);
     }
 
-    pub fn discovery(&self, peers_clone: Peerlist) {
+    /// Browse for peers on our channel as a task on the network runtime.
+    pub fn discovery(&self, runtime: &Handle, peers_clone: Peerlist, peer_names: PeerNames) {
         let mdns = ServiceDaemon::new().expect("Failed to create daemon");
         let receiver = mdns
             .browse(&self.service_type)
             .expect("Failed to browse for services");
         let self_addr = SocketAddr::new(self.ip, self.port);
+        let name_suffix = format!(".{}", self.service_type);
+        let channel = self.channel.clone();
 
         info!("Browsing for services... discovery");
+        self.daemons.lock().unwrap().push(mdns);
 
-        spawn(move || {
-            while let Ok(event) = receiver.recv() {
+        runtime.spawn(async move {
+            while let Ok(event) = receiver.recv_async().await {
                 if let ServiceEvent::ServiceResolved(info) = event
                     && let Some(addr) = info.get_addresses().iter().next()
                 {
@@ -89,6 +112,17 @@ impl Data {
                     if peer == self_addr {
                         continue; // Skip self
                     }
+                    let peer_channel = info
+                        .get_property_val_str(CHANNEL_PROPERTY)
+                        .unwrap_or(DEFAULT_CHANNEL);
+                    if peer_channel != channel {
+                        continue;
+                    }
+
+                    let fullname = info.get_fullname();
+                    let name = fullname.strip_suffix(&name_suffix).unwrap_or(fullname);
+                    peer_names.lock().unwrap().insert(peer, name.to_string());
+
                     let mut peers = peers_clone.lock().unwrap();
                     if !peers.contains(&peer) {
                         peers.push(peer);
@@ -99,3 +133,23 @@ impl Data {
         });
     }
 }
+
+impl Discovery for Data {
+    fn local_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+
+    fn start(&self, runtime: &Handle, peers: Peerlist, names: PeerNames) {
+        self.announce();
+        self.discovery(runtime, peers, names);
+    }
+}
+
+impl Drop for Data {
+    fn drop(&mut self) {
+        // Withdraws our announcement and stops browsing
+        for daemon in self.daemons.lock().unwrap().drain(..) {
+            let _ = daemon.shutdown();
+        }
+    }
+}
@@ -1,2 +1,14 @@
+pub mod capture;
+pub mod chat;
+pub mod discovery;
+pub mod floor;
+pub mod http;
+pub mod impair;
 pub mod mdns;
+pub mod protocol;
+pub mod report;
+pub mod rtp;
+pub mod stats;
+pub mod transfer;
 pub mod udp;
+pub mod webrtc;
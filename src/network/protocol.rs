@@ -0,0 +1,144 @@
+use crate::network::chat::ChatMessage;
+use crate::network::report::ReceiverReport;
+use crate::network::rtp::{self, ReportBlock};
+use crate::network::transfer::FileOffer;
+use serde::{Deserialize, Serialize};
+
+/// Marker byte for an Opus audio datagram: `[0x01][header][opus packet]`.
+pub const AUDIO_MARKER: u8 = 0x01;
+/// Marker byte for a control datagram: `[0x02][bincode ControlMessage]`.
+pub const CONTROL_MARKER: u8 = 0x02;
+
+/// Out-of-band messages exchanged between peers on the audio socket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ControlMessage {
+    /// Sender is holding the floor (re-sent periodically while talking).
+    FloorTaken,
+    /// Sender let go of the floor.
+    FloorReleased,
+    /// Reception quality of the audio the recipient sends us.
+    ReceiverReport(ReceiverReport),
+    /// WebRTC offer SDP (all ICE candidates included).
+    WebRtcOffer(String),
+    /// WebRTC answer SDP to a previous offer.
+    WebRtcAnswer(String),
+    /// Text chat; re-sent until acknowledged.
+    Chat(ChatMessage),
+    /// Acknowledges the chat message with this id.
+    ChatAck(u64),
+    /// Sender wants to send us a file; repeated until answered.
+    FileOffer(FileOffer),
+    FileAccept(u64),
+    FileReject(u64),
+    /// One chunk of an accepted file.
+    FileChunk {
+        id: u64,
+        index: u32,
+        data: Vec<u8>,
+    },
+    /// Every chunk before `next` has arrived.
+    FileAck {
+        id: u64,
+        next: u32,
+    },
+    /// All chunks arrived; `ok` is the checksum result.
+    FileDone {
+        id: u64,
+        ok: bool,
+    },
+    /// Either side gave up on the transfer.
+    FileCancel(u64),
+    /// Round-trip probe; answered with a `Pong` carrying the same token.
+    Ping(u64),
+    Pong(u64),
+}
+
+/// Largest Opus frame: 60ms at 48kHz, per channel.
+pub const MAX_FRAME_SAMPLES: u16 = 2880;
+
+/// Stream parameters sent ahead of every Opus packet so the receiver can
+/// size its decode buffer without knowing the sender's settings.
+///
+/// Wire layout: `[channels: u8][frame_samples: u16 LE][sequence: u16 LE][timestamp: u32 LE]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioHeader {
+    pub channels: u8,
+    /// Samples per channel in one frame.
+    pub frame_samples: u16,
+    /// Incremented by one per packet; gaps are losses.
+    pub sequence: u16,
+    /// Sampling instant of the first sample, in 48kHz ticks.
+    pub timestamp: u32,
+}
+
+impl AudioHeader {
+    pub const LEN: usize = 9;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.channels);
+        out.extend_from_slice(&self.frame_samples.to_le_bytes());
+        out.extend_from_slice(&self.sequence.to_le_bytes());
+        out.extend_from_slice(&self.timestamp.to_le_bytes());
+    }
+
+    fn read(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; Self::LEN] = bytes.get(..Self::LEN)?.try_into().ok()?;
+        let header = Self {
+            channels: bytes[0],
+            frame_samples: u16::from_le_bytes([bytes[1], bytes[2]]),
+            sequence: u16::from_le_bytes([bytes[3], bytes[4]]),
+            timestamp: u32::from_le_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]),
+        };
+
+        let valid = matches!(header.channels, 1 | 2)
+            && (1..=MAX_FRAME_SAMPLES).contains(&header.frame_samples);
+        valid.then_some(header)
+    }
+}
+
+/// A parsed datagram.
+#[derive(Debug)]
+pub enum Packet<'a> {
+    Audio {
+        header: AudioHeader,
+        /// Stream identifier, for RTP packets.
+        ssrc: Option<u32>,
+        payload: &'a [u8],
+    },
+    Control(ControlMessage),
+    /// Report blocks from an RTCP SR/RR.
+    Rtcp(Vec<ReportBlock>),
+}
+
+pub fn encode_audio(header: &AudioHeader, opus: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(opus.len() + 1 + AudioHeader::LEN);
+    datagram.push(AUDIO_MARKER);
+    header.write(&mut datagram);
+    datagram.extend_from_slice(opus);
+    datagram
+}
+
+pub fn encode_control(message: &ControlMessage) -> Vec<u8> {
+    let mut datagram = vec![CONTROL_MARKER];
+    datagram.extend(bincode::serialize(message).expect("Control messages always serialize"));
+    datagram
+}
+
+/// Parse a datagram; `None` for empty, unknown or malformed packets.
+pub fn decode(datagram: &[u8]) -> Option<Packet<'_>> {
+    if rtp::is_rtp(datagram) {
+        return rtp::decode(datagram);
+    }
+
+    let (&marker, body) = datagram.split_first()?;
+
+    match marker {
+        AUDIO_MARKER if body.len() > AudioHeader::LEN => Some(Packet::Audio {
+            header: AudioHeader::read(body)?,
+            ssrc: None,
+            payload: &body[AudioHeader::LEN..],
+        }),
+        CONTROL_MARKER => bincode::deserialize(body).ok().map(Packet::Control),
+        _ => None,
+    }
+}
@@ -0,0 +1,147 @@
+use crate::audio::sad::OPUS_SAMPLE_RATE;
+use crate::network::protocol::AudioHeader;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often receivers report back to each sender they hear.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// RTCP-style reception report (RFC 3550 section 6.4.1), sent by a receiver
+/// back to the sender of an audio stream.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ReceiverReport {
+    /// Packets lost since the previous report, as a fraction of 256.
+    pub fraction_lost: u8,
+    /// Packets lost since the stream started.
+    pub cumulative_lost: u32,
+    /// Highest sequence number received, extended with wrap-around cycles.
+    pub highest_seq: u32,
+    /// Interarrival jitter in 48kHz ticks.
+    pub jitter: u32,
+}
+
+impl ReceiverReport {
+    /// Loss since the previous report, 0.0 to 1.0.
+    pub fn loss(&self) -> f32 {
+        self.fraction_lost as f32 / 256.0
+    }
+
+    pub fn jitter_ms(&self) -> f32 {
+        self.jitter as f32 * 1000.0 / OPUS_SAMPLE_RATE as f32
+    }
+}
+
+/// Loss and jitter bookkeeping for one incoming stream.
+struct StreamStats {
+    base_seq: u32,
+    highest_seq: u32,
+    received: u32,
+    expected_prior: u32,
+    received_prior: u32,
+    /// Last relative transit time (arrival - timestamp), in ticks.
+    transit: Option<i64>,
+    /// RFC 3550 A.8 running jitter estimate, in ticks.
+    jitter: f64,
+}
+
+impl StreamStats {
+    fn new(sequence: u16) -> Self {
+        Self {
+            base_seq: sequence as u32,
+            highest_seq: sequence as u32,
+            received: 0,
+            expected_prior: 0,
+            received_prior: 0,
+            transit: None,
+            jitter: 0.0,
+        }
+    }
+
+    fn on_packet(&mut self, header: &AudioHeader, arrival_ticks: i64) {
+        // Place the 16-bit sequence next to the highest one seen so far
+        let delta = header.sequence.wrapping_sub(self.highest_seq as u16) as i16;
+        let extended = self.highest_seq as i64 + delta as i64;
+        if extended > self.highest_seq as i64 {
+            self.highest_seq = extended as u32;
+        }
+        self.received += 1;
+
+        let transit = arrival_ticks - header.timestamp as i64;
+        if let Some(previous) = self.transit {
+            let d = (transit - previous).abs() as f64;
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+    }
+
+    fn report(&mut self) -> ReceiverReport {
+        let expected = self.highest_seq - self.base_seq + 1;
+        let expected_interval = expected - self.expected_prior;
+        let received_interval = self.received - self.received_prior;
+        self.expected_prior = expected;
+        self.received_prior = self.received;
+
+        let lost_interval = expected_interval.saturating_sub(received_interval);
+        let fraction_lost = (lost_interval << 8)
+            .checked_div(expected_interval)
+            .map_or(0, |fraction| fraction.min(255) as u8);
+
+        ReceiverReport {
+            fraction_lost,
+            cumulative_lost: expected.saturating_sub(self.received),
+            highest_seq: self.highest_seq,
+            jitter: self.jitter as u32,
+        }
+    }
+}
+
+/// Reception statistics for every stream we receive.
+pub struct ReceptionStats {
+    epoch: Instant,
+    streams: Mutex<HashMap<SocketAddr, StreamStats>>,
+}
+
+impl Default for ReceptionStats {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl ReceptionStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on_packet(&self, from: SocketAddr, header: &AudioHeader) {
+        self.on_packet_at(from, header, self.epoch.elapsed());
+    }
+
+    /// A packet that arrived `arrival` after this tracker was created
+    /// (replayed captures supply their recorded times).
+    pub fn on_packet_at(&self, from: SocketAddr, header: &AudioHeader, arrival: Duration) {
+        let arrival_ticks = (arrival.as_secs_f64() * OPUS_SAMPLE_RATE as f64) as i64;
+        self.streams
+            .lock()
+            .unwrap()
+            .entry(from)
+            .or_insert_with(|| StreamStats::new(header.sequence))
+            .on_packet(header, arrival_ticks);
+    }
+
+    /// Reports for every stream that delivered packets since the last call.
+    pub fn take_reports(&self) -> Vec<(SocketAddr, ReceiverReport)> {
+        self.streams
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|(_, stats)| stats.received > stats.received_prior)
+            .map(|(from, stats)| (*from, stats.report()))
+            .collect()
+    }
+}
@@ -0,0 +1,432 @@
+//! Optional RTP wire format (RFC 3550 headers, RFC 7587 Opus payload) with
+//! RTCP sender/receiver reports multiplexed on the audio socket (RFC 5761),
+//! so streams can be inspected in Wireshark or recorded with ffmpeg/GStreamer.
+
+use crate::audio::codec::{CodecConfig, Profile};
+use crate::audio::sad::OPUS_SAMPLE_RATE;
+use crate::network::protocol::{AudioHeader, Packet};
+use crate::network::report::ReceiverReport;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Dynamic payload type we send Opus as (the de-facto WebRTC choice).
+pub const OPUS_PAYLOAD_TYPE: u8 = 111;
+
+const RTP_VERSION: u8 = 2;
+const RTP_HEADER_LEN: usize = 12;
+const RTCP_SR: u8 = 200;
+const RTCP_RR: u8 = 201;
+const REPORT_BLOCK_LEN: usize = 24;
+/// Seconds between the NTP epoch (1900) and the Unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// A reception report block from an incoming SR/RR.
+#[derive(Debug, Clone, Copy)]
+pub struct ReportBlock {
+    /// The stream the report is about.
+    pub ssrc: u32,
+    pub report: ReceiverReport,
+}
+
+/// Our outgoing RTP stream plus the SSRCs of the streams we receive.
+pub struct RtpSession {
+    enabled: bool,
+    /// Extra destination (e.g. ffmpeg) that gets our stream as RTP.
+    forward: Option<SocketAddr>,
+    ssrc: u32,
+    packets_sent: AtomicU32,
+    octets_sent: AtomicU32,
+    last_timestamp: AtomicU32,
+    sent_since_report: AtomicBool,
+    remote_ssrcs: Mutex<HashMap<SocketAddr, u32>>,
+}
+
+impl RtpSession {
+    pub fn new(enabled: bool, forward: Option<SocketAddr>) -> Self {
+        Self {
+            enabled,
+            forward,
+            ssrc: rand::random(),
+            packets_sent: AtomicU32::new(0),
+            octets_sent: AtomicU32::new(0),
+            last_timestamp: AtomicU32::new(0),
+            sent_since_report: AtomicBool::new(false),
+            remote_ssrcs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether peers get RTP instead of the native format.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn forward(&self) -> Option<SocketAddr> {
+        self.forward
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Remember which SSRC a peer sends with, so our reports can name it.
+    pub fn on_remote_stream(&self, from: SocketAddr, ssrc: u32) {
+        self.remote_ssrcs.lock().unwrap().insert(from, ssrc);
+    }
+
+    pub fn remote_ssrc(&self, from: &SocketAddr) -> Option<u32> {
+        self.remote_ssrcs.lock().unwrap().get(from).copied()
+    }
+
+    /// Wrap one Opus packet in an RTP header.
+    pub fn encode(&self, header: &AudioHeader, opus: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(RTP_HEADER_LEN + opus.len());
+        datagram.push(RTP_VERSION << 6);
+        datagram.push(OPUS_PAYLOAD_TYPE);
+        datagram.extend_from_slice(&header.sequence.to_be_bytes());
+        datagram.extend_from_slice(&header.timestamp.to_be_bytes());
+        datagram.extend_from_slice(&self.ssrc.to_be_bytes());
+        datagram.extend_from_slice(opus);
+
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.octets_sent
+            .fetch_add(opus.len() as u32, Ordering::Relaxed);
+        self.last_timestamp
+            .store(header.timestamp, Ordering::Relaxed);
+        self.sent_since_report.store(true, Ordering::Relaxed);
+
+        datagram
+    }
+
+    /// RTCP sender report, if we sent anything since the last one.
+    pub fn sender_report(&self) -> Option<Vec<u8>> {
+        if !self.sent_since_report.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let ntp_seconds = (now.as_secs() + NTP_UNIX_OFFSET) as u32;
+        let ntp_fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+
+        let mut packet = rtcp_header(RTCP_SR, 0, 6);
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(&ntp_seconds.to_be_bytes());
+        packet.extend_from_slice(&(ntp_fraction as u32).to_be_bytes());
+        packet.extend_from_slice(&self.last_timestamp.load(Ordering::Relaxed).to_be_bytes());
+        packet.extend_from_slice(&self.packets_sent.load(Ordering::Relaxed).to_be_bytes());
+        packet.extend_from_slice(&self.octets_sent.load(Ordering::Relaxed).to_be_bytes());
+        Some(packet)
+    }
+
+    /// RTCP receiver report about the stream `source_ssrc`.
+    pub fn receiver_report(&self, source_ssrc: u32, report: &ReceiverReport) -> Vec<u8> {
+        let mut packet = rtcp_header(RTCP_RR, 1, 7);
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(&source_ssrc.to_be_bytes());
+        let lost = report.cumulative_lost.min(0x00FF_FFFF);
+        packet.extend_from_slice(&(((report.fraction_lost as u32) << 24) | lost).to_be_bytes());
+        packet.extend_from_slice(&report.highest_seq.to_be_bytes());
+        packet.extend_from_slice(&report.jitter.to_be_bytes());
+        // No SR timing (LSR/DLSR) - RTT is not derived from RTCP here
+        packet.extend_from_slice(&[0; 8]);
+        packet
+    }
+
+    /// SDP describing our stream as it arrives at `dest`, for `ffmpeg -i`.
+    pub fn sdp(
+        &self,
+        instance_name: &str,
+        local_ip: &str,
+        dest: SocketAddr,
+        codec: &CodecConfig,
+    ) -> String {
+        let ip_version = if dest.is_ipv6() { "IP6" } else { "IP4" };
+        let stereo = u8::from(codec.profile == Profile::Music);
+
+        format!(
+            "v=0\r\n\
+             o=- {ssrc} 0 IN {ip_version} {local_ip}\r\n\
+             s={instance_name}\r\n\
+             c=IN {ip_version} {dest_ip}\r\n\
+             t=0 0\r\n\
+             m=audio {port} RTP/AVP {pt}\r\n\
+             a=rtpmap:{pt} opus/{rate}/2\r\n\
+             a=fmtp:{pt} useinbandfec=1;stereo={stereo};sprop-stereo={stereo}\r\n\
+             a=ptime:{ptime}\r\n\
+             a=ssrc:{ssrc} cname:{instance_name}\r\n\
+             a=recvonly\r\n",
+            ssrc = self.ssrc,
+            dest_ip = dest.ip(),
+            port = dest.port(),
+            pt = OPUS_PAYLOAD_TYPE,
+            rate = OPUS_SAMPLE_RATE,
+            ptime = codec.frame_ms,
+        )
+    }
+}
+
+/// `len_words` is the packet length in 32-bit words minus one.
+fn rtcp_header(packet_type: u8, count: u8, len_words: u16) -> Vec<u8> {
+    let mut packet = vec![(RTP_VERSION << 6) | count, packet_type];
+    packet.extend_from_slice(&len_words.to_be_bytes());
+    packet
+}
+
+/// RTP and RTCP both start with version 2; native packets start with 0x01/0x02.
+pub fn is_rtp(datagram: &[u8]) -> bool {
+    datagram.first().is_some_and(|b| b >> 6 == RTP_VERSION)
+}
+
+/// Parse an RTP or RTCP datagram.
+pub fn decode(datagram: &[u8]) -> Option<Packet<'_>> {
+    // RFC 5761: RTCP packet types 192-223 never clash with RTP payload types
+    match datagram.get(1)? {
+        192..=223 => Some(Packet::Rtcp(decode_rtcp(datagram))),
+        _ => decode_rtp(datagram),
+    }
+}
+
+fn decode_rtp(datagram: &[u8]) -> Option<Packet<'_>> {
+    if datagram.len() < RTP_HEADER_LEN {
+        return None;
+    }
+
+    let padding = datagram[0] & 0x20 != 0;
+    let extension = datagram[0] & 0x10 != 0;
+    let csrc_count = (datagram[0] & 0x0F) as usize;
+    let sequence = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let timestamp = u32::from_be_bytes(datagram[4..8].try_into().ok()?);
+    let ssrc = u32::from_be_bytes(datagram[8..12].try_into().ok()?);
+
+    let mut start = RTP_HEADER_LEN + csrc_count * 4;
+    if extension {
+        let words = datagram.get(start + 2..start + 4)?;
+        start += 4 + u16::from_be_bytes([words[0], words[1]]) as usize * 4;
+    }
+    let mut end = datagram.len();
+    if padding {
+        end = end.checked_sub(*datagram.last()? as usize)?;
+    }
+    let payload = datagram.get(start..end).filter(|p| !p.is_empty())?;
+
+    Some(Packet::Audio {
+        header: opus_header(payload, sequence, timestamp)?,
+        ssrc: Some(ssrc),
+        payload,
+    })
+}
+
+/// Build our audio header for an RTP Opus payload. RFC 7587 carries no frame
+/// size, so it's read from the Opus TOC byte.
+pub fn opus_header(payload: &[u8], sequence: u16, timestamp: u32) -> Option<AudioHeader> {
+    let channels = match opus::packet::get_nb_channels(payload).ok()? {
+        opus::Channels::Mono => 1,
+        opus::Channels::Stereo => 2,
+    };
+    let frame_samples = opus::packet::get_nb_samples(payload, OPUS_SAMPLE_RATE).ok()?;
+
+    Some(AudioHeader {
+        channels,
+        frame_samples: u16::try_from(frame_samples).ok()?,
+        sequence,
+        timestamp,
+    })
+}
+
+/// Report blocks from every SR/RR in a compound RTCP packet.
+fn decode_rtcp(mut datagram: &[u8]) -> Vec<ReportBlock> {
+    let mut blocks = Vec::new();
+
+    while datagram.len() >= 4 {
+        let count = (datagram[0] & 0x1F) as usize;
+        let packet_type = datagram[1];
+        let len = (u16::from_be_bytes([datagram[2], datagram[3]]) as usize + 1) * 4;
+        let Some(packet) = datagram.get(..len) else {
+            break;
+        };
+        datagram = &datagram[len..];
+
+        let first_block = match packet_type {
+            RTCP_SR => 28,
+            RTCP_RR => 8,
+            _ => continue,
+        };
+        let report_blocks = packet
+            .get(first_block..)
+            .unwrap_or_default()
+            .chunks_exact(REPORT_BLOCK_LEN)
+            .take(count);
+
+        for block in report_blocks {
+            let word = |i: usize| u32::from_be_bytes(block[i..i + 4].try_into().unwrap());
+            blocks.push(ReportBlock {
+                ssrc: word(0),
+                report: ReceiverReport {
+                    fraction_lost: block[4],
+                    cumulative_lost: word(4) & 0x00FF_FFFF,
+                    highest_seq: word(8),
+                    jitter: word(12),
+                },
+            });
+        }
+    }
+
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SILK wideband, 20ms, mono, one frame.
+    const OPUS: [u8; 4] = [0x58, 0xA1, 0xB2, 0xC3];
+
+    fn audio(packet: Option<Packet<'_>>) -> (AudioHeader, Option<u32>, &[u8]) {
+        match packet {
+            Some(Packet::Audio {
+                header,
+                ssrc,
+                payload,
+            }) => (header, ssrc, payload),
+            other => panic!("expected audio, got {other:?}"),
+        }
+    }
+
+    fn rtcp(packet: Option<Packet<'_>>) -> Vec<ReportBlock> {
+        match packet {
+            Some(Packet::Rtcp(blocks)) => blocks,
+            other => panic!("expected RTCP, got {other:?}"),
+        }
+    }
+
+    /// RTP fixed header with the given first byte, sequence 5, timestamp 960
+    /// and SSRC 0x01020304.
+    fn rtp_header(first: u8) -> Vec<u8> {
+        let mut datagram = vec![first, OPUS_PAYLOAD_TYPE, 0, 5];
+        datagram.extend_from_slice(&960u32.to_be_bytes());
+        datagram.extend_from_slice(&0x0102_0304u32.to_be_bytes());
+        datagram
+    }
+
+    #[test]
+    fn encoded_packet_decodes_to_the_same_stream() {
+        let session = RtpSession::new(true, None);
+        let header = AudioHeader {
+            channels: 1,
+            frame_samples: 960,
+            sequence: 65_535,
+            timestamp: 123_456,
+        };
+        let datagram = session.encode(&header, &OPUS);
+        assert!(is_rtp(&datagram));
+
+        let (decoded, ssrc, payload) = audio(decode(&datagram));
+        assert_eq!(decoded, header);
+        assert_eq!(ssrc, Some(session.ssrc()));
+        assert_eq!(payload, OPUS);
+    }
+
+    #[test]
+    fn csrcs_extension_and_padding_are_skipped() {
+        // Padding, extension, two CSRCs
+        let mut datagram = rtp_header(0x80 | 0x20 | 0x10 | 2);
+        datagram.extend_from_slice(&[0x11; 8]);
+        datagram.extend_from_slice(&[0xBE, 0xDE, 0, 1, 0x22, 0x22, 0x22, 0x22]);
+        datagram.extend_from_slice(&OPUS);
+        datagram.extend_from_slice(&[0, 0, 3]);
+
+        let (header, ssrc, payload) = audio(decode(&datagram));
+        assert_eq!(payload, OPUS);
+        assert_eq!(ssrc, Some(0x0102_0304));
+        assert_eq!((header.sequence, header.timestamp), (5, 960));
+        assert_eq!((header.channels, header.frame_samples), (1, 960));
+    }
+
+    #[test]
+    fn malformed_rtp_is_rejected() {
+        // Too short for the fixed header
+        assert!(decode(&rtp_header(0x80)[..10]).is_none());
+
+        // Extension flag set, but the datagram ends before its length
+        let mut datagram = rtp_header(0x80 | 0x10);
+        datagram.extend_from_slice(&[0xBE, 0xDE]);
+        assert!(decode(&datagram).is_none());
+
+        // Extension longer than the rest of the datagram
+        let mut datagram = rtp_header(0x80 | 0x10);
+        datagram.extend_from_slice(&[0xBE, 0xDE, 0, 9]);
+        datagram.extend_from_slice(&OPUS);
+        assert!(decode(&datagram).is_none());
+
+        // Padding longer than the payload, and longer than the datagram
+        for padding in [OPUS.len() as u8 + 1, 255] {
+            let mut datagram = rtp_header(0x80 | 0x20);
+            datagram.extend_from_slice(&OPUS);
+            datagram.push(padding);
+            assert!(decode(&datagram).is_none());
+        }
+
+        // Header only, no payload
+        assert!(decode(&rtp_header(0x80)).is_none());
+    }
+
+    #[test]
+    fn compound_sender_and_receiver_report_round_trip() {
+        let session = RtpSession::new(true, None);
+        assert!(session.sender_report().is_none(), "nothing sent yet");
+
+        let header = AudioHeader {
+            channels: 1,
+            frame_samples: 960,
+            sequence: 1,
+            timestamp: 960,
+        };
+        session.encode(&header, &OPUS);
+        let sr = session.sender_report().expect("sent since the last report");
+        assert!(session.sender_report().is_none(), "one report per send");
+        assert_eq!(sr.len(), 28);
+        assert!(rtcp(decode(&sr)).is_empty(), "our SR carries no blocks");
+
+        let report = ReceiverReport {
+            fraction_lost: 13,
+            cumulative_lost: 42,
+            highest_seq: 70_000,
+            jitter: 96,
+        };
+        let rr = session.receiver_report(0xCAFE, &report);
+        assert_eq!(rr.len(), 32);
+
+        let mut compound = sr;
+        compound.extend_from_slice(&rr);
+        let blocks = rtcp(decode(&compound));
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].ssrc, 0xCAFE);
+        assert_eq!(blocks[0].report, report);
+    }
+
+    #[test]
+    fn report_count_beyond_the_packet_is_ignored() {
+        let session = RtpSession::new(true, None);
+        let report = ReceiverReport {
+            fraction_lost: 0,
+            cumulative_lost: 0,
+            highest_seq: 1,
+            jitter: 0,
+        };
+        let mut rr = session.receiver_report(7, &report);
+        // Claim three blocks where only one fits
+        rr[0] = (RTP_VERSION << 6) | 3;
+
+        let blocks = rtcp(decode(&rr));
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].ssrc, 7);
+
+        // Length field running past the datagram: nothing is read
+        rr[3] = 20;
+        assert!(rtcp(decode(&rr)).is_empty());
+    }
+}
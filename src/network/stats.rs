@@ -0,0 +1,306 @@
+//! Per-peer session statistics: traffic counters, late and duplicate packets,
+//! round-trip time from ping/pong, and a per-second history for the TUI
+//! sparklines and the export command.
+
+use crate::audio::sad::OPUS_SAMPLE_RATE;
+use crate::config::data_dir;
+use crate::network::report::ReceiverReport;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::time::{Duration, Instant};
+
+/// How often history samples are taken and peers are pinged.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// Samples kept per peer (two minutes).
+const HISTORY_LEN: usize = 120;
+/// Recent sequence numbers remembered per peer to spot duplicates.
+const SEEN_LEN: usize = 64;
+/// Pings without a pong after this long are forgotten.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One history point, taken every [`STATS_INTERVAL`].
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct StatsSample {
+    pub rx_kbps: f32,
+    pub tx_kbps: f32,
+    pub loss_pct: f32,
+    pub jitter_ms: f32,
+    pub rtt_ms: Option<f32>,
+    pub buffer_ms: f32,
+}
+
+/// Statistics for one peer. Byte counts are Opus payload, without headers.
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerStats {
+    pub peer: SocketAddr,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Loss of the peer's stream over the last report interval.
+    pub loss_pct: f32,
+    /// Packets lost since the peer's stream started.
+    pub lost: u32,
+    /// Packets that arrived after one with a higher sequence number.
+    pub late: u64,
+    pub duplicates: u64,
+    pub jitter_ms: f32,
+    /// Smoothed round-trip time; `None` until the first pong.
+    pub rtt_ms: Option<f32>,
+    pub rx_kbps: f32,
+    pub tx_kbps: f32,
+    /// Audio queued for this peer in the jitter buffer.
+    pub buffer_ms: f32,
+    pub history: VecDeque<StatsSample>,
+}
+
+impl PeerStats {
+    fn new(peer: SocketAddr) -> Self {
+        Self {
+            peer,
+            packets_sent: 0,
+            bytes_sent: 0,
+            packets_received: 0,
+            bytes_received: 0,
+            loss_pct: 0.0,
+            lost: 0,
+            late: 0,
+            duplicates: 0,
+            jitter_ms: 0.0,
+            rtt_ms: None,
+            rx_kbps: 0.0,
+            tx_kbps: 0.0,
+            buffer_ms: 0.0,
+            history: VecDeque::new(),
+        }
+    }
+}
+
+/// Counters plus the bookkeeping needed to derive the rest.
+struct PeerCounters {
+    stats: PeerStats,
+    highest_seq: Option<u16>,
+    seen: VecDeque<u16>,
+    /// Byte counts at the previous tick, for bitrates.
+    last_bytes: (u64, u64),
+    pings: HashMap<u64, Instant>,
+}
+
+impl PeerCounters {
+    fn new(peer: SocketAddr) -> Self {
+        Self {
+            stats: PeerStats::new(peer),
+            highest_seq: None,
+            seen: VecDeque::new(),
+            last_bytes: (0, 0),
+            pings: HashMap::new(),
+        }
+    }
+
+    fn on_received(&mut self, sequence: u16, bytes: usize) {
+        self.stats.packets_received += 1;
+        self.stats.bytes_received += bytes as u64;
+
+        if self.seen.contains(&sequence) {
+            self.stats.duplicates += 1;
+            return;
+        }
+        self.seen.push_back(sequence);
+        if self.seen.len() > SEEN_LEN {
+            self.seen.pop_front();
+        }
+
+        match self.highest_seq {
+            Some(highest) if (sequence.wrapping_sub(highest) as i16) < 0 => self.stats.late += 1,
+            _ => self.highest_seq = Some(sequence),
+        }
+    }
+
+    fn tick(&mut self, elapsed: Duration) {
+        let stats = &mut self.stats;
+        let seconds = elapsed.as_secs_f32().max(0.001);
+        let (received, sent) = self.last_bytes;
+        stats.rx_kbps = (stats.bytes_received - received) as f32 * 8.0 / 1000.0 / seconds;
+        stats.tx_kbps = (stats.bytes_sent - sent) as f32 * 8.0 / 1000.0 / seconds;
+        self.last_bytes = (stats.bytes_received, stats.bytes_sent);
+
+        // Loss and jitter only mean something while the peer is talking
+        let receiving = stats.rx_kbps > 0.0;
+        stats.history.push_back(StatsSample {
+            rx_kbps: stats.rx_kbps,
+            tx_kbps: stats.tx_kbps,
+            loss_pct: if receiving { stats.loss_pct } else { 0.0 },
+            jitter_ms: if receiving { stats.jitter_ms } else { 0.0 },
+            rtt_ms: stats.rtt_ms,
+            buffer_ms: stats.buffer_ms,
+        });
+        if stats.history.len() > HISTORY_LEN {
+            stats.history.pop_front();
+        }
+
+        self.pings.retain(|_, sent| sent.elapsed() < PING_TIMEOUT);
+    }
+}
+
+/// Audio pipeline counters and device state, not tied to one peer.
+#[derive(Default)]
+pub struct PipelineStats {
+    /// A peer's playback ran dry mid-stream (an audible gap).
+    pub underruns: AtomicU64,
+    pub encode_errors: AtomicU64,
+    pub decode_errors: AtomicU64,
+    pub input_running: AtomicBool,
+    pub output_running: AtomicBool,
+}
+
+/// Statistics for every peer we exchange audio or pings with.
+pub struct SessionStats {
+    peers: Mutex<HashMap<SocketAddr, PeerCounters>>,
+    last_tick: Mutex<Instant>,
+    pub pipeline: PipelineStats,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self {
+            peers: Mutex::new(HashMap::new()),
+            last_tick: Mutex::new(Instant::now()),
+            pipeline: PipelineStats::default(),
+        }
+    }
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_peer<T>(&self, peer: SocketAddr, f: impl FnOnce(&mut PeerCounters) -> T) -> T {
+        f(self
+            .peers
+            .lock()
+            .unwrap()
+            .entry(peer)
+            .or_insert_with(|| PeerCounters::new(peer)))
+    }
+
+    /// One audio packet of `bytes` Opus payload sent to `peer`.
+    pub fn on_sent(&self, peer: SocketAddr, bytes: usize) {
+        self.with_peer(peer, |counters| {
+            counters.stats.packets_sent += 1;
+            counters.stats.bytes_sent += bytes as u64;
+        });
+    }
+
+    /// One audio packet received from `from`, on any transport.
+    pub fn on_received(&self, from: SocketAddr, sequence: u16, bytes: usize) {
+        self.with_peer(from, |counters| counters.on_received(sequence, bytes));
+    }
+
+    /// Our own reception report about `from`'s stream.
+    pub fn on_report(&self, from: SocketAddr, report: &ReceiverReport) {
+        self.with_peer(from, |counters| {
+            counters.stats.loss_pct = report.loss() * 100.0;
+            counters.stats.lost = report.cumulative_lost;
+            counters.stats.jitter_ms = report.jitter_ms();
+        });
+    }
+
+    /// Jitter-buffer depth for `from`, in 48kHz samples.
+    pub fn set_buffered(&self, from: SocketAddr, samples: usize) {
+        self.with_peer(from, |counters| {
+            counters.stats.buffer_ms = samples as f32 * 1000.0 / OPUS_SAMPLE_RATE as f32;
+        });
+    }
+
+    /// Token for a new ping to `peer`; the pong echoes it back.
+    pub fn ping(&self, peer: SocketAddr) -> u64 {
+        let token = rand::random();
+        self.with_peer(peer, |counters| {
+            counters.pings.insert(token, Instant::now());
+        });
+        token
+    }
+
+    pub fn on_pong(&self, from: SocketAddr, token: u64) {
+        self.with_peer(from, |counters| {
+            let Some(sent) = counters.pings.remove(&token) else {
+                return;
+            };
+            let sample = sent.elapsed().as_secs_f32() * 1000.0;
+            // Same smoothing as TCP's SRTT (RFC 6298)
+            counters.stats.rtt_ms = Some(match counters.stats.rtt_ms {
+                Some(rtt) => rtt + (sample - rtt) / 8.0,
+                None => sample,
+            });
+        });
+    }
+
+    /// Update bitrates and record a history sample for every peer.
+    pub fn tick(&self) {
+        let elapsed = {
+            let mut last_tick = self.last_tick.lock().unwrap();
+            let elapsed = last_tick.elapsed();
+            *last_tick = Instant::now();
+            elapsed
+        };
+        self.tick_after(elapsed);
+    }
+
+    /// [`tick`](Self::tick) for a caller keeping its own clock, e.g. a replay.
+    pub fn tick_after(&self, elapsed: Duration) {
+        for counters in self.peers.lock().unwrap().values_mut() {
+            counters.tick(elapsed);
+        }
+    }
+
+    /// Current statistics, sorted by address.
+    pub fn snapshot(&self) -> Vec<PeerStats> {
+        let mut stats: Vec<_> = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|counters| counters.stats.clone())
+            .collect();
+        stats.sort_by_key(|stats| stats.peer);
+        stats
+    }
+
+    /// `stats/stats-<time>.json` in the data directory.
+    pub fn default_export_path() -> Option<PathBuf> {
+        let time = chrono::Local::now().format("%Y%m%d-%H%M%S");
+        Some(data_dir()?.join("stats").join(format!("stats-{time}.json")))
+    }
+
+    /// Write the current statistics, history included, as JSON; `name`
+    /// labels each peer.
+    pub fn export(&self, path: &Path, name: impl Fn(&SocketAddr) -> String) -> Result<()> {
+        #[derive(Serialize)]
+        struct Exported {
+            name: String,
+            #[serde(flatten)]
+            stats: PeerStats,
+        }
+
+        let exported: Vec<_> = self
+            .snapshot()
+            .into_iter()
+            .map(|stats| Exported {
+                name: name(&stats.peer),
+                stats,
+            })
+            .collect();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(&exported)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1055 @@
+//! File transfer over the audio socket. The sender offers a file, the
+//! receiver accepts or rejects it from the TUI, then chunks flow in a sliding
+//! window with cumulative acks (go-back-N on timeout). The receiver checks the
+//! SHA-256 from the offer before keeping the file.
+//!
+//! Chunks are paced to a fixed rate well below what a LAN carries, so a
+//! transfer never queues up in front of audio packets. Every transfer does its
+//! file I/O and hashing on a thread of its own, never on the network runtime.
+
+use crate::network::protocol::ControlMessage;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+/// Payload bytes per chunk; keeps datagrams under a typical MTU.
+pub const CHUNK_SIZE: usize = 1024;
+/// Chunks in flight before the sender waits for an ack.
+const WINDOW: u32 = 32;
+/// How often `pump` should be called; one chunk per call caps a transfer at
+/// about 200 KB/s.
+pub const PUMP_INTERVAL: Duration = Duration::from_millis(5);
+/// Chunks sent per `pump` call across all transfers.
+const CHUNKS_PER_PUMP: usize = 1;
+/// Without ack progress for this long, unacked chunks are sent again.
+const RESEND_TIMEOUT: Duration = Duration::from_millis(500);
+/// Without any progress for this long, the transfer fails.
+const STALL_TIMEOUT: Duration = Duration::from_secs(15);
+/// Offers are repeated this often until answered.
+const OFFER_RETRY: Duration = Duration::from_secs(1);
+/// Offers nobody answers expire after this long.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(120);
+/// Finished transfers kept for the TUI.
+const FINISHED_KEPT: usize = 10;
+
+/// What the sender announces before any data flows.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileOffer {
+    pub id: u64,
+    /// File name only, no directories.
+    pub name: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+impl FileOffer {
+    fn total_chunks(&self) -> u32 {
+        self.size.div_ceil(CHUNK_SIZE as u64) as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferState {
+    /// Waiting for the receiver's decision.
+    Offered,
+    Transferring,
+    /// All data sent, waiting for the receiver's checksum result.
+    Verifying,
+    Done,
+    Failed(String),
+}
+
+impl TransferState {
+    pub fn finished(&self) -> bool {
+        matches!(self, Self::Done | Self::Failed(_))
+    }
+}
+
+/// A transfer as shown in the TUI.
+#[derive(Debug, Clone)]
+pub struct TransferStatus {
+    pub id: u64,
+    pub direction: Direction,
+    pub peer: SocketAddr,
+    pub name: String,
+    pub size: u64,
+    pub transferred: u64,
+    /// Bytes per second since the data started flowing.
+    pub rate: f64,
+    pub state: TransferState,
+    offered_at: Instant,
+}
+
+impl TransferStatus {
+    pub fn fraction(&self) -> f64 {
+        if self.size == 0 {
+            return if self.state == TransferState::Done {
+                1.0
+            } else {
+                0.0
+            };
+        }
+        self.transferred as f64 / self.size as f64
+    }
+}
+
+struct Outgoing {
+    offer: FileOffer,
+    peer: SocketAddr,
+    /// Chunk indexes for the reader thread; dropped once finished.
+    reader: Option<mpsc::Sender<u32>>,
+    state: TransferState,
+    /// First unacknowledged chunk.
+    base: u32,
+    /// Next chunk to send.
+    next: u32,
+    offered_at: Instant,
+    last_sent: Option<Instant>,
+    /// Last time `base` moved (or the transfer started).
+    last_progress: Instant,
+    started: Option<Instant>,
+}
+
+struct Incoming {
+    offer: FileOffer,
+    peer: SocketAddr,
+    state: TransferState,
+    /// Next chunk expected in order.
+    next: u32,
+    /// Chunks that arrived ahead of `next`.
+    early: BTreeMap<u32, Vec<u8>>,
+    offered_at: Instant,
+    /// In-order data for the writer thread; dropping it aborts the download.
+    writer: Option<mpsc::Sender<WriteJob>>,
+    part_path: PathBuf,
+    started: Option<Instant>,
+}
+
+/// Work for a download's writer thread.
+enum WriteJob {
+    Chunk(Vec<u8>),
+    /// All data is in: check the hash and move the file into place.
+    Finish,
+}
+
+/// What the reader and writer threads report back; applied by the next `pump`.
+enum DiskResult {
+    ReadFailed {
+        id: u64,
+        error: String,
+    },
+    Saved {
+        id: u64,
+        result: Result<PathBuf, String>,
+    },
+}
+
+type Outbox = Arc<Mutex<Vec<(SocketAddr, ControlMessage)>>>;
+
+pub struct Transfers {
+    /// Without one, every offer is declined.
+    download_dir: Option<PathBuf>,
+    outgoing: Mutex<HashMap<u64, Outgoing>>,
+    incoming: Mutex<HashMap<u64, Incoming>>,
+    /// Messages from TUI actions and chunks read from disk, sent by the next
+    /// `pump`.
+    outbox: Outbox,
+    disk: Arc<Mutex<Vec<DiskResult>>>,
+}
+
+impl Transfers {
+    /// Received files are saved into `download_dir`; with `None`, incoming
+    /// offers are declined and only uploads work.
+    pub fn new(download_dir: Option<PathBuf>) -> Self {
+        Self {
+            download_dir,
+            outgoing: Mutex::new(HashMap::new()),
+            incoming: Mutex::new(HashMap::new()),
+            outbox: Arc::new(Mutex::new(Vec::new())),
+            disk: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn download_dir(&self) -> Option<&Path> {
+        self.download_dir.as_deref()
+    }
+
+    /// Offer `path` to `peer`. Hashing runs in the background; the offer goes
+    /// out once it's done. The same thread then reads the chunks to send.
+    pub fn offer(self: &Arc<Self>, peer: SocketAddr, path: PathBuf) {
+        let transfers = self.clone();
+        spawn(move || {
+            let offer = match hash_file(&path) {
+                Ok((size, sha256)) => FileOffer {
+                    id: rand::random(),
+                    name: path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| "file".to_string()),
+                    size,
+                    sha256,
+                },
+                Err(e) => {
+                    warn!("Cannot offer {}: {}", path.display(), e);
+                    return;
+                }
+            };
+            let mut file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Cannot open {}: {}", path.display(), e);
+                    return;
+                }
+            };
+
+            info!("Offering {} ({} bytes) to {}", offer.name, offer.size, peer);
+            let (id, size) = (offer.id, offer.size);
+            let (reader, indexes) = mpsc::channel();
+            transfers.outgoing.lock().unwrap().insert(
+                id,
+                Outgoing {
+                    offer,
+                    peer,
+                    reader: Some(reader),
+                    state: TransferState::Offered,
+                    base: 0,
+                    next: 0,
+                    offered_at: Instant::now(),
+                    last_sent: None,
+                    last_progress: Instant::now(),
+                    started: None,
+                },
+            );
+
+            for index in indexes {
+                match read_chunk(&mut file, id, size, index) {
+                    Ok(chunk) => transfers.outbox.lock().unwrap().push((peer, chunk)),
+                    Err(e) => {
+                        transfers.disk.lock().unwrap().push(DiskResult::ReadFailed {
+                            id,
+                            error: e.to_string(),
+                        });
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Every transfer, uploads first, oldest first within each direction.
+    pub fn statuses(&self) -> Vec<TransferStatus> {
+        let mut statuses: Vec<TransferStatus> = Vec::new();
+
+        for (id, out) in self.outgoing.lock().unwrap().iter() {
+            let transferred = (out.base as u64 * CHUNK_SIZE as u64).min(out.offer.size);
+            statuses.push(TransferStatus {
+                id: *id,
+                direction: Direction::Upload,
+                peer: out.peer,
+                name: out.offer.name.clone(),
+                size: out.offer.size,
+                transferred,
+                rate: rate(transferred, out.started),
+                state: out.state.clone(),
+                offered_at: out.offered_at,
+            });
+        }
+        for (id, inc) in self.incoming.lock().unwrap().iter() {
+            let transferred = (inc.next as u64 * CHUNK_SIZE as u64).min(inc.offer.size);
+            statuses.push(TransferStatus {
+                id: *id,
+                direction: Direction::Download,
+                peer: inc.peer,
+                name: inc.offer.name.clone(),
+                size: inc.offer.size,
+                transferred,
+                rate: rate(transferred, inc.started),
+                state: inc.state.clone(),
+                offered_at: inc.offered_at,
+            });
+        }
+
+        statuses.sort_by_key(|s| (s.direction == Direction::Download, s.offered_at));
+        statuses
+    }
+
+    /// The oldest offer still waiting for the user's decision.
+    pub fn pending_offer(&self) -> Option<TransferStatus> {
+        self.statuses()
+            .into_iter()
+            .find(|s| s.direction == Direction::Download && s.state == TransferState::Offered)
+    }
+
+    /// Accept an incoming offer and start receiving into the download directory.
+    pub fn accept(&self, id: u64) {
+        let mut incoming = self.incoming.lock().unwrap();
+        let Some(inc) = incoming.get_mut(&id) else {
+            return;
+        };
+        if inc.state != TransferState::Offered {
+            return;
+        }
+        let Some(dir) = &self.download_dir else {
+            return;
+        };
+
+        let file = fs::create_dir_all(dir).and_then(|()| File::create(&inc.part_path));
+        let reply = match file {
+            Ok(file) => {
+                inc.writer = Some(self.save(inc, file));
+                inc.state = TransferState::Transferring;
+                inc.started = Some(Instant::now());
+                ControlMessage::FileAccept(id)
+            }
+            Err(e) => {
+                inc.state = TransferState::Failed(format!("cannot save: {e}"));
+                ControlMessage::FileReject(id)
+            }
+        };
+        self.outbox.lock().unwrap().push((inc.peer, reply));
+
+        // Empty files are complete as soon as they're accepted
+        if inc.state == TransferState::Transferring && inc.offer.total_chunks() == 0 {
+            finish(inc);
+        }
+    }
+
+    /// Start the writer thread for an accepted download.
+    fn save(&self, inc: &Incoming, file: File) -> mpsc::Sender<WriteJob> {
+        let (writer, writes) = mpsc::channel();
+        let id = inc.offer.id;
+        let part_path = inc.part_path.clone();
+        let dir = self.download_dir.clone().unwrap_or_default();
+        let name = inc.offer.name.clone();
+        let sha256 = inc.offer.sha256;
+        let disk = self.disk.clone();
+        spawn(move || {
+            let mut file = BufWriter::new(file);
+            let mut hasher = Sha256::new();
+            let result = loop {
+                match writes.recv() {
+                    Ok(WriteJob::Chunk(data)) => {
+                        if let Err(e) = file.write_all(&data) {
+                            break Err(format!("write failed: {e}"));
+                        }
+                        hasher.update(&data);
+                    }
+                    Ok(WriteJob::Finish) => {
+                        let digest: [u8; 32] = hasher.finalize().into();
+                        if let Err(e) = file.flush() {
+                            break Err(format!("write failed: {e}"));
+                        }
+                        if digest != sha256 {
+                            break Err("checksum mismatch".to_string());
+                        }
+                        let path = unique_path(&dir, &name);
+                        break fs::rename(&part_path, &path)
+                            .map(|()| path)
+                            .map_err(|e| format!("cannot save: {e}"));
+                    }
+                    // Cancelled
+                    Err(_) => {
+                        drop(file);
+                        let _ = fs::remove_file(&part_path);
+                        return;
+                    }
+                }
+            };
+            if result.is_err() {
+                drop(file);
+                let _ = fs::remove_file(&part_path);
+            }
+            disk.lock().unwrap().push(DiskResult::Saved { id, result });
+        });
+        writer
+    }
+
+    pub fn reject(&self, id: u64) {
+        let mut incoming = self.incoming.lock().unwrap();
+        if let Some(inc) = incoming.get_mut(&id)
+            && inc.state == TransferState::Offered
+        {
+            inc.state = TransferState::Failed("rejected".to_string());
+            self.outbox
+                .lock()
+                .unwrap()
+                .push((inc.peer, ControlMessage::FileReject(id)));
+        }
+    }
+
+    /// Abort every unfinished transfer in both directions.
+    pub fn cancel_all(&self) {
+        let mut outbox = self.outbox.lock().unwrap();
+        for (id, out) in self.outgoing.lock().unwrap().iter_mut() {
+            if !out.state.finished() {
+                out.state = TransferState::Failed("cancelled".to_string());
+                outbox.push((out.peer, ControlMessage::FileCancel(*id)));
+            }
+        }
+        for (id, inc) in self.incoming.lock().unwrap().iter_mut() {
+            if !inc.state.finished() {
+                inc.state = TransferState::Failed("cancelled".to_string());
+                inc.writer = None;
+                outbox.push((inc.peer, ControlMessage::FileCancel(*id)));
+            }
+        }
+    }
+
+    /// Handle a transfer message from `from`; returns the replies to send back.
+    pub fn on_message(&self, from: SocketAddr, message: ControlMessage) -> Vec<ControlMessage> {
+        match message {
+            ControlMessage::FileOffer(offer) => self.on_offer(from, offer),
+            ControlMessage::FileChunk { id, index, data } => self.on_chunk(from, id, index, &data),
+            ControlMessage::FileAccept(id) => {
+                self.update_outgoing(from, id, |out| {
+                    if out.state == TransferState::Offered {
+                        out.state = TransferState::Transferring;
+                        out.started = Some(Instant::now());
+                        out.last_progress = Instant::now();
+                    }
+                });
+                Vec::new()
+            }
+            ControlMessage::FileReject(id) => {
+                self.update_outgoing(from, id, |out| {
+                    if !out.state.finished() {
+                        out.state = TransferState::Failed("rejected".to_string());
+                    }
+                });
+                Vec::new()
+            }
+            ControlMessage::FileAck { id, next } => {
+                self.update_outgoing(from, id, |out| {
+                    if out.state == TransferState::Transferring && next > out.base {
+                        out.base = next.min(out.offer.total_chunks());
+                        out.next = out.next.max(out.base);
+                        out.last_progress = Instant::now();
+                        if out.base == out.offer.total_chunks() {
+                            out.state = TransferState::Verifying;
+                        }
+                    }
+                });
+                Vec::new()
+            }
+            ControlMessage::FileDone { id, ok } => {
+                self.update_outgoing(from, id, |out| {
+                    if !out.state.finished() {
+                        out.base = out.offer.total_chunks();
+                        out.state = if ok {
+                            TransferState::Done
+                        } else {
+                            TransferState::Failed("checksum mismatch".to_string())
+                        };
+                    }
+                });
+                Vec::new()
+            }
+            ControlMessage::FileCancel(id) => {
+                self.update_outgoing(from, id, |out| {
+                    if !out.state.finished() {
+                        out.state = TransferState::Failed("cancelled by peer".to_string());
+                    }
+                });
+                if let Some(inc) = self.incoming.lock().unwrap().get_mut(&id)
+                    && inc.peer == from
+                    && !inc.state.finished()
+                {
+                    inc.state = TransferState::Failed("cancelled by peer".to_string());
+                    inc.writer = None;
+                }
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn update_outgoing(&self, from: SocketAddr, id: u64, update: impl FnOnce(&mut Outgoing)) {
+        if let Some(out) = self.outgoing.lock().unwrap().get_mut(&id)
+            && out.peer == from
+        {
+            update(out);
+        }
+    }
+
+    fn on_offer(&self, from: SocketAddr, offer: FileOffer) -> Vec<ControlMessage> {
+        let mut incoming = self.incoming.lock().unwrap();
+
+        // A repeated offer means our answer got lost - answer again
+        if let Some(inc) = incoming.get(&offer.id) {
+            if inc.peer != from {
+                return Vec::new();
+            }
+            return match &inc.state {
+                TransferState::Offered => Vec::new(),
+                TransferState::Transferring | TransferState::Verifying => {
+                    vec![ControlMessage::FileAccept(offer.id)]
+                }
+                TransferState::Done => vec![ControlMessage::FileDone {
+                    id: offer.id,
+                    ok: true,
+                }],
+                TransferState::Failed(_) => vec![ControlMessage::FileReject(offer.id)],
+            };
+        }
+
+        let name = safe_file_name(&offer.name);
+        let (state, part_path, replies) = match &self.download_dir {
+            Some(dir) => {
+                info!("{} offers {} ({} bytes)", from, name, offer.size);
+                let part_path = dir.join(format!("{name}.{:016x}.part", offer.id));
+                (TransferState::Offered, part_path, Vec::new())
+            }
+            None => {
+                info!("Declining {} from {}: no download directory", name, from);
+                (
+                    TransferState::Failed("no download directory".to_string()),
+                    PathBuf::new(),
+                    vec![ControlMessage::FileReject(offer.id)],
+                )
+            }
+        };
+        incoming.insert(
+            offer.id,
+            Incoming {
+                offer: FileOffer { name, ..offer },
+                peer: from,
+                state,
+                next: 0,
+                early: BTreeMap::new(),
+                offered_at: Instant::now(),
+                writer: None,
+                part_path,
+                started: None,
+            },
+        );
+        replies
+    }
+
+    fn on_chunk(&self, from: SocketAddr, id: u64, index: u32, data: &[u8]) -> Vec<ControlMessage> {
+        let mut incoming = self.incoming.lock().unwrap();
+        let Some(inc) = incoming.get_mut(&id) else {
+            return Vec::new();
+        };
+        if inc.peer != from {
+            return Vec::new();
+        }
+
+        match inc.state {
+            TransferState::Transferring => {}
+            // The sender missed our result and is probing with old chunks
+            TransferState::Done => return vec![ControlMessage::FileDone { id, ok: true }],
+            TransferState::Failed(_) if inc.next == inc.offer.total_chunks() => {
+                return vec![ControlMessage::FileDone { id, ok: false }];
+            }
+            _ => return Vec::new(),
+        }
+
+        // Bounds first: `index` comes straight off the wire
+        let total = inc.offer.total_chunks();
+        if index >= total {
+            return Vec::new();
+        }
+        let expected_len = if index == total - 1 {
+            inc.offer.size as usize - (total as usize - 1) * CHUNK_SIZE
+        } else {
+            CHUNK_SIZE
+        };
+        if data.len() != expected_len {
+            return Vec::new();
+        }
+
+        if index == inc.next {
+            write_chunk(inc, data.to_vec());
+            while let Some(data) = inc.early.remove(&inc.next) {
+                write_chunk(inc, data);
+            }
+        } else if index > inc.next && index < inc.next + WINDOW {
+            inc.early.insert(index, data.to_vec());
+        }
+
+        if inc.next == total {
+            finish(inc);
+        }
+        vec![ControlMessage::FileAck { id, next: inc.next }]
+    }
+
+    /// Apply what the reader and writer threads reported since the last pump.
+    fn apply_disk_results(&self, out_messages: &mut Vec<(SocketAddr, ControlMessage)>) {
+        let results: Vec<_> = self.disk.lock().unwrap().drain(..).collect();
+        for result in results {
+            match result {
+                DiskResult::ReadFailed { id, error } => {
+                    if let Some(out) = self.outgoing.lock().unwrap().get_mut(&id)
+                        && !out.state.finished()
+                    {
+                        out.state = TransferState::Failed(format!("read failed: {error}"));
+                        out_messages.push((out.peer, ControlMessage::FileCancel(id)));
+                    }
+                }
+                DiskResult::Saved { id, result } => {
+                    let mut incoming = self.incoming.lock().unwrap();
+                    let Some(inc) = incoming.get_mut(&id) else {
+                        continue;
+                    };
+                    if inc.state.finished() {
+                        continue;
+                    }
+                    // Before all data is in, only a write can have failed
+                    let reply = if inc.state == TransferState::Verifying {
+                        ControlMessage::FileDone {
+                            id,
+                            ok: result.is_ok(),
+                        }
+                    } else {
+                        ControlMessage::FileCancel(id)
+                    };
+                    inc.writer = None;
+                    inc.state = match result {
+                        Ok(path) => {
+                            info!("Received {}", path.display());
+                            TransferState::Done
+                        }
+                        Err(e) => TransferState::Failed(e),
+                    };
+                    out_messages.push((inc.peer, reply));
+                }
+            }
+        }
+    }
+
+    /// Send offers, chunks and queued replies that are due. Call every
+    /// `PUMP_INTERVAL`.
+    pub fn pump(&self) -> Vec<(SocketAddr, ControlMessage)> {
+        let mut out_messages: Vec<_> = self.outbox.lock().unwrap().drain(..).collect();
+        self.apply_disk_results(&mut out_messages);
+        let mut budget = CHUNKS_PER_PUMP;
+
+        let mut outgoing = self.outgoing.lock().unwrap();
+        for (id, out) in outgoing.iter_mut() {
+            match out.state {
+                TransferState::Offered => {
+                    if out.offered_at.elapsed() > OFFER_TIMEOUT {
+                        out.state = TransferState::Failed("no answer".to_string());
+                    } else if out.last_sent.is_none_or(|at| at.elapsed() >= OFFER_RETRY) {
+                        out.last_sent = Some(Instant::now());
+                        out_messages.push((out.peer, ControlMessage::FileOffer(out.offer.clone())));
+                    }
+                }
+                TransferState::Transferring | TransferState::Verifying => {
+                    let stalled = out.last_progress.elapsed();
+                    if stalled > STALL_TIMEOUT {
+                        out.state = TransferState::Failed("peer stopped responding".to_string());
+                        out_messages.push((out.peer, ControlMessage::FileCancel(*id)));
+                        continue;
+                    }
+
+                    let total = out.offer.total_chunks();
+                    let resend_due = out.last_sent.is_none_or(|at| at.elapsed() > RESEND_TIMEOUT)
+                        && stalled > RESEND_TIMEOUT;
+                    if out.state == TransferState::Verifying {
+                        // Probe for the lost result: the receiver answers any chunk with it
+                        if resend_due {
+                            out.last_sent = Some(Instant::now());
+                            match total {
+                                0 => out_messages
+                                    .push((out.peer, ControlMessage::FileOffer(out.offer.clone()))),
+                                _ => out.read(total - 1),
+                            }
+                        }
+                        continue;
+                    }
+
+                    // Go back to the first unacked chunk when acks stop coming
+                    if resend_due && out.next > out.base {
+                        out.next = out.base;
+                    }
+                    while budget > 0 && out.next < total && out.next < out.base + WINDOW {
+                        out.read(out.next);
+                        out.next += 1;
+                        out.last_sent = Some(Instant::now());
+                        budget -= 1;
+                    }
+                }
+                // Let the reader thread go
+                TransferState::Done | TransferState::Failed(_) => out.reader = None,
+            }
+        }
+        prune_finished(&mut outgoing, |out| (&out.state, out.offered_at));
+        drop(outgoing);
+        prune_finished(&mut self.incoming.lock().unwrap(), |inc| {
+            (&inc.state, inc.offered_at)
+        });
+
+        out_messages
+    }
+}
+
+impl Outgoing {
+    /// Have the reader thread queue chunk `index` for sending.
+    fn read(&self, index: u32) {
+        if let Some(reader) = &self.reader {
+            let _ = reader.send(index);
+        }
+    }
+}
+
+/// Hand the next in-order chunk to the writer thread.
+fn write_chunk(inc: &mut Incoming, data: Vec<u8>) {
+    if let Some(writer) = &inc.writer {
+        let _ = writer.send(WriteJob::Chunk(data));
+    }
+    inc.next += 1;
+}
+
+/// All data is in: the writer thread checks the hash and moves the file into
+/// place, and the next `pump` tells the sender how it went.
+fn finish(inc: &mut Incoming) {
+    if let Some(writer) = inc.writer.take() {
+        let _ = writer.send(WriteJob::Finish);
+    }
+    inc.state = TransferState::Verifying;
+}
+
+fn read_chunk(file: &mut File, id: u64, size: u64, index: u32) -> std::io::Result<ControlMessage> {
+    let offset = index as u64 * CHUNK_SIZE as u64;
+    let len = size.saturating_sub(offset).min(CHUNK_SIZE as u64) as usize;
+    let mut data = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(ControlMessage::FileChunk { id, index, data })
+}
+
+/// Keep only the most recent finished transfers.
+fn prune_finished<T>(map: &mut HashMap<u64, T>, info: impl Fn(&T) -> (&TransferState, Instant)) {
+    let mut finished: Vec<(Instant, u64)> = map
+        .iter()
+        .filter(|(_, t)| info(t).0.finished())
+        .map(|(id, t)| (info(t).1, *id))
+        .collect();
+    finished.sort_unstable_by(|a, b| b.cmp(a));
+    for (_, id) in finished.iter().skip(FINISHED_KEPT) {
+        map.remove(id);
+    }
+}
+
+fn hash_file(path: &Path) -> std::io::Result<(u64, [u8; 32])> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut file, &mut hasher)?;
+    Ok((size, hasher.finalize().into()))
+}
+
+fn rate(transferred: u64, started: Option<Instant>) -> f64 {
+    match started {
+        Some(started) if started.elapsed().as_secs_f64() > 0.0 => {
+            transferred as f64 / started.elapsed().as_secs_f64()
+        }
+        _ => 0.0,
+    }
+}
+
+/// Strip directories and anything odd from a name a peer sent us.
+fn safe_file_name(name: &str) -> String {
+    let name = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '/' | '\\' | ':'))
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// `dir/name`, or `dir/name (1)` etc. if that already exists.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let candidate = dir.join(name);
+    if !candidate.exists() {
+        return candidate;
+    }
+
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{ext}")),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{stem} ({n}){ext}")))
+        .find(|path| !path.exists())
+        .expect("ran out of file names")
+}
+
+/// Human-readable byte count.
+pub fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..1_048_576 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const SENDER: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 5000);
+    const RECEIVER: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 5001);
+    const ID: u64 = 42;
+
+    /// A fresh directory for one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vl-transfer-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Three full chunks and a short one.
+    fn test_data() -> Vec<u8> {
+        (0..CHUNK_SIZE * 3 + 100).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn test_offer(data: &[u8]) -> FileOffer {
+        FileOffer {
+            id: ID,
+            name: "take.wav".to_string(),
+            size: data.len() as u64,
+            sha256: Sha256::digest(data).into(),
+        }
+    }
+
+    /// A receiver that accepted `offer` into `dir`.
+    fn receiving(dir: &Path, offer: FileOffer) -> Transfers {
+        let transfers = Transfers::new(Some(dir.to_path_buf()));
+        assert!(
+            transfers
+                .on_message(SENDER, ControlMessage::FileOffer(offer))
+                .is_empty()
+        );
+        transfers.accept(ID);
+        assert_eq!(
+            transfers.pump(),
+            vec![(SENDER, ControlMessage::FileAccept(ID))]
+        );
+        transfers
+    }
+
+    fn send_chunk(transfers: &Transfers, data: &[u8], index: u32) -> Vec<ControlMessage> {
+        let start = index as usize * CHUNK_SIZE;
+        let data = data[start..(start + CHUNK_SIZE).min(data.len())].to_vec();
+        transfers.on_message(
+            SENDER,
+            ControlMessage::FileChunk {
+                id: ID,
+                index,
+                data,
+            },
+        )
+    }
+
+    fn ack(next: u32) -> Vec<ControlMessage> {
+        vec![ControlMessage::FileAck { id: ID, next }]
+    }
+
+    /// Pump until the writer thread's verdict goes out.
+    fn verdict(transfers: &Transfers) -> ControlMessage {
+        for _ in 0..1000 {
+            if let Some((peer, message)) = transfers.pump().pop() {
+                assert_eq!(peer, SENDER);
+                return message;
+            }
+            sleep(PUMP_INTERVAL);
+        }
+        panic!("no verdict from the writer thread");
+    }
+
+    fn state(transfers: &Transfers) -> TransferState {
+        transfers.statuses()[0].state.clone()
+    }
+
+    #[test]
+    fn in_order_chunks_are_saved() {
+        let dir = test_dir("in-order");
+        let data = test_data();
+        let transfers = receiving(&dir, test_offer(&data));
+
+        for index in 0..4 {
+            assert_eq!(send_chunk(&transfers, &data, index), ack(index + 1));
+        }
+        assert_eq!(
+            verdict(&transfers),
+            ControlMessage::FileDone { id: ID, ok: true }
+        );
+        assert_eq!(state(&transfers), TransferState::Done);
+        assert_eq!(fs::read(dir.join("take.wav")).unwrap(), data);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reordered_and_duplicate_chunks_are_saved_once() {
+        let dir = test_dir("reordered");
+        let data = test_data();
+        let transfers = receiving(&dir, test_offer(&data));
+
+        // Early chunks wait for the gap to fill; repeats change nothing
+        assert_eq!(send_chunk(&transfers, &data, 2), ack(0));
+        assert_eq!(send_chunk(&transfers, &data, 0), ack(1));
+        assert_eq!(send_chunk(&transfers, &data, 0), ack(1));
+        assert_eq!(send_chunk(&transfers, &data, 3), ack(1));
+        assert_eq!(send_chunk(&transfers, &data, 2), ack(1));
+        assert_eq!(send_chunk(&transfers, &data, 1), ack(4));
+
+        assert_eq!(
+            verdict(&transfers),
+            ControlMessage::FileDone { id: ID, ok: true }
+        );
+        assert_eq!(fs::read(dir.join("take.wav")).unwrap(), data);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn out_of_range_index_is_ignored() {
+        let dir = test_dir("out-of-range");
+        let data = test_data();
+        let transfers = receiving(&dir, test_offer(&data));
+
+        for index in [4, WINDOW, u32::MAX] {
+            let chunk = ControlMessage::FileChunk {
+                id: ID,
+                index,
+                data: vec![0; 100],
+            };
+            assert!(transfers.on_message(SENDER, chunk).is_empty());
+        }
+        assert_eq!(state(&transfers), TransferState::Transferring);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn chunks_of_the_wrong_length_are_ignored() {
+        let dir = test_dir("short");
+        let data = test_data();
+        let transfers = receiving(&dir, test_offer(&data));
+
+        // Only the last chunk may be short, and only by the right amount
+        let short = ControlMessage::FileChunk {
+            id: ID,
+            index: 0,
+            data: vec![0; 100],
+        };
+        assert!(transfers.on_message(SENDER, short).is_empty());
+        for len in [99, 101, CHUNK_SIZE] {
+            let last = ControlMessage::FileChunk {
+                id: ID,
+                index: 3,
+                data: vec![0; len],
+            };
+            assert!(transfers.on_message(SENDER, last).is_empty());
+        }
+
+        for index in 0..4 {
+            assert_eq!(send_chunk(&transfers, &data, index), ack(index + 1));
+        }
+        assert_eq!(
+            verdict(&transfers),
+            ControlMessage::FileDone { id: ID, ok: true }
+        );
+        assert_eq!(fs::read(dir.join("take.wav")).unwrap(), data);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn corrupted_data_fails_the_checksum() {
+        let dir = test_dir("corrupted");
+        let data = test_data();
+        let mut offer = test_offer(&data);
+        offer.sha256[0] ^= 1;
+        let transfers = receiving(&dir, offer);
+
+        for index in 0..4 {
+            assert_eq!(send_chunk(&transfers, &data, index), ack(index + 1));
+        }
+        assert_eq!(
+            verdict(&transfers),
+            ControlMessage::FileDone { id: ID, ok: false }
+        );
+        assert_eq!(
+            state(&transfers),
+            TransferState::Failed("checksum mismatch".to_string())
+        );
+        // Neither the file nor its partial download is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn offers_are_declined_without_a_download_dir() {
+        let transfers = Transfers::new(None);
+        let offer = ControlMessage::FileOffer(test_offer(&test_data()));
+        let reject = vec![ControlMessage::FileReject(ID)];
+        assert_eq!(transfers.on_message(SENDER, offer.clone()), reject);
+        // A repeated offer gets the same answer
+        assert_eq!(transfers.on_message(SENDER, offer), reject);
+        assert!(transfers.pending_offer().is_none());
+    }
+
+    #[test]
+    fn upload_reaches_the_receiver() {
+        let dir = test_dir("upload");
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let data: Vec<u8> = (0..CHUNK_SIZE * 40 + 1).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &data).unwrap();
+
+        let sender = Arc::new(Transfers::new(None));
+        let receiver = Transfers::new(Some(dir.join("received")));
+        sender.offer(RECEIVER, source);
+
+        for _ in 0..2000 {
+            for (peer, message) in sender.pump() {
+                assert_eq!(peer, RECEIVER);
+                for reply in receiver.on_message(SENDER, message) {
+                    sender.on_message(RECEIVER, reply);
+                }
+            }
+            if let Some(offer) = receiver.pending_offer() {
+                receiver.accept(offer.id);
+            }
+            for (peer, message) in receiver.pump() {
+                assert_eq!(peer, SENDER);
+                sender.on_message(RECEIVER, message);
+            }
+            if sender
+                .statuses()
+                .first()
+                .is_some_and(|s| s.state.finished())
+            {
+                break;
+            }
+            sleep(PUMP_INTERVAL);
+        }
+
+        assert_eq!(sender.statuses()[0].state, TransferState::Done);
+        assert_eq!(receiver.statuses()[0].state, TransferState::Done);
+        assert_eq!(fs::read(dir.join("received/source.bin")).unwrap(), data);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
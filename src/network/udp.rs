@@ -1,100 +1,672 @@
-use log::{debug, info, trace};
-use std::collections::VecDeque;
-use std::net::{SocketAddr, UdpSocket};
+use crate::audio::adapt::RateController;
+use crate::audio::bridge::BrowserBridge;
+use crate::config::NetworkConfig;
+use crate::network::capture::Capture;
+use crate::network::chat::Chat;
+use crate::network::floor::{FloorControl, Talkers};
+use crate::network::impair::{Impairment, Impairments, impaired};
+use crate::network::protocol::{self, AudioHeader, ControlMessage, Packet};
+use crate::network::report::ReceptionStats;
+use crate::network::rtp::{ReportBlock, RtpSession};
+use crate::network::stats::SessionStats;
+use crate::network::transfer::Transfers;
+use crate::network::webrtc::WebRtcTransport;
+use anyhow::{Context, Result};
+use log::{debug, info, trace, warn};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread::{sleep, spawn};
 use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::mpsc::{self, error::TrySendError};
 
-pub type AudioBuffer = Arc<Mutex<VecDeque<u8>>>;
-
-// pub fn udp_recv(port: u16, udp_socket: &UdpSocket) {
-//     info!("🎧 UDP listening on port {}", port);
-
-//     let udp_recv = udp_socket.try_clone().unwrap();
-//     spawn(move || {
-//         let mut buf = [0u8; 1024];
-//         loop {
-//             if let Ok((len, from)) = udp_recv.recv_from(&mut buf) {
-//                 let msg = String::from_utf8_lossy(&buf[..len]);
-//                 debug!("From {} → {}", from, msg);
-//             }
-//             sleep(Duration::from_millis(50));
-//         }
-//     });
-// }
-
-pub fn audio_udp_recv(port: u16, udp_socket: &UdpSocket, audio_buffer: AudioBuffer) {
-    info!("🎧 UDP listening on port {}", port);
-
-    let udp_recv = udp_socket.try_clone().unwrap();
-
-    spawn(move || {
-        let mut buf = [0u8; 65535];
-
-        loop {
-            if let Ok((len, from)) = udp_recv.recv_from(&mut buf) {
-                if len == 0 {
-                    debug!("Empty packet from {}", from);
-                } else {
-                    // IMPORTANT PART
-                    // Each UDP packet IS one Opus packet — no reassembly needed
-                    // Push it length-prefixed into the buffer for the decoder
-                    push_opus_packet(&audio_buffer, &buf[..len]);
-
-                    trace!(
-                        "From {} → received Opus packet {} bytes, buffer size: {}",
-                        from,
-                        len,
-                        audio_buffer.lock().unwrap().len()
-                    );
-                }
-            }
+/// One received Opus packet and the frame size its header announced.
+pub struct AudioPacket {
+    pub sequence: u16,
+    pub frame_samples: usize,
+    pub payload: Vec<u8>,
+}
 
-            sleep(Duration::from_millis(1));
+/// Opus packets queued per sender, so each peer gets its own decoder in the
+/// playback mixer.
+pub type AudioBuffer = Arc<Mutex<HashMap<SocketAddr, VecDeque<AudioPacket>>>>;
+
+/// Shared state the receive loop feeds besides the audio queues.
+#[derive(Clone)]
+pub struct RecvHandlers {
+    pub talkers: Arc<Talkers>,
+    pub floor: Arc<FloorControl>,
+    pub reception: Arc<ReceptionStats>,
+    pub rate: Arc<RateController>,
+    pub rtp: Arc<RtpSession>,
+    pub webrtc: Option<Arc<WebRtcTransport>>,
+    /// Browser clients that hear everything we hear.
+    pub bridge: Option<Arc<BrowserBridge>>,
+    pub chat: Arc<Chat>,
+    pub transfers: Arc<Transfers>,
+    pub stats: Arc<SessionStats>,
+    /// Debug capture of every received datagram.
+    pub capture: Option<Arc<Capture>>,
+}
+
+impl RecvHandlers {
+    /// Queue one Opus packet from `from`, whichever transport it came in on,
+    /// and count it towards the reception report `from` gets back.
+    pub fn on_audio(
+        &self,
+        audio_buffer: &AudioBuffer,
+        from: SocketAddr,
+        header: &AudioHeader,
+        payload: &[u8],
+    ) -> usize {
+        let queued = push_opus_packet(audio_buffer, from, header, payload);
+        self.talkers.heard(from);
+        self.stats.on_received(from, header.sequence, payload.len());
+        self.reception.on_packet(from, header);
+        self.floor.on_remote_taken(from);
+        if let Some(bridge) = &self.bridge {
+            bridge.on_packet(from, header, payload);
         }
-    });
+        queued
+    }
 }
 
-// pub fn udp_send(
-//     udp_socket: &UdpSocket,
-//     input: String,
-//     peers_snapshot: Vec<SocketAddr>,
-//     device_name: String,
-// ) {
-//     let udp_snd = udp_socket.try_clone().unwrap();
+/// Datagrams waiting for the send task before new ones are dropped.
+const SEND_QUEUE_LEN: usize = 1024;
+
+/// Which DSCP a datagram is marked with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TrafficClass {
+    Audio,
+    Data,
+}
 
-//     for peer in &peers_snapshot {
-//         let msg = format!("🎙 {} says {}", device_name, input.trim());
-//         let _ = udp_snd.send_to(msg.as_bytes(), peer);
-//     }
-//     // sleep(Duration::from_secs(3));
-// }
+impl TrafficClass {
+    /// Chat and file transfer are bulk; everything else is as urgent as the audio.
+    fn of(message: &ControlMessage) -> Self {
+        match message {
+            ControlMessage::Chat(_)
+            | ControlMessage::ChatAck(_)
+            | ControlMessage::FileOffer(_)
+            | ControlMessage::FileAccept(_)
+            | ControlMessage::FileReject(_)
+            | ControlMessage::FileChunk { .. }
+            | ControlMessage::FileAck { .. }
+            | ControlMessage::FileDone { .. }
+            | ControlMessage::FileCancel(_) => Self::Data,
+            _ => Self::Audio,
+        }
+    }
+}
 
-pub fn udp_send_audio(udp_socket: &UdpSocket, audio_bytes: &[u8], peers_snapshot: &[SocketAddr]) {
-    use log::warn;
-    if peers_snapshot.is_empty() {
-        return;
+/// Conventional name of a DSCP value (EF, AF11, CS1, ...).
+pub fn dscp_name(dscp: u8) -> String {
+    match dscp {
+        0 => "BE".to_string(),
+        46 => "EF".to_string(),
+        44 => "VOICE-ADMIT".to_string(),
+        d if d % 8 == 0 => format!("CS{}", d / 8),
+        d if (8..40).contains(&d) && d % 2 == 0 && d % 8 != 0 => {
+            format!("AF{}{}", d / 8, (d % 8) / 2)
+        }
+        d => d.to_string(),
     }
+}
 
-    let udp_snd = udp_socket.try_clone().unwrap();
+/// The DSCP values outgoing datagrams are marked with, shared between the
+/// send task and the statistics panel.
+pub struct DscpMarking {
+    audio: u8,
+    data: u8,
+    /// The platform refused the option; everything goes out unmarked.
+    failed: AtomicBool,
+}
 
-    for peer in peers_snapshot {
-        if let Err(e) = udp_snd.send_to(audio_bytes, peer) {
+impl DscpMarking {
+    /// Out of range values fall back to 0.
+    pub fn new(config: &NetworkConfig) -> Self {
+        let checked = |dscp: u8| if dscp > 63 { 0 } else { dscp };
+        Self {
+            audio: checked(config.audio_dscp),
+            data: checked(config.data_dscp),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// `(audio, data)` DSCP in effect, or `None` if sending unmarked.
+    pub fn effective(&self) -> Option<(u8, u8)> {
+        (!self.failed.load(Ordering::Relaxed)).then_some((self.audio, self.data))
+    }
+}
+
+/// A datagram queued for the send task.
+#[derive(Clone)]
+struct Outgoing {
+    datagram: Vec<u8>,
+    targets: Vec<SocketAddr>,
+    class: TrafficClass,
+}
+
+/// Queue into the network core's send task. Cheap to clone and never
+/// blocks, so audio workers and UI threads send without touching the socket.
+#[derive(Clone)]
+pub struct NetSender {
+    queue: mpsc::Sender<Outgoing>,
+}
+
+impl NetSender {
+    /// Send `datagram` to every target, marked as audio; dropped if the queue is full.
+    pub fn send_to(&self, datagram: Vec<u8>, targets: &[SocketAddr]) {
+        self.enqueue(datagram, targets, TrafficClass::Audio);
+    }
+
+    fn enqueue(&self, datagram: Vec<u8>, targets: &[SocketAddr], class: TrafficClass) {
+        if targets.is_empty() {
+            return;
+        }
+        let outgoing = Outgoing {
+            datagram,
+            targets: targets.to_vec(),
+            class,
+        };
+        if let Err(TrySendError::Full(outgoing)) = self.queue.try_send(outgoing) {
             warn!(
-                "Failed to send {} bytes to {}: {}",
-                audio_bytes.len(),
-                peer,
-                e
+                "Send queue full, dropping {} bytes",
+                outgoing.datagram.len()
             );
         }
     }
+
+    pub fn send_control(&self, message: &ControlMessage, peers: &[SocketAddr]) {
+        self.enqueue(
+            protocol::encode_control(message),
+            peers,
+            TrafficClass::of(message),
+        );
+    }
+
+    /// Send one Opus packet to every peer, plus the RTP forward target.
+    pub fn send_audio(
+        &self,
+        rtp: &RtpSession,
+        header: &AudioHeader,
+        audio_bytes: &[u8],
+        peers: &[SocketAddr],
+    ) {
+        if peers.is_empty() && rtp.forward().is_none() {
+            return;
+        }
+
+        let rtp_datagram =
+            (rtp.enabled() || rtp.forward().is_some()).then(|| rtp.encode(header, audio_bytes));
+        let datagram = match &rtp_datagram {
+            Some(rtp_datagram) if rtp.enabled() => rtp_datagram.clone(),
+            _ => protocol::encode_audio(header, audio_bytes),
+        };
+
+        if let (Some(forward), Some(rtp_datagram)) = (rtp.forward(), rtp_datagram) {
+            self.send_to(rtp_datagram, &[forward]);
+        }
+        self.send_to(datagram, peers);
+    }
+}
+
+/// The UDP socket and the tokio runtime its tasks run on: one task receives
+/// and queues audio, one handles control traffic and one sends everything
+/// queued through [`NetSender`].
+pub struct NetworkCore {
+    runtime: Runtime,
+    socket: Arc<UdpSocket>,
+    sender: NetSender,
+    marking: Arc<DscpMarking>,
+    /// Applied to received datagrams before dispatch.
+    receive_impairment: Option<Impairment>,
+}
+
+impl NetworkCore {
+    /// Bind the shared audio/control socket on `port` and start sending.
+    pub fn bind(port: u16, config: &NetworkConfig, impairments: &Impairments) -> Result<Self> {
+        Self::with_socket(bind_socket(port, config)?, config, impairments)
+    }
+
+    /// Run on an already bound socket, e.g. one on localhost in tests.
+    pub fn with_socket(
+        std_socket: std::net::UdpSocket,
+        config: &NetworkConfig,
+        impairments: &Impairments,
+    ) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("network")
+            .enable_all()
+            .build()
+            .context("Failed to start network runtime")?;
+
+        std_socket.set_nonblocking(true)?;
+        let socket = {
+            let _guard = runtime.enter();
+            Arc::new(UdpSocket::from_std(std_socket)?)
+        };
+
+        let (mut queue, outgoing) = mpsc::channel(SEND_QUEUE_LEN);
+        for (what, dscp) in [("Audio", config.audio_dscp), ("Data", config.data_dscp)] {
+            if dscp > 63 {
+                warn!("{} DSCP {} out of range, using 0", what, dscp);
+            }
+        }
+        let marking = Arc::new(DscpMarking::new(config));
+        runtime.spawn(send_task(
+            socket.clone(),
+            outgoing,
+            Marking::new(marking.clone()),
+        ));
+        if let Some(impairment) = impairments.send.clone() {
+            queue = impaired(runtime.handle(), impairment, queue, SEND_QUEUE_LEN);
+        }
+
+        Ok(Self {
+            runtime,
+            socket,
+            sender: NetSender { queue },
+            marking,
+            receive_impairment: impairments.receive.clone(),
+        })
+    }
+
+    pub fn sender(&self) -> NetSender {
+        self.sender.clone()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// The DSCP marking actually applied to what we send.
+    pub fn marking(&self) -> Arc<DscpMarking> {
+        self.marking.clone()
+    }
+
+    /// Stop every task on the network runtime and close the socket.
+    pub fn shutdown(self) {
+        self.runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    }
+
+    /// For spawning further network tasks (control, discovery).
+    pub fn handle(&self) -> Handle {
+        self.runtime.handle().clone()
+    }
+
+    /// Start the receive task, feeding `audio_buffer` and `handlers`.
+    pub fn start_receive(&self, audio_buffer: AudioBuffer, handlers: RecvHandlers) {
+        let port = self.socket.local_addr().map_or(0, |addr| addr.port());
+        info!("🎧 UDP listening on port {}", port);
+        let (control, inbound) = mpsc::channel(CONTROL_QUEUE_LEN);
+        self.runtime.spawn(control_receive_task(
+            handlers.clone(),
+            self.sender(),
+            inbound,
+        ));
+        let dispatcher = Dispatcher {
+            audio_buffer,
+            handlers,
+            control,
+        };
+        self.runtime.spawn(receive_task(
+            self.socket.clone(),
+            dispatcher,
+            self.receive_impairment.clone(),
+        ));
+    }
+}
+
+/// How long shutdown waits for network tasks to finish.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The audio/control socket on `port`, set up through socket2: dual-stack
+/// IPv6 where the host supports it (IPv4 peers arrive as mapped addresses),
+/// plain IPv4 otherwise.
+pub fn bind_socket(port: u16, config: &NetworkConfig) -> Result<std::net::UdpSocket> {
+    let socket = match bind_dual_stack(port) {
+        Ok(socket) => socket,
+        Err(e) => {
+            debug!("No dual-stack socket ({}), using IPv4 only", e);
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            socket
+                .bind(&addr.into())
+                .with_context(|| format!("Cannot bind UDP port {port}"))?;
+            socket
+        }
+    };
+    // Larger kernel buffers ride out bursts while a task is busy; not fatal if refused
+    if let Err(e) = socket.set_recv_buffer_size(config.recv_buffer) {
+        warn!(
+            "Cannot set receive buffer to {} bytes: {}",
+            config.recv_buffer, e
+        );
+    }
+    if let Err(e) = socket.set_send_buffer_size(config.send_buffer) {
+        warn!(
+            "Cannot set send buffer to {} bytes: {}",
+            config.send_buffer, e
+        );
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+fn bind_dual_stack(port: u16) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket)
+}
+
+/// How a dual-stack socket addresses an IPv4 peer.
+fn to_mapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::from((v4.ip().to_ipv6_mapped(), v4.port())),
+        v6 => v6,
+    }
+}
+
+/// The plain IPv4 address behind a mapped one, so peers compare equal
+/// whichever socket family they reached us on.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Applies the configured DSCP to the socket before each datagram. Sends are
+/// serialized through one task, so the mark only changes with the class. IPv4
+/// (and mapped) destinations take it from the ToS byte, IPv6 ones from the
+/// traffic class, and each is set separately.
+struct Marking {
+    status: Arc<DscpMarking>,
+    current_v4: Option<TrafficClass>,
+    current_v6: Option<TrafficClass>,
+}
+
+impl Marking {
+    fn new(status: Arc<DscpMarking>) -> Self {
+        Self {
+            status,
+            current_v4: None,
+            current_v6: None,
+        }
+    }
+
+    fn apply(&mut self, socket: &UdpSocket, class: TrafficClass, target: SocketAddr) {
+        let Some((audio, data)) = self.status.effective() else {
+            return;
+        };
+        let v6 = canonical(target).is_ipv6();
+        let current = if v6 {
+            &mut self.current_v6
+        } else {
+            &mut self.current_v4
+        };
+        if *current == Some(class) {
+            return;
+        }
+        let dscp = match class {
+            TrafficClass::Audio => audio,
+            TrafficClass::Data => data,
+        };
+        // DSCP is the top six bits of the ToS / traffic class byte
+        let tos = u32::from(dscp) << 2;
+        let socket = SockRef::from(socket);
+        let result = if v6 {
+            set_tclass_v6(&socket, tos)
+        } else {
+            socket.set_tos(tos)
+        };
+        match result {
+            Ok(()) => *current = Some(class),
+            Err(e) => {
+                // Typically a platform without ToS support; send unmarked from now on
+                warn!("Cannot set DSCP {} ({}): {}", dscp, dscp_name(dscp), e);
+                self.status.failed.store(true, Ordering::Relaxed);
+            }
+        }
+    }
 }
 
-// In your UDP receive handler, before pushing into AudioBuffer:
-fn push_opus_packet(buffer: &AudioBuffer, packet: &[u8]) {
-    let mut buf = buffer.lock().unwrap();
-    let len = packet.len() as u16;
-    buf.push_back(len as u8); // low byte
-    buf.push_back((len >> 8) as u8); // high byte
-    buf.extend(packet.iter().copied());
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn set_tclass_v6(socket: &SockRef, tclass: u32) -> std::io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+fn set_tclass_v6(_socket: &SockRef, _tclass: u32) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+async fn send_task(
+    socket: Arc<UdpSocket>,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+    mut marking: Marking,
+) {
+    let dual_stack = socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
+    while let Some(Outgoing {
+        datagram,
+        targets,
+        class,
+    }) = outgoing.recv().await
+    {
+        for target in targets {
+            marking.apply(&socket, class, target);
+            let target = if dual_stack {
+                to_mapped(target)
+            } else {
+                target
+            };
+            if let Err(e) = socket.send_to(&datagram, target).await {
+                warn!(
+                    "Failed to send {} bytes to {}: {}",
+                    datagram.len(),
+                    target,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Received datagrams not yet dispatched before new ones are dropped
+/// (only queued when receive impairment delays them).
+const RECEIVE_QUEUE_LEN: usize = 1024;
+
+async fn receive_task(
+    socket: Arc<UdpSocket>,
+    dispatcher: Dispatcher,
+    impairment: Option<Impairment>,
+) {
+    let mut buf = vec![0u8; 65535];
+
+    // Impaired datagrams take a detour through timers to a dispatch task
+    let impaired = impairment.map(|impairment| {
+        let (queue, mut received) = mpsc::channel::<(Vec<u8>, SocketAddr)>(RECEIVE_QUEUE_LEN);
+        let dispatcher = dispatcher.clone();
+        tokio::spawn(async move {
+            while let Some((datagram, from)) = received.recv().await {
+                dispatcher.dispatch(&datagram, from);
+            }
+        });
+        impaired(&Handle::current(), impairment, queue, RECEIVE_QUEUE_LEN)
+    });
+
+    loop {
+        // Errors are per datagram (e.g. ICMP port unreachable on Windows)
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok((len, from)) => (len, canonical(from)),
+            Err(e) => {
+                debug!("UDP receive error: {}", e);
+                continue;
+            }
+        };
+
+        match &impaired {
+            Some(queue) => {
+                let _ = queue.try_send((buf[..len].to_vec(), from));
+            }
+            None => dispatcher.dispatch(&buf[..len], from),
+        }
+    }
+}
+
+/// Control datagrams decoded but not yet handled before new ones are dropped.
+const CONTROL_QUEUE_LEN: usize = 1024;
+
+/// A received datagram for the control task.
+enum Inbound {
+    Control(ControlMessage),
+    Rtcp(Vec<ReportBlock>),
+}
+
+/// Everything a received datagram may be handed to.
+#[derive(Clone)]
+struct Dispatcher {
+    audio_buffer: AudioBuffer,
+    handlers: RecvHandlers,
+    /// Control traffic goes to its own task, so a slow handler (say, a file
+    /// chunk) never holds up the audio behind it.
+    control: mpsc::Sender<(SocketAddr, Inbound)>,
+}
+
+impl Dispatcher {
+    fn dispatch(&self, datagram: &[u8], from: SocketAddr) {
+        let Self {
+            audio_buffer,
+            handlers,
+            control,
+        } = self;
+        let len = datagram.len();
+
+        if let Some(capture) = &handlers.capture {
+            capture.record(from, datagram);
+        }
+
+        match protocol::decode(datagram) {
+            None => debug!("Empty or unknown packet from {}", from),
+            Some(Packet::Audio {
+                header,
+                ssrc,
+                payload,
+            }) => {
+                // IMPORTANT PART
+                // Each UDP packet IS one Opus packet — no reassembly needed
+                // Queue it with its header so the decoder knows the frame size
+                let queued = handlers.on_audio(audio_buffer, from, &header, payload);
+                if let Some(ssrc) = ssrc {
+                    handlers.rtp.on_remote_stream(from, ssrc);
+                }
+
+                trace!(
+                    "From {} → received Opus packet {} bytes, queued packets: {}",
+                    from, len, queued
+                );
+            }
+            Some(Packet::Control(message)) => hand_off(control, from, Inbound::Control(message)),
+            Some(Packet::Rtcp(blocks)) => hand_off(control, from, Inbound::Rtcp(blocks)),
+        }
+    }
+}
+
+fn hand_off(control: &mpsc::Sender<(SocketAddr, Inbound)>, from: SocketAddr, inbound: Inbound) {
+    if let Err(TrySendError::Full(_)) = control.try_send((from, inbound)) {
+        debug!("Control queue full, dropping a packet from {}", from);
+    }
+}
+
+async fn control_receive_task(
+    handlers: RecvHandlers,
+    net: NetSender,
+    mut inbound: mpsc::Receiver<(SocketAddr, Inbound)>,
+) {
+    while let Some((from, inbound)) = inbound.recv().await {
+        match inbound {
+            Inbound::Control(message) => handle_control(&handlers, &net, from, message),
+            Inbound::Rtcp(blocks) => {
+                // Only blocks about our own stream matter; SRs without blocks are skipped
+                for block in blocks.iter().filter(|b| b.ssrc == handlers.rtp.ssrc()) {
+                    handlers.rate.on_report(from, block.report);
+                }
+            }
+        }
+    }
+}
+
+fn handle_control(
+    handlers: &RecvHandlers,
+    net: &NetSender,
+    from: SocketAddr,
+    message: ControlMessage,
+) {
+    match message {
+        ControlMessage::FloorTaken => handlers.floor.on_remote_taken(from),
+        ControlMessage::FloorReleased => handlers.floor.on_remote_released(from),
+        ControlMessage::ReceiverReport(report) => handlers.rate.on_report(from, report),
+        ControlMessage::WebRtcOffer(sdp) => match &handlers.webrtc {
+            Some(webrtc) => webrtc.on_offer(from, sdp),
+            None => debug!("Ignoring WebRTC offer from {} (WebRTC disabled)", from),
+        },
+        ControlMessage::WebRtcAnswer(sdp) => match &handlers.webrtc {
+            Some(webrtc) => webrtc.on_answer(from, sdp),
+            None => debug!("Ignoring WebRTC answer from {} (WebRTC disabled)", from),
+        },
+        ControlMessage::Chat(chat) => {
+            // Always acknowledge, even duplicates: the first ack may have been lost
+            net.send_control(&ControlMessage::ChatAck(chat.id), &[from]);
+            handlers.chat.on_message(from, chat);
+        }
+        ControlMessage::ChatAck(id) => handlers.chat.on_ack(from, id),
+        message @ (ControlMessage::FileOffer(_)
+        | ControlMessage::FileAccept(_)
+        | ControlMessage::FileReject(_)
+        | ControlMessage::FileChunk { .. }
+        | ControlMessage::FileAck { .. }
+        | ControlMessage::FileDone { .. }
+        | ControlMessage::FileCancel(_)) => {
+            let replies = handlers.transfers.on_message(from, message);
+            for reply in replies {
+                net.send_control(&reply, &[from]);
+            }
+        }
+        ControlMessage::Ping(token) => net.send_control(&ControlMessage::Pong(token), &[from]),
+        ControlMessage::Pong(token) => handlers.stats.on_pong(from, token),
+    }
+}
+
+/// Queue one Opus packet for the mixer; returns the sender's queue length.
+pub fn push_opus_packet(
+    buffer: &AudioBuffer,
+    from: SocketAddr,
+    header: &AudioHeader,
+    packet: &[u8],
+) -> usize {
+    let mut streams = buffer.lock().unwrap();
+    let buf = streams.entry(from).or_default();
+    buf.push_back(AudioPacket {
+        sequence: header.sequence,
+        frame_samples: header.frame_samples as usize,
+        payload: packet.to_vec(),
+    });
+    buf.len()
 }
@@ -0,0 +1,457 @@
+//! Optional WebRTC transport: the same Opus stream carried over a peer
+//! connection (ICE + DTLS-SRTP) instead of plain UDP, so peers on other
+//! subnets or behind NAT on the site network can still reach each other.
+//!
+//! Signalling runs over our UDP control channel: the peer with the lower
+//! address offers, the other answers. Candidates are gathered up front and
+//! sent inside the SDP, so one offer and one answer are all it takes.
+//!
+//! The same machinery accepts browser clients (see `ui::web`), which get a
+//! track of their own instead of the shared one.
+
+use crate::network::protocol::{AudioHeader, ControlMessage};
+use crate::network::rtp::opus_header;
+use crate::network::udp::{AudioBuffer, NetSender, RecvHandlers};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MIME_TYPE_OPUS, MediaEngine};
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::api::{API, APIBuilder};
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::interceptor::registry::Registry;
+use webrtc::media::Sample;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_local::TrackLocal;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_remote::TrackRemote;
+
+/// An unanswered offer is retried after this long (lost datagram, peer restarting).
+const OFFER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Frames waiting to be written to tracks before new ones are dropped.
+const WRITE_QUEUE_LEN: usize = 64;
+
+/// A frame on its way to a track.
+type TrackWrite = (Arc<TrackLocalStaticSample>, Sample);
+
+/// Called with every Opus packet arriving on a peer connection.
+pub type OnAudio = Arc<dyn Fn(&AudioHeader, &[u8]) + Send + Sync>;
+
+/// A peer connection in progress or established; `pc` is filled in once the
+/// async setup finishes.
+struct Link {
+    pc: Option<Arc<RTCPeerConnection>>,
+    since: Instant,
+}
+
+pub struct WebRtcTransport {
+    /// Always `Some` until dropped.
+    runtime: Option<Runtime>,
+    api: API,
+    config: RTCConfiguration,
+    local_addr: SocketAddr,
+    net: NetSender,
+    /// Our outgoing audio, shared by every peer connection.
+    track: Arc<TrackLocalStaticSample>,
+    /// Frames for the writer task, so encoders never wait on the runtime.
+    writes: mpsc::Sender<TrackWrite>,
+    links: Mutex<HashMap<SocketAddr, Link>>,
+    connected: Arc<Mutex<HashSet<SocketAddr>>>,
+    audio_buffer: AudioBuffer,
+    handlers: RecvHandlers,
+}
+
+impl WebRtcTransport {
+    pub fn new(
+        net: NetSender,
+        local_addr: SocketAddr,
+        ice_servers: &[String],
+        audio_buffer: AudioBuffer,
+        handlers: RecvHandlers,
+    ) -> Result<Arc<Self>> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("webrtc")
+            .enable_all()
+            .build()
+            .context("Failed to start WebRTC runtime")?;
+
+        let mut media = MediaEngine::default();
+        media.register_default_codecs()?;
+        let registry = register_default_interceptors(Registry::new(), &mut media)?;
+        // Peers on one machine (tests) only reach each other over loopback
+        let mut settings = SettingEngine::default();
+        settings.set_include_loopback_candidate(local_addr.ip().is_loopback());
+        let api = APIBuilder::new()
+            .with_media_engine(media)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(settings)
+            .build();
+
+        let config = RTCConfiguration {
+            ice_servers: ice_servers
+                .iter()
+                .map(|url| RTCIceServer {
+                    urls: vec![url.clone()],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let (writes, queue) = mpsc::channel(WRITE_QUEUE_LEN);
+        runtime.spawn(write_samples(queue));
+
+        Ok(Arc::new(Self {
+            runtime: Some(runtime),
+            api,
+            config,
+            local_addr,
+            net,
+            track: Self::audio_track(),
+            writes,
+            links: Mutex::new(HashMap::new()),
+            connected: Arc::new(Mutex::new(HashSet::new())),
+            audio_buffer,
+            handlers,
+        }))
+    }
+
+    fn runtime(&self) -> &Runtime {
+        self.runtime
+            .as_ref()
+            .expect("WebRTC runtime already shut down")
+    }
+
+    /// A new outgoing Opus track.
+    pub fn audio_track() -> Arc<TrackLocalStaticSample> {
+        Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                clock_rate: 48000,
+                channels: 2,
+                ..Default::default()
+            },
+            "audio".to_owned(),
+            "vl".to_owned(),
+        ))
+    }
+
+    /// Peers currently reachable over WebRTC.
+    pub fn connected_peers(&self) -> HashSet<SocketAddr> {
+        self.connected.lock().unwrap().clone()
+    }
+
+    /// Peers from `peers` that still need audio over plain UDP.
+    pub fn udp_peers(&self, peers: &[SocketAddr]) -> Vec<SocketAddr> {
+        let connected = self.connected.lock().unwrap();
+        peers
+            .iter()
+            .filter(|peer| !connected.contains(peer))
+            .copied()
+            .collect()
+    }
+
+    /// Send one encoded frame to every connected peer.
+    pub fn send(&self, opus: &[u8], duration: Duration) {
+        if self.connected.lock().unwrap().is_empty() {
+            return;
+        }
+        self.write(&self.track, opus, duration);
+    }
+
+    /// Queue one encoded frame for `track`; never blocks. Frames are
+    /// dropped while the writer is behind.
+    pub fn write(&self, track: &Arc<TrackLocalStaticSample>, opus: &[u8], duration: Duration) {
+        let sample = Sample {
+            data: Bytes::copy_from_slice(opus),
+            duration,
+            ..Default::default()
+        };
+        if self.writes.try_send((track.clone(), sample)).is_err() {
+            debug!("WebRTC write queue full, frame dropped");
+        }
+    }
+
+    /// Answer a browser's offer. `key` names the client in logs; its audio
+    /// goes to `on_audio` and it hears whatever is written to `track`.
+    /// Blocks until ICE gathering is done.
+    pub fn accept_browser(
+        &self,
+        key: SocketAddr,
+        offer: String,
+        track: Arc<TrackLocalStaticSample>,
+        on_audio: OnAudio,
+    ) -> Result<(String, Arc<RTCPeerConnection>)> {
+        self.runtime().block_on(async {
+            let pc = self.new_peer_connection(key, track, on_audio, None).await?;
+            pc.set_remote_description(RTCSessionDescription::offer(offer)?)
+                .await?;
+            let answer = pc.create_answer(None).await?;
+            let answer = self.gather(&pc, answer).await?;
+            Ok((answer, pc))
+        })
+    }
+
+    /// Offer to discovered peers we're responsible for, retry stale offers
+    /// and drop peers that went away. Call periodically.
+    pub fn maintain(self: &Arc<Self>, peers: &[SocketAddr]) {
+        let mut links = self.links.lock().unwrap();
+
+        links.retain(|peer, link| {
+            let keep = peers.contains(peer);
+            if !keep && let Some(pc) = link.pc.take() {
+                self.close(*peer, pc);
+            }
+            keep
+        });
+
+        let connected = self.connected.lock().unwrap().clone();
+        for &peer in peers {
+            // The lower address offers, so two peers never offer to each other
+            if self.local_addr >= peer || connected.contains(&peer) {
+                continue;
+            }
+            if links
+                .get(&peer)
+                .is_some_and(|link| link.since.elapsed() < OFFER_TIMEOUT)
+            {
+                continue;
+            }
+
+            if let Some(pc) = links.remove(&peer).and_then(|link| link.pc) {
+                self.close(peer, pc);
+            }
+            links.insert(
+                peer,
+                Link {
+                    pc: None,
+                    since: Instant::now(),
+                },
+            );
+
+            let transport = self.clone();
+            self.runtime().spawn(async move {
+                if let Err(e) = transport.offer(peer).await {
+                    warn!("WebRTC offer to {} failed: {:#}", peer, e);
+                }
+            });
+        }
+    }
+
+    pub fn on_offer(self: &Arc<Self>, from: SocketAddr, sdp: String) {
+        let transport = self.clone();
+        self.runtime().spawn(async move {
+            match transport.answer(from, sdp).await {
+                Ok(answer) => transport
+                    .net
+                    .send_control(&ControlMessage::WebRtcAnswer(answer), &[from]),
+                Err(e) => warn!("WebRTC answer to {} failed: {:#}", from, e),
+            }
+        });
+    }
+
+    pub fn on_answer(self: &Arc<Self>, from: SocketAddr, sdp: String) {
+        let Some(pc) = self
+            .links
+            .lock()
+            .unwrap()
+            .get(&from)
+            .and_then(|link| link.pc.clone())
+        else {
+            debug!("WebRTC answer from {} without a pending offer", from);
+            return;
+        };
+
+        self.runtime().spawn(async move {
+            let result = match RTCSessionDescription::answer(sdp) {
+                Ok(answer) => pc.set_remote_description(answer).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Bad WebRTC answer from {}: {}", from, e);
+            }
+        });
+    }
+
+    async fn offer(self: Arc<Self>, peer: SocketAddr) -> Result<()> {
+        let pc = self.native_peer_connection(peer).await?;
+        if let Some(link) = self.links.lock().unwrap().get_mut(&peer) {
+            link.pc = Some(pc.clone());
+        }
+
+        let offer = pc.create_offer(None).await?;
+        let local = self.gather(&pc, offer).await?;
+
+        info!("Sending WebRTC offer to {}", peer);
+        self.net
+            .send_control(&ControlMessage::WebRtcOffer(local), &[peer]);
+        Ok(())
+    }
+
+    /// Accept an offer from `from` and return the answer SDP.
+    pub async fn answer(self: &Arc<Self>, from: SocketAddr, sdp: String) -> Result<String> {
+        let pc = self.native_peer_connection(from).await?;
+        let previous = self.links.lock().unwrap().insert(
+            from,
+            Link {
+                pc: Some(pc.clone()),
+                since: Instant::now(),
+            },
+        );
+        // A repeated offer replaces the old connection
+        if let Some(old) = previous.and_then(|link| link.pc) {
+            self.close(from, old);
+        }
+
+        pc.set_remote_description(RTCSessionDescription::offer(sdp)?)
+            .await?;
+        let answer = pc.create_answer(None).await?;
+
+        info!("Answering WebRTC offer from {}", from);
+        self.gather(&pc, answer).await
+    }
+
+    /// Set the local description and wait for every ICE candidate.
+    async fn gather(
+        &self,
+        pc: &RTCPeerConnection,
+        description: RTCSessionDescription,
+    ) -> Result<String> {
+        let mut gathered = pc.gathering_complete_promise().await;
+        pc.set_local_description(description).await?;
+        let _ = gathered.recv().await;
+
+        let local = pc
+            .local_description()
+            .await
+            .context("No local description after ICE gathering")?;
+        Ok(local.sdp)
+    }
+
+    /// A connection to another instance: our shared track out, its audio
+    /// into the playback queues, tracked in `connected`.
+    async fn native_peer_connection(&self, peer: SocketAddr) -> Result<Arc<RTCPeerConnection>> {
+        let audio_buffer = self.audio_buffer.clone();
+        let handlers = self.handlers.clone();
+        let on_audio: OnAudio = Arc::new(move |header, payload| {
+            handlers.on_audio(&audio_buffer, peer, header, payload);
+        });
+        self.new_peer_connection(
+            peer,
+            self.track.clone(),
+            on_audio,
+            Some(self.connected.clone()),
+        )
+        .await
+    }
+
+    async fn new_peer_connection(
+        &self,
+        peer: SocketAddr,
+        track: Arc<TrackLocalStaticSample>,
+        on_audio: OnAudio,
+        connected: Option<Arc<Mutex<HashSet<SocketAddr>>>>,
+    ) -> Result<Arc<RTCPeerConnection>> {
+        let pc = Arc::new(self.api.new_peer_connection(self.config.clone()).await?);
+
+        let sender = pc
+            .add_track(track as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+        // RTCP has to be read for the interceptors (NACK, reports) to work
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1500];
+            while sender.read(&mut buf).await.is_ok() {}
+        });
+
+        pc.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+            info!("WebRTC link to {}: {}", peer, state);
+            let Some(connected) = &connected else {
+                return Box::pin(async {});
+            };
+            let mut connected = connected.lock().unwrap();
+            match state {
+                RTCPeerConnectionState::Connected => {
+                    connected.insert(peer);
+                }
+                RTCPeerConnectionState::Disconnected
+                | RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed => {
+                    connected.remove(&peer);
+                }
+                _ => {}
+            }
+            Box::pin(async {})
+        }));
+
+        pc.on_track(Box::new(move |track, _receiver, _transceiver| {
+            let on_audio = on_audio.clone();
+            Box::pin(async move {
+                receive_track(track, peer, on_audio).await;
+            })
+        }));
+
+        Ok(pc)
+    }
+
+    fn close(&self, peer: SocketAddr, pc: Arc<RTCPeerConnection>) {
+        self.connected.lock().unwrap().remove(&peer);
+        self.hang_up(pc);
+    }
+
+    /// Close a peer connection in the background.
+    pub fn hang_up(&self, pc: Arc<RTCPeerConnection>) {
+        self.runtime().spawn(async move {
+            let _ = pc.close().await;
+        });
+    }
+}
+
+impl Drop for WebRtcTransport {
+    fn drop(&mut self) {
+        // The last handle may go away on a runtime thread, where a blocking shutdown panics
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Write queued frames in order; ends when the transport is dropped.
+async fn write_samples(mut queue: mpsc::Receiver<TrackWrite>) {
+    while let Some((track, sample)) = queue.recv().await {
+        if let Err(e) = track.write_sample(&sample).await {
+            debug!("WebRTC write failed: {}", e);
+        }
+    }
+}
+
+/// Hand every Opus packet of a remote track to `on_audio`.
+async fn receive_track(track: Arc<TrackRemote>, peer: SocketAddr, on_audio: OnAudio) {
+    info!(
+        "WebRTC track from {}: {}",
+        peer,
+        track.codec().capability.mime_type
+    );
+
+    while let Ok((packet, _)) = track.read_rtp().await {
+        let Some(header) = opus_header(
+            &packet.payload,
+            packet.header.sequence_number,
+            packet.header.timestamp,
+        ) else {
+            continue;
+        };
+        on_audio(&header, &packet.payload);
+    }
+}
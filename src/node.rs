@@ -0,0 +1,470 @@
+//! One running walkie-talkie: transport, discovery, playback and capture,
+//! the periodic control traffic, and the shared state front-ends read.
+//! Everything outside the process is injected - the socket, how peers are
+//! found and the sound card - so several nodes can run side by side in
+//! one process on localhost.
+
+use crate::audio::backend::{AudioBackend, AudioStream};
+use crate::audio::bridge::BrowserBridge;
+use crate::audio::codec::CodecConfig;
+use crate::audio::rad::{OutputControls, start_audio_output};
+use crate::audio::sad::{MicControls, StreamOutput, start_mic_capture};
+use crate::config::{Config, ConfigStore};
+use crate::event::{Event, Stream};
+use crate::network::capture::Capture;
+use crate::network::discovery::{Discovery, Peerlist};
+use crate::network::floor::{FLOOR_REFRESH, FloorControl};
+use crate::network::impair::Impairments;
+use crate::network::protocol::ControlMessage;
+use crate::network::report::REPORT_INTERVAL;
+use crate::network::rtp::RtpSession;
+use crate::network::stats::STATS_INTERVAL;
+use crate::network::transfer::PUMP_INTERVAL;
+use crate::network::udp::{AudioBuffer, NetSender, NetworkCore, RecvHandlers};
+use crate::network::webrtc::WebRtcTransport;
+use crate::state::AppState;
+use anyhow::{Context, Result};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::time::{MissedTickBehavior, interval};
+
+/// Everything about a node that isn't an injected dependency.
+pub struct NodeOptions {
+    /// Instance name announced to peers.
+    pub name: String,
+    pub channel: String,
+    pub config: Arc<ConfigStore>,
+    pub codec: CodecConfig,
+    pub floor_control: bool,
+    /// Send RTP instead of the native format.
+    pub rtp: bool,
+    pub rtp_forward: Option<SocketAddr>,
+    /// Carry audio to peers over WebRTC.
+    pub webrtc: bool,
+    pub ice_servers: Vec<String>,
+    /// Run the browser bridge (needs the WebRTC machinery).
+    pub web: bool,
+    pub impairments: Impairments,
+    pub capture: Option<Arc<Capture>>,
+    /// Keep the chat log in this file; in memory only if `None`.
+    pub chat_history: Option<PathBuf>,
+    /// Save received files here; offers are declined if `None`.
+    pub download_dir: Option<PathBuf>,
+    /// File the `play` command and the TUI's 'P' transmit.
+    pub play_file: Option<PathBuf>,
+}
+
+impl NodeOptions {
+    /// Defaults for `name` on `channel`, with settings kept in memory.
+    pub fn new(name: &str, channel: &str) -> Self {
+        let config = Config::default();
+        Self {
+            name: name.to_string(),
+            channel: channel.to_string(),
+            codec: config.codec.clone(),
+            config: Arc::new(ConfigStore::in_memory(config)),
+            floor_control: false,
+            rtp: false,
+            rtp_forward: None,
+            webrtc: false,
+            ice_servers: Vec::new(),
+            web: false,
+            impairments: Impairments::default(),
+            capture: None,
+            chat_history: None,
+            download_dir: None,
+            play_file: None,
+        }
+    }
+}
+
+/// A running node. Dropping it without [`Node::shutdown`] leaves the audio
+/// streams to stop on their own.
+pub struct Node {
+    pub state: Arc<AppState>,
+    pub net: NetSender,
+    pub peers: Peerlist,
+    pub audio_buffer: AudioBuffer,
+    pub handlers: RecvHandlers,
+    /// The WebRTC machinery, also running when only the browser bridge needs it.
+    pub webrtc: Option<Arc<WebRtcTransport>>,
+    pub bridge: Option<Arc<BrowserBridge>>,
+    /// Where encoded frames go, for other transmit sources (file playback).
+    pub output: StreamOutput,
+    streams: Vec<Box<dyn AudioStream>>,
+    discovery: Box<dyn Discovery>,
+    network: NetworkCore,
+}
+
+impl Node {
+    /// Start a node on `socket`, finding peers through `discovery` and
+    /// playing and capturing through `audio`.
+    pub fn start(
+        socket: UdpSocket,
+        discovery: Box<dyn Discovery>,
+        audio: Arc<dyn AudioBackend>,
+        options: NodeOptions,
+    ) -> Result<Self> {
+        let local_addr = discovery.local_addr();
+        let peers = Peerlist::default();
+        let audio_buffer = AudioBuffer::default();
+
+        let network =
+            NetworkCore::with_socket(socket, &options.config.get().network, &options.impairments)?;
+        let net = network.sender();
+
+        let mut state = AppState::new(
+            options.name,
+            local_addr.ip().to_string(),
+            local_addr.port(),
+            peers.clone(),
+            options.play_file,
+            options.config,
+            Arc::new(FloorControl::new(options.floor_control, local_addr)),
+        )
+        .with_codec(options.codec)
+        .with_rtp(RtpSession::new(options.rtp, options.rtp_forward))
+        .with_channel(options.channel)
+        .with_marking(network.marking());
+        if let Some(path) = options.chat_history {
+            state = state.with_chat_history(path);
+        }
+        if let Some(dir) = options.download_dir {
+            state = state.with_download_dir(dir);
+        }
+        let state = Arc::new(state);
+
+        discovery.start(&network.handle(), peers.clone(), state.peer_names.clone());
+
+        state.events.publish(Event::SessionStarted {
+            name: state.instance_name.clone(),
+            channel: state.channel.clone(),
+        });
+        state.events.publish(Event::Listening {
+            port: local_addr.port(),
+        });
+        let impairments = &options.impairments;
+        for (direction, impairment) in [
+            ("sent", &impairments.send),
+            ("received", &impairments.receive),
+        ] {
+            if let Some(impairment) = impairment {
+                state.events.publish(Event::Impairing {
+                    direction: direction.to_string(),
+                    impairment: impairment.to_string(),
+                });
+            }
+        }
+
+        if let Some(forward) = options.rtp_forward
+            && forward.port() == u16::MAX
+        {
+            state.events.publish(Event::failure(format!(
+                "No RTCP to {}: port {} has no port after it",
+                forward,
+                forward.port()
+            )));
+        }
+
+        // Browser clients hear a mix of everything that passes through here
+        let bridge = options.web.then(|| BrowserBridge::new(local_addr));
+
+        let mut handlers = RecvHandlers {
+            talkers: state.talkers.clone(),
+            floor: state.floor.clone(),
+            reception: state.reception.clone(),
+            rate: state.rate.clone(),
+            rtp: state.rtp.clone(),
+            webrtc: None,
+            bridge: bridge.clone(),
+            chat: state.chat.clone(),
+            transfers: state.transfers.clone(),
+            stats: state.stats.clone(),
+            capture: options.capture,
+        };
+
+        // WebRTC transport: ICE + DTLS-SRTP links to peers, signalled over the UDP socket.
+        // Browser clients need it too, even when native peers stay on plain UDP.
+        let webrtc = if options.webrtc || options.web {
+            Some(
+                WebRtcTransport::new(
+                    net.clone(),
+                    local_addr,
+                    &options.ice_servers,
+                    audio_buffer.clone(),
+                    handlers.clone(),
+                )
+                .context("Failed to start WebRTC transport")?,
+            )
+        } else {
+            None
+        };
+        handlers.webrtc = webrtc.clone().filter(|_| options.webrtc);
+
+        if let Some(webrtc) = handlers.webrtc.clone() {
+            let peers = peers.clone();
+            network.handle().spawn(async move {
+                let mut maintain = interval(Duration::from_secs(1));
+                loop {
+                    maintain.tick().await;
+                    let peers_list = peers.lock().unwrap().clone();
+                    webrtc.maintain(&peers_list);
+                }
+            });
+        }
+        if let (Some(bridge), Some(transport)) = (&bridge, &webrtc) {
+            bridge.start(transport.clone());
+        }
+
+        let output = StreamOutput {
+            encoder: state.encoder.clone(),
+            rtp: state.rtp.clone(),
+            webrtc: handlers.webrtc.clone(),
+            bridge: bridge.clone(),
+            stats: state.stats.clone(),
+        };
+
+        network.start_receive(audio_buffer.clone(), handlers.clone());
+
+        let mut streams = Vec::new();
+        let speaker = start_audio_output(
+            audio.as_ref(),
+            audio_buffer.clone(),
+            OutputControls {
+                speaker_level: state.speaker_level.clone(),
+                peer_levels: state.peer_levels.clone(),
+                peer_names: state.peer_names.clone(),
+                volume: state.volume.clone(),
+                floor: state.floor.clone(),
+                stats: state.stats.clone(),
+                events: state.events.clone(),
+            },
+        );
+        speaker.play().context("Failed to play audio stream")?;
+        streams.push(speaker);
+        state
+            .stats
+            .pipeline
+            .output_running
+            .store(true, Ordering::Relaxed);
+        state.events.publish(Event::StreamStarted {
+            stream: Stream::Output,
+        });
+
+        // Jitter buffer depth for the status panel and statistics
+        let buffered = audio_buffer.clone();
+        let buffer_state = state.clone();
+        network.handle().spawn(async move {
+            let mut monitor = interval(Duration::from_millis(500));
+            loop {
+                monitor.tick().await;
+                let mut buf_size = 0;
+                for (peer, packets) in buffered.lock().unwrap().iter() {
+                    let samples = packets.iter().map(|packet| packet.frame_samples).sum();
+                    buffer_state.stats.set_buffered(*peer, samples);
+                    buf_size += samples;
+                }
+                *buffer_state.buffer_size.lock().unwrap() = buf_size;
+            }
+        });
+
+        // Control traffic: reports, pings, chat, file chunks and floor claims
+        network
+            .handle()
+            .spawn(control_task(state.clone(), peers.clone(), net.clone()));
+
+        let mic = start_mic_capture(
+            audio.as_ref(),
+            &net,
+            peers.clone(),
+            MicControls {
+                ptt_enabled: state.ptt_active.clone(),
+                file_playing: state.file_playing.clone(),
+                level: state.mic_level.clone(),
+                volume: state.volume.clone(),
+                floor: state.floor.clone(),
+                codec: state.codec.clone(),
+                output: output.clone(),
+                events: state.events.clone(),
+            },
+        );
+        mic.play().context("Failed to start mic stream")?;
+        streams.push(mic);
+        state
+            .stats
+            .pipeline
+            .input_running
+            .store(true, Ordering::Relaxed);
+        state.events.publish(Event::StreamStarted {
+            stream: Stream::Input,
+        });
+
+        Ok(Self {
+            state,
+            net,
+            peers,
+            audio_buffer,
+            handlers,
+            webrtc,
+            bridge,
+            output,
+            streams,
+            discovery,
+            network,
+        })
+    }
+
+    /// Our address as peers see it.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.discovery.local_addr()
+    }
+
+    /// Stop the audio streams, leave discovery and close the socket.
+    /// Returns once the network tasks have stopped.
+    pub fn shutdown(self) {
+        let Self {
+            state,
+            streams,
+            discovery,
+            network,
+            ..
+        } = self;
+        state.events.publish(Event::SessionEnded);
+
+        // The capture and mixer workers exit once their streams are gone
+        drop(streams);
+        let pipeline = &state.stats.pipeline;
+        pipeline.input_running.store(false, Ordering::Relaxed);
+        pipeline.output_running.store(false, Ordering::Relaxed);
+        state.running.store(false, Ordering::Relaxed);
+
+        drop(discovery);
+        network.shutdown();
+    }
+}
+
+/// Periodic control traffic, one task on the network runtime: receiver
+/// reports and pings, chat retransmission, file chunk pacing and floor claims.
+async fn control_task(state: Arc<AppState>, peers: Peerlist, net: NetSender) {
+    let mut reports = interval(REPORT_INTERVAL);
+    let mut pings = interval(STATS_INTERVAL);
+    let mut chat = interval(Duration::from_millis(100));
+    let mut transfers = interval(PUMP_INTERVAL);
+    let mut floor = interval(Duration::from_millis(20));
+    for timer in [
+        &mut reports,
+        &mut pings,
+        &mut chat,
+        &mut transfers,
+        &mut floor,
+    ] {
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    }
+
+    let mut floor_claim = FloorClaim::default();
+    loop {
+        tokio::select! {
+            _ = reports.tick() => send_reports(&state, &peers, &net),
+            _ = pings.tick() => {
+                // Ping every peer for RTT and record a statistics history sample
+                let peers_list = peers.lock().unwrap().clone();
+                for peer in peers_list {
+                    net.send_control(&ControlMessage::Ping(state.stats.ping(peer)), &[peer]);
+                }
+                state.stats.tick();
+            }
+            _ = chat.tick() => {
+                // Send queued messages and retransmit until every peer acknowledges
+                let peers_list = peers.lock().unwrap().clone();
+                for (message, targets) in state.chat.due(&peers_list) {
+                    net.send_control(&ControlMessage::Chat(message), &targets);
+                }
+            }
+            _ = transfers.tick() => {
+                for (peer, message) in state.transfers.pump() {
+                    net.send_control(&message, &[peer]);
+                }
+            }
+            _ = floor.tick(), if state.floor.enabled() => {
+                let peers_list = peers.lock().unwrap().clone();
+                floor_claim.update(&state, &peers_list, &net);
+            }
+        }
+    }
+}
+
+/// Receiver reports: tell each sender how its stream is arriving.
+fn send_reports(state: &AppState, peers: &Peerlist, net: &NetSender) {
+    let rtp = &state.rtp;
+    for (sender, report) in state.reception.take_reports() {
+        state.stats.on_report(sender, &report);
+        // RTP senders get a standard RTCP RR, native senders a control message
+        match rtp.remote_ssrc(&sender) {
+            Some(ssrc) => net.send_to(rtp.receiver_report(ssrc, &report), &[sender]),
+            None => net.send_control(&ControlMessage::ReceiverReport(report), &[sender]),
+        }
+    }
+
+    // RTCP goes to port+1 on the forward target (no rtcp-mux in plain SDP)
+    if let Some(sr) = rtp.sender_report() {
+        let mut targets = Vec::new();
+        if rtp.enabled() {
+            targets.extend(peers.lock().unwrap().iter().copied());
+        }
+        if let Some(forward) = rtp.forward()
+            && let Some(port) = forward.port().checked_add(1)
+        {
+            targets.push(SocketAddr::new(forward.ip(), port));
+        }
+        net.send_to(sr, &targets);
+    }
+}
+
+/// Floor control: claim the floor on PTT press, keep the claim fresh,
+/// release on PTT release. File playback holds the floor like a held PTT.
+struct FloorClaim {
+    was_pressed: bool,
+    last_claim: Instant,
+}
+
+impl Default for FloorClaim {
+    fn default() -> Self {
+        Self {
+            was_pressed: false,
+            last_claim: Instant::now(),
+        }
+    }
+}
+
+impl FloorClaim {
+    fn update(&mut self, state: &AppState, peers: &[SocketAddr], net: &NetSender) {
+        let floor = &state.floor;
+        let pressed =
+            state.ptt_active.load(Ordering::Relaxed) || state.file_playing.load(Ordering::Relaxed);
+        let was_pressed = self.was_pressed;
+
+        if pressed && !floor.holding() {
+            // Fresh press, or still held while waiting for a busy floor to free up
+            let waiting = was_pressed && floor.busy();
+            if (!was_pressed || waiting) && floor.acquire() {
+                net.send_control(&ControlMessage::FloorTaken, peers);
+                self.last_claim = Instant::now();
+                state.events.publish(Event::FloorTaken);
+            } else if !was_pressed && let Some(holder) = floor.remote_holder() {
+                state.events.publish(Event::ChannelBusy {
+                    holder: state.peer_name(&holder),
+                });
+            }
+        } else if pressed && self.last_claim.elapsed() >= FLOOR_REFRESH {
+            net.send_control(&ControlMessage::FloorTaken, peers);
+            self.last_claim = Instant::now();
+        } else if !pressed && was_pressed && floor.release() {
+            net.send_control(&ControlMessage::FloorReleased, peers);
+        }
+
+        self.was_pressed = pressed;
+    }
+}
@@ -0,0 +1,550 @@
+//! The embedding API: build a [`Session`] from an identity, a transport,
+//! discovery, audio I/O and codec settings, then drive it through the
+//! [`Ptt`], [`Mute`] and [`Volume`] handles and follow its event stream.
+//! The TUI is one front-end on top of this.
+
+use crate::audio::backend::{AudioBackend, CpalBackend};
+use crate::audio::codec::CodecConfig;
+use crate::audio::file::{load_wav, start_file_playback};
+use crate::audio::volume::clamp_gain;
+use crate::config::{ConfigStore, PeerConfig};
+use crate::event::{Event, PttSource, Record, log_events};
+use crate::history::record_history;
+use crate::network::discovery::Discovery;
+use crate::network::mdns::{DEFAULT_CHANNEL, Data, peer_identity};
+use crate::network::protocol::ControlMessage;
+use crate::network::stats::STATS_INTERVAL;
+use crate::network::transfer::{Direction as TransferDirection, TransferState};
+use crate::network::udp::bind_socket;
+use crate::node::{Node, NodeOptions};
+use crate::state::AppState;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread::{JoinHandle, sleep, spawn};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// A peer's loss over one report interval worth an [`Event::LossSpike`];
+/// the next one is reported once loss fell below half of this.
+const LOSS_SPIKE_PCT: f32 = 10.0;
+
+/// Where the session's datagrams go in and out.
+enum Transport {
+    /// Bind this UDP port on all interfaces.
+    Port(u16),
+    Socket(UdpSocket),
+}
+
+/// Collects everything a [`Session`] needs; anything not given gets the
+/// same default as the `vl` binary.
+pub struct SessionBuilder {
+    options: NodeOptions,
+    transport: Transport,
+    discovery: Option<Box<dyn Discovery>>,
+    audio: Option<Arc<dyn AudioBackend>>,
+    history: Option<PathBuf>,
+}
+
+impl SessionBuilder {
+    /// Join `channel` instead of the default one.
+    pub fn with_channel(mut self, channel: &str) -> Self {
+        self.options.channel = channel.to_string();
+        self
+    }
+
+    /// Listen on `port` (0 picks a free one).
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.transport = Transport::Port(port);
+        self
+    }
+
+    /// Use an already bound socket.
+    pub fn with_socket(mut self, socket: UdpSocket) -> Self {
+        self.transport = Transport::Socket(socket);
+        self
+    }
+
+    /// Find peers some other way than mDNS.
+    pub fn with_discovery(mut self, discovery: Box<dyn Discovery>) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    /// Play and capture somewhere other than the default sound card.
+    pub fn with_audio(mut self, audio: Arc<dyn AudioBackend>) -> Self {
+        self.audio = Some(audio);
+        self
+    }
+
+    pub fn with_codec(mut self, codec: CodecConfig) -> Self {
+        self.options.codec = codec;
+        self
+    }
+
+    /// Settings and persisted volumes, e.g. from [`ConfigStore::open`].
+    /// The codec settings are taken from it too.
+    pub fn with_config(mut self, config: Arc<ConfigStore>) -> Self {
+        self.options.codec = config.get().codec;
+        self.options.config = config;
+        self
+    }
+
+    /// Everything else: RTP, WebRTC, floor control, impairments and so on.
+    pub fn with_options(mut self, configure: impl FnOnce(&mut NodeOptions)) -> Self {
+        configure(&mut self.options);
+        self
+    }
+
+    /// Keep the channel history in `dir` (the `vl` binary uses
+    /// [`History::default_dir`](crate::history::History::default_dir)); off by default.
+    pub fn with_history(mut self, dir: PathBuf) -> Self {
+        self.history = Some(dir);
+        self
+    }
+
+    /// Bind, announce and start the audio streams.
+    pub fn start(self) -> Result<Session> {
+        let options = self.options;
+        options.codec.validate()?;
+
+        let socket = match self.transport {
+            Transport::Port(port) => bind_socket(port, &options.config.get().network)
+                .context("Failed to bind UDP socket")?,
+            Transport::Socket(socket) => socket,
+        };
+        let discovery = match self.discovery {
+            Some(discovery) => discovery,
+            None => {
+                let port = socket.local_addr()?.port();
+                Box::new(Data::new(&options.name, port, &options.channel))
+            }
+        };
+        let audio = self.audio.unwrap_or_else(|| Arc::new(CpalBackend));
+
+        let node = Node::start(socket, discovery, audio, options)?;
+        log_events(&node.state.events);
+        let history = self
+            .history
+            .map(|dir| record_history(&node.state.events, dir));
+        save_volume_changes(&node);
+        watch_peers(&node);
+        watch_health(&node);
+        watch_talkers(&node);
+        watch_transfers(&node);
+        if node.handlers.webrtc.is_some() {
+            watch_webrtc(&node);
+        }
+        run_file_playback(&node);
+
+        Ok(Session { node, history })
+    }
+}
+
+/// A running walkie-talkie.
+pub struct Session {
+    node: Node,
+    history: Option<JoinHandle<()>>,
+}
+
+impl Session {
+    /// A session announced as `name` on the default channel.
+    pub fn builder(name: &str) -> SessionBuilder {
+        SessionBuilder {
+            options: NodeOptions::new(name, DEFAULT_CHANNEL),
+            transport: Transport::Port(0),
+            discovery: None,
+            audio: None,
+            history: None,
+        }
+    }
+
+    /// Everything a front-end displays: peers, levels, statistics, chat.
+    pub fn state(&self) -> &Arc<AppState> {
+        &self.node.state
+    }
+
+    /// The assembled pipeline, for front-ends that need the transport itself.
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.node.local_addr()
+    }
+
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.node.peers.lock().unwrap().clone()
+    }
+
+    pub fn ptt(&self) -> Ptt {
+        Ptt {
+            state: self.node.state.clone(),
+            source: PttSource::Api,
+        }
+    }
+
+    pub fn mute(&self) -> Mute {
+        Mute {
+            state: self.node.state.clone(),
+        }
+    }
+
+    pub fn volume(&self) -> Volume {
+        Volume {
+            state: self.node.state.clone(),
+        }
+    }
+
+    /// Every event from now on.
+    pub fn events(&self) -> broadcast::Receiver<Record> {
+        self.node.state.events.subscribe()
+    }
+
+    /// Stop everything, release the socket and finish writing the history.
+    pub fn shutdown(self) {
+        self.node.state.save_volume();
+        self.node.shutdown();
+        if let Some(history) = self.history {
+            let _ = history.join();
+        }
+    }
+}
+
+/// Push-to-talk. Cloned handles all drive the same transmitter; every
+/// change is published as an [`Event::Ptt`] from the handle's source.
+#[derive(Clone)]
+pub struct Ptt {
+    state: Arc<AppState>,
+    source: PttSource,
+}
+
+impl Ptt {
+    /// Report changes made through this handle as coming from `source`.
+    pub fn with_source(mut self, source: PttSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Start transmitting; false if already transmitting.
+    pub fn press(&self) -> bool {
+        let changed = !self.state.ptt_active.swap(true, Ordering::Relaxed);
+        if changed {
+            self.publish(true);
+        }
+        changed
+    }
+
+    /// Stop transmitting; false if not transmitting.
+    pub fn release(&self) -> bool {
+        let changed = self.state.ptt_active.swap(false, Ordering::Relaxed);
+        if changed {
+            self.publish(false);
+        }
+        changed
+    }
+
+    /// Flip transmit on/off and return the new state.
+    pub fn toggle(&self) -> bool {
+        let active = !self.state.ptt_active.fetch_xor(true, Ordering::Relaxed);
+        self.publish(active);
+        active
+    }
+
+    fn publish(&self, active: bool) {
+        self.state.events.publish(Event::Ptt {
+            active,
+            source: self.source,
+        });
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state.ptt_active.load(Ordering::Relaxed)
+    }
+}
+
+/// Microphone mute, saved to the config like the other volume settings.
+#[derive(Clone)]
+pub struct Mute {
+    state: Arc<AppState>,
+}
+
+impl Mute {
+    pub fn set(&self, muted: bool) {
+        if self.state.volume.mic_muted.swap(muted, Ordering::Relaxed) != muted {
+            self.state.events.publish(Event::MicMuted { muted });
+        }
+        self.state.volume_changed();
+    }
+
+    /// Flip mute and return the new state.
+    pub fn toggle(&self) -> bool {
+        let muted = !self.is_muted();
+        self.set(muted);
+        muted
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.state.volume.mic_muted.load(Ordering::Relaxed)
+    }
+}
+
+/// Output volume, mic gain and per-peer volume/mute/solo. Gains run from
+/// 0.0 to [`MAX_GAIN`](crate::audio::volume::MAX_GAIN); every change is
+/// saved to the config once changes settle.
+#[derive(Clone)]
+pub struct Volume {
+    state: Arc<AppState>,
+}
+
+impl Volume {
+    pub fn master(&self) -> f32 {
+        self.state.volume.master.load()
+    }
+
+    pub fn set_master(&self, gain: f32) {
+        self.state.volume.master.store(clamp_gain(gain));
+        self.state.volume_changed();
+    }
+
+    pub fn mic_gain(&self) -> f32 {
+        self.state.volume.mic_gain.load()
+    }
+
+    pub fn set_mic_gain(&self, gain: f32) {
+        self.state.volume.mic_gain.store(clamp_gain(gain));
+        self.state.volume_changed();
+    }
+
+    /// Change the settings for the peer announced as `identity`.
+    pub fn update_peer(&self, identity: &str, update: impl FnOnce(&mut PeerConfig)) {
+        self.state.volume.update_peer(identity, update);
+        self.state.volume_changed();
+    }
+}
+
+/// Volume changes are written to the config at most this often.
+const VOLUME_SAVE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Write volume changes once they've settled, rather than per key repeat.
+fn save_volume_changes(node: &Node) {
+    let state = node.state.clone();
+    spawn(move || {
+        while state.running.load(Ordering::Relaxed) {
+            sleep(VOLUME_SAVE_INTERVAL);
+            state.save_volume();
+        }
+    });
+}
+
+/// Peers joining and leaving as discovery sees them.
+fn watch_peers(node: &Node) {
+    let state = node.state.clone();
+    let peers = node.peers.clone();
+    spawn(move || {
+        let mut known = HashSet::new();
+        while state.running.load(Ordering::Relaxed) {
+            sleep(Duration::from_secs(1));
+            let current: HashSet<_> = peers.lock().unwrap().iter().copied().collect();
+            let names = state.peer_names.lock().unwrap().clone();
+            for &peer in current.difference(&known) {
+                let name = peer_identity(&names, &peer);
+                state.events.publish(Event::PeerJoined { peer, name });
+            }
+            for &peer in known.difference(&current) {
+                let name = peer_identity(&names, &peer);
+                state.events.publish(Event::PeerLeft { peer, name });
+            }
+            known = current;
+        }
+    });
+}
+
+/// Decode errors and peers whose streams start losing packets.
+fn watch_health(node: &Node) {
+    let state = node.state.clone();
+    spawn(move || {
+        let mut decode_errors = 0;
+        let mut lossy = HashSet::new();
+        while state.running.load(Ordering::Relaxed) {
+            sleep(STATS_INTERVAL);
+            let errors = state.stats.pipeline.decode_errors.load(Ordering::Relaxed);
+            if errors > decode_errors {
+                state.events.publish(Event::DecodeErrors {
+                    count: errors - decode_errors,
+                });
+            }
+            decode_errors = errors;
+
+            for peer in state.stats.snapshot() {
+                if peer.loss_pct >= LOSS_SPIKE_PCT && lossy.insert(peer.peer) {
+                    state.events.publish(Event::LossSpike {
+                        peer: peer.peer,
+                        name: state.peer_name(&peer.peer),
+                        loss_pct: peer.loss_pct,
+                    });
+                } else if peer.loss_pct < LOSS_SPIKE_PCT / 2.0 {
+                    lossy.remove(&peer.peer);
+                }
+            }
+        }
+    });
+}
+
+/// Talker indication: log talk spurts per stream.
+fn watch_talkers(node: &Node) {
+    let state = node.state.clone();
+    spawn(move || {
+        let mut talking = HashSet::new();
+        while state.running.load(Ordering::Relaxed) {
+            sleep(Duration::from_millis(100));
+            let active: HashSet<_> = state.talkers.active().into_iter().collect();
+            for &peer in active.difference(&talking) {
+                let name = state.peer_name(&peer);
+                state.events.publish(Event::TalkStarted { peer, name });
+            }
+            for &peer in talking.difference(&active) {
+                let name = state.peer_name(&peer);
+                state.events.publish(Event::TalkStopped { peer, name });
+            }
+            talking = active;
+        }
+    });
+}
+
+/// File transfer offers and results in the events log.
+fn watch_transfers(node: &Node) {
+    let state = node.state.clone();
+    spawn(move || {
+        let mut known = HashMap::new();
+        while state.running.load(Ordering::Relaxed) {
+            sleep(Duration::from_millis(200));
+            for transfer in state.transfers.statuses() {
+                if known.get(&transfer.id) == Some(&transfer.state) {
+                    continue;
+                }
+                let peer = state.peer_name(&transfer.peer);
+                let file = transfer.name.clone();
+                let incoming = transfer.direction == TransferDirection::Download;
+                match &transfer.state {
+                    TransferState::Offered if incoming => {
+                        state.events.publish(Event::TransferOffered {
+                            peer,
+                            file,
+                            size: transfer.size,
+                        })
+                    }
+                    TransferState::Done => state.events.publish(Event::TransferDone {
+                        peer,
+                        file,
+                        incoming,
+                    }),
+                    TransferState::Failed(reason) => state.events.publish(Event::TransferFailed {
+                        file,
+                        reason: reason.clone(),
+                    }),
+                    _ => {}
+                }
+                known.insert(transfer.id, transfer.state);
+            }
+        }
+    });
+}
+
+/// Log WebRTC links coming up and going down.
+fn watch_webrtc(node: &Node) {
+    let state = node.state.clone();
+    let Some(webrtc) = node.handlers.webrtc.clone() else {
+        return;
+    };
+    spawn(move || {
+        let mut linked = HashSet::new();
+        while state.running.load(Ordering::Relaxed) {
+            sleep(Duration::from_secs(1));
+            let connected = webrtc.connected_peers();
+            for &peer in connected.difference(&linked) {
+                let name = state.peer_name(&peer);
+                state.events.publish(Event::WebRtcUp { peer, name });
+            }
+            for &peer in linked.difference(&connected) {
+                let name = state.peer_name(&peer);
+                state.events.publish(Event::WebRtcDown { peer, name });
+            }
+            linked = connected;
+        }
+    });
+}
+
+/// File playback: started from the `play` command and re-triggered with
+/// `play_requested` (the TUI's 'P').
+fn run_file_playback(node: &Node) {
+    let state = node.state.clone();
+    let peers = node.peers.clone();
+    let net = node.net.clone();
+    let output = node.output.clone();
+    if state.play_file.is_some() {
+        state.play_requested.store(true, Ordering::Relaxed);
+    }
+    spawn(move || {
+        while state.running.load(Ordering::Relaxed) {
+            sleep(Duration::from_millis(100));
+
+            if !state.play_requested.load(Ordering::Relaxed)
+                || state.file_playing.load(Ordering::Relaxed)
+                || peers.lock().unwrap().is_empty()
+            {
+                continue;
+            }
+            state.play_requested.store(false, Ordering::Relaxed);
+
+            let Some(path) = state.play_file.as_ref() else {
+                continue;
+            };
+
+            let samples = match load_wav(path) {
+                Ok(samples) => samples,
+                Err(e) => {
+                    state
+                        .events
+                        .publish(Event::failure(format!("Cannot play file: {:#}", e)));
+                    continue;
+                }
+            };
+
+            // Playback talks like PTT: it needs the floor, which the control
+            // task keeps refreshed and releases once the file ends
+            let floor = &state.floor;
+            if !floor.holding() {
+                if let Some(holder) = floor.remote_holder() {
+                    state.events.publish(Event::ChannelBusy {
+                        holder: state.peer_name(&holder),
+                    });
+                    continue;
+                }
+                if floor.enabled() && floor.acquire() {
+                    let peers_list = peers.lock().unwrap().clone();
+                    net.send_control(&ControlMessage::FloorTaken, &peers_list);
+                    state.events.publish(Event::FloorTaken);
+                }
+            }
+
+            state
+                .events
+                .publish(Event::PlaybackStarted { path: path.clone() });
+            let playback = start_file_playback(
+                &net,
+                peers.clone(),
+                samples,
+                state.file_playing.clone(),
+                state.floor.clone(),
+                state.codec.clone(),
+                output.clone(),
+            );
+            let _ = playback.join();
+            state.events.publish(Event::PlaybackFinished);
+        }
+    });
+}
@@ -0,0 +1,205 @@
+//! Shared state of a running node: the peer list, audio levels and controls,
+//! network statistics, chat and transfers. The pipeline writes it, front-ends
+//! (the TUI, the web UI, metrics) read it.
+
+use crate::audio::adapt::RateController;
+use crate::audio::codec::{CodecConfig, EncoderControl};
+use crate::audio::meter::{LevelMeter, PeerLevels};
+use crate::audio::volume::VolumeControls;
+use crate::config::ConfigStore;
+use crate::event::{Event, EventBus};
+use crate::network::chat::Chat;
+use crate::network::floor::{FloorControl, Talkers};
+use crate::network::mdns::{DEFAULT_CHANNEL, PeerNames, peer_identity};
+use crate::network::report::ReceptionStats;
+use crate::network::rtp::RtpSession;
+use crate::network::stats::SessionStats;
+use crate::network::transfer::Transfers;
+use crate::network::udp::DscpMarking;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub struct AppState {
+    pub instance_name: String,
+    pub local_ip: String,
+    pub port: u16,
+    pub peers: Arc<Mutex<Vec<SocketAddr>>>,
+    pub buffer_size: Arc<Mutex<usize>>,
+    pub ptt_active: Arc<AtomicBool>,
+    pub events: EventBus,
+    pub running: Arc<AtomicBool>,
+    pub play_file: Option<PathBuf>,
+    pub play_requested: Arc<AtomicBool>,
+    pub file_playing: Arc<AtomicBool>,
+    pub mic_level: Arc<LevelMeter>,
+    pub speaker_level: Arc<LevelMeter>,
+    pub peer_levels: PeerLevels,
+    pub peer_names: PeerNames,
+    pub volume: Arc<VolumeControls>,
+    pub config: Arc<ConfigStore>,
+    pub talkers: Arc<Talkers>,
+    pub floor: Arc<FloorControl>,
+    pub codec: CodecConfig,
+    pub encoder: Arc<EncoderControl>,
+    pub rate: Arc<RateController>,
+    pub reception: Arc<ReceptionStats>,
+    pub rtp: Arc<RtpSession>,
+    pub channel: String,
+    pub chat: Arc<Chat>,
+    pub transfers: Arc<Transfers>,
+    pub stats: Arc<SessionStats>,
+    /// DSCP marking in effect on the socket.
+    pub marking: Arc<DscpMarking>,
+    /// Volume settings changed since they were last written to the config.
+    volume_dirty: AtomicBool,
+}
+
+impl AppState {
+    pub fn new(
+        instance_name: String,
+        local_ip: String,
+        port: u16,
+        peers: Arc<Mutex<Vec<SocketAddr>>>,
+        play_file: Option<PathBuf>,
+        config: Arc<ConfigStore>,
+        floor: Arc<FloorControl>,
+    ) -> Self {
+        let volume = Arc::new(VolumeControls::from_config(&config.get()));
+        let codec = config.get().codec;
+        let encoder = Arc::new(EncoderControl::new(&codec));
+        let rate = Arc::new(RateController::new(
+            codec.adaptive,
+            encoder.clone(),
+            codec.frame_ms,
+        ));
+
+        let chat = Arc::new(Chat::new(instance_name.clone(), None));
+        let marking = Arc::new(DscpMarking::new(&config.get().network));
+
+        Self {
+            instance_name,
+            local_ip,
+            port,
+            peers,
+            buffer_size: Arc::new(Mutex::new(0)),
+            ptt_active: Arc::new(AtomicBool::new(false)),
+            events: EventBus::new(),
+            running: Arc::new(AtomicBool::new(true)),
+            play_file,
+            play_requested: Arc::new(AtomicBool::new(false)),
+            file_playing: Arc::new(AtomicBool::new(false)),
+            mic_level: Arc::new(LevelMeter::new()),
+            speaker_level: Arc::new(LevelMeter::new()),
+            peer_levels: Arc::new(Mutex::new(HashMap::new())),
+            peer_names: Arc::new(Mutex::new(HashMap::new())),
+            volume,
+            config,
+            talkers: Arc::new(Talkers::new()),
+            floor,
+            codec,
+            encoder,
+            rate,
+            reception: Arc::new(ReceptionStats::new()),
+            rtp: Arc::new(RtpSession::new(false, None)),
+            channel: DEFAULT_CHANNEL.to_string(),
+            chat,
+            transfers: Arc::new(Transfers::new(None)),
+            stats: Arc::new(SessionStats::new()),
+            marking,
+            volume_dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Use codec settings other than the config file's (CLI overrides).
+    pub fn with_codec(mut self, codec: CodecConfig) -> Self {
+        self.encoder = Arc::new(EncoderControl::new(&codec));
+        self.rate = Arc::new(RateController::new(
+            codec.adaptive,
+            self.encoder.clone(),
+            codec.frame_ms,
+        ));
+        self.codec = codec;
+        self
+    }
+
+    /// Send RTP instead of the native format and/or forward our stream.
+    pub fn with_rtp(mut self, rtp: RtpSession) -> Self {
+        self.rtp = Arc::new(rtp);
+        self
+    }
+
+    /// Join `channel`.
+    pub fn with_channel(mut self, channel: String) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Keep the chat log in `path` (the `vl` binary uses
+    /// [`Chat::default_history_path`]); in memory only by default.
+    pub fn with_chat_history(mut self, path: PathBuf) -> Self {
+        self.chat = Arc::new(Chat::new(self.instance_name.clone(), Some(path)));
+        self
+    }
+
+    /// Show the marking of the socket actually sending our traffic.
+    pub fn with_marking(mut self, marking: Arc<DscpMarking>) -> Self {
+        self.marking = marking;
+        self
+    }
+
+    /// Accept file offers, saving received files into `dir`.
+    pub fn with_download_dir(mut self, dir: PathBuf) -> Self {
+        self.transfers = Arc::new(Transfers::new(Some(dir)));
+        self
+    }
+
+    /// Display name for a peer address.
+    pub fn peer_name(&self, peer: &SocketAddr) -> String {
+        peer_identity(&self.peer_names.lock().unwrap(), peer)
+    }
+
+    /// Identity (instance name) of the peer at `index` in the peer list.
+    pub fn peer_identity_at(&self, index: usize) -> Option<String> {
+        let peer = *self.peers.lock().unwrap().get(index)?;
+        Some(peer_identity(&self.peer_names.lock().unwrap(), &peer))
+    }
+
+    /// Write the session statistics to a timestamped file in the data directory.
+    pub fn export_stats(&self) {
+        let Some(path) = SessionStats::default_export_path() else {
+            self.events
+                .publish(Event::failure("No data directory to export statistics to"));
+            return;
+        };
+        match self.stats.export(&path, |peer| self.peer_name(peer)) {
+            Ok(()) => self.events.publish(Event::notice(format!(
+                "📊 Exported statistics to {}",
+                path.display()
+            ))),
+            Err(e) => self.events.publish(Event::failure(format!(
+                "Failed to export statistics: {:#}",
+                e
+            ))),
+        }
+    }
+
+    /// Note a volume change; [`AppState::save_volume`] writes it out later,
+    /// so a held key doesn't rewrite the config on every repeat.
+    pub fn volume_changed(&self) {
+        self.volume_dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Persist the volume settings to the config file if they changed.
+    pub fn save_volume(&self) {
+        if !self.volume_dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = self.config.update(|config| self.volume.write_to(config)) {
+            self.events
+                .publish(Event::failure(format!("Failed to save config: {:#}", e)));
+        }
+    }
+}
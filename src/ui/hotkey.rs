@@ -0,0 +1,82 @@
+//! Global push-to-talk hotkey read straight from `/dev/input`, so PTT works
+//! while the terminal is not focused. Linux only, behind the `global-hotkey`
+//! feature; needs read access to the event devices (usually the `input` group).
+
+use crate::session::Session;
+use anyhow::Result;
+
+#[cfg(all(target_os = "linux", feature = "global-hotkey"))]
+pub fn start_global_ptt(key_name: &str, session: &Session) -> Result<Vec<String>> {
+    use crate::event::{Event, PttSource};
+    use anyhow::{anyhow, bail};
+    use evdev::{InputEventKind, Key};
+    use log::warn;
+    use std::thread::spawn;
+
+    // evdev names, e.g. KEY_RIGHTCTRL, KEY_F13, BTN_SIDE (mouse thumb button)
+    let key: Key = key_name.parse().map_err(|_| {
+        anyhow!("Unknown key '{key_name}' (use evdev names like KEY_RIGHTCTRL or BTN_SIDE)")
+    })?;
+
+    let mut listening = Vec::new();
+    for (path, mut device) in evdev::enumerate() {
+        if !device
+            .supported_keys()
+            .is_some_and(|keys| keys.contains(key))
+        {
+            continue;
+        }
+
+        let name = format!(
+            "{} ({})",
+            device.name().unwrap_or("unnamed device"),
+            path.display()
+        );
+        listening.push(name.clone());
+
+        let state = session.state().clone();
+        let ptt = session.ptt().with_source(PttSource::GlobalHotkey);
+        spawn(move || {
+            loop {
+                let events = match device.fetch_events() {
+                    Ok(events) => events,
+                    Err(e) => {
+                        warn!("Global PTT device {} failed: {}", name, e);
+                        state
+                            .events
+                            .publish(Event::failure(format!("Global PTT lost {}: {}", name, e)));
+                        return;
+                    }
+                };
+
+                for event in events {
+                    if event.kind() != InputEventKind::Key(key) {
+                        continue;
+                    }
+
+                    // 1 = press, 0 = release, 2 = autorepeat
+                    match event.value() {
+                        1 => {
+                            ptt.press();
+                        }
+                        0 => {
+                            ptt.release();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        });
+    }
+
+    if listening.is_empty() {
+        bail!("No readable input device reports {key_name} - is this user in the 'input' group?");
+    }
+
+    Ok(listening)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "global-hotkey")))]
+pub fn start_global_ptt(_key_name: &str, _session: &Session) -> Result<Vec<String>> {
+    anyhow::bail!("Global PTT hotkey needs Linux and a build with `--features global-hotkey`")
+}
@@ -0,0 +1,270 @@
+//! Prometheus/OpenMetrics text endpoint for headless boxes. It renders the
+//! same statistics as the TUI's statistics panel on every scrape. The
+//! recent events log is served alongside as JSON lines.
+
+use crate::network::http::{read_request, write_response};
+use crate::network::stats::PeerStats;
+use crate::state::AppState;
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::fmt::Write as _;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::spawn;
+use std::time::Duration;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const EVENTS_TYPE: &str = "application/x-ndjson";
+
+/// Serve `GET /metrics` and `GET /events` on `addr`; returns the bound address.
+pub fn start_metrics_server(addr: SocketAddr, state: Arc<AppState>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("Cannot listen on {addr}"))?;
+    let bound = listener.local_addr()?;
+    info!("📈 Metrics on http://{}/metrics", bound);
+
+    spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            // Scrapes are cheap and rare: answer them one at a time
+            if let Err(e) = handle_connection(stream, &state) {
+                debug!("Metrics request failed: {:#}", e);
+            }
+        }
+    });
+
+    Ok(bound)
+}
+
+fn handle_connection(stream: TcpStream, state: &AppState) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let request = read_request(&stream)?;
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            write_response(&stream, "200 OK", CONTENT_TYPE, render(state).as_bytes())
+        }
+        ("GET", "/events") => write_response(
+            &stream,
+            "200 OK",
+            EVENTS_TYPE,
+            render_events(state).as_bytes(),
+        ),
+        _ => write_response(&stream, "404 Not Found", "text/plain", b"Not found\n"),
+    }
+}
+
+/// Recent events, oldest first, one JSON object per line.
+fn render_events(state: &AppState) -> String {
+    let (recent, _) = state.events.subscribe_with_recent();
+    let mut out = String::new();
+    for record in recent {
+        if let Ok(line) = serde_json::to_string(&record) {
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// One metric family: `# HELP`/`# TYPE` then its samples.
+struct Family<'a> {
+    out: &'a mut String,
+}
+
+impl<'a> Family<'a> {
+    fn new(out: &'a mut String, name: &str, kind: &str, help: &str) -> Self {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        Self { out }
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) -> &mut Self {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| format!("{key}=\"{}\"", escape(value)))
+            .collect();
+        let _ = if labels.is_empty() {
+            writeln!(self.out, "{name} {value}")
+        } else {
+            writeln!(self.out, "{name}{{{}}} {value}", labels.join(","))
+        };
+        self
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    Family::new(out, name, "counter", help).sample(name, &[], value.load(Ordering::Relaxed) as f64);
+}
+
+fn gauge_flag(out: &mut String, name: &str, help: &str, value: &AtomicBool) {
+    let value = value.load(Ordering::Relaxed) as u8 as f64;
+    Family::new(out, name, "gauge", help).sample(name, &[], value);
+}
+
+type PeerValue = fn(&PeerStats) -> Option<f64>;
+
+/// Per-peer families, labelled with the peer's address and name.
+const PEER_METRICS: &[(&str, &str, &str, PeerValue)] = &[
+    (
+        "vl_packets_sent_total",
+        "counter",
+        "Audio packets sent to the peer.",
+        |s| Some(s.packets_sent as f64),
+    ),
+    (
+        "vl_packets_received_total",
+        "counter",
+        "Audio packets received from the peer.",
+        |s| Some(s.packets_received as f64),
+    ),
+    (
+        "vl_bytes_sent_total",
+        "counter",
+        "Opus payload bytes sent to the peer.",
+        |s| Some(s.bytes_sent as f64),
+    ),
+    (
+        "vl_bytes_received_total",
+        "counter",
+        "Opus payload bytes received from the peer.",
+        |s| Some(s.bytes_received as f64),
+    ),
+    (
+        "vl_packets_lost_total",
+        "counter",
+        "Packets of the peer's stream that never arrived.",
+        |s| Some(s.lost as f64),
+    ),
+    (
+        "vl_packets_late_total",
+        "counter",
+        "Packets from the peer that arrived out of order.",
+        |s| Some(s.late as f64),
+    ),
+    (
+        "vl_packets_duplicate_total",
+        "counter",
+        "Packets from the peer that arrived twice.",
+        |s| Some(s.duplicates as f64),
+    ),
+    (
+        "vl_loss_ratio",
+        "gauge",
+        "Loss of the peer's stream over the last report interval.",
+        |s| Some(s.loss_pct as f64 / 100.0),
+    ),
+    (
+        "vl_jitter_seconds",
+        "gauge",
+        "Interarrival jitter of the peer's stream.",
+        |s| Some(s.jitter_ms as f64 / 1000.0),
+    ),
+    (
+        "vl_rtt_seconds",
+        "gauge",
+        "Smoothed round-trip time to the peer.",
+        |s| s.rtt_ms.map(|rtt| rtt as f64 / 1000.0),
+    ),
+    (
+        "vl_receive_bitrate_bps",
+        "gauge",
+        "Current bitrate received from the peer.",
+        |s| Some(s.rx_kbps as f64 * 1000.0),
+    ),
+    (
+        "vl_send_bitrate_bps",
+        "gauge",
+        "Current bitrate sent to the peer.",
+        |s| Some(s.tx_kbps as f64 * 1000.0),
+    ),
+    (
+        "vl_jitter_buffer_seconds",
+        "gauge",
+        "Audio queued for the peer in the jitter buffer.",
+        |s| Some(s.buffer_ms as f64 / 1000.0),
+    ),
+];
+
+/// The whole exposition, rendered from the current statistics.
+fn render(state: &AppState) -> String {
+    let mut out = String::new();
+    let pipeline = &state.stats.pipeline;
+
+    let peers = state.peers.lock().unwrap().len() as f64;
+    Family::new(
+        &mut out,
+        "vl_peers",
+        "gauge",
+        "Peers discovered on the channel.",
+    )
+    .sample("vl_peers", &[], peers);
+    gauge_flag(
+        &mut out,
+        "vl_ptt_active",
+        "Whether push-to-talk is engaged.",
+        &state.ptt_active,
+    );
+    let bitrate = state.encoder.settings().bitrate as f64;
+    Family::new(
+        &mut out,
+        "vl_encoder_bitrate_bps",
+        "gauge",
+        "Current Opus encoder bitrate.",
+    )
+    .sample("vl_encoder_bitrate_bps", &[], bitrate);
+
+    let input = pipeline.input_running.load(Ordering::Relaxed) as u8 as f64;
+    let output = pipeline.output_running.load(Ordering::Relaxed) as u8 as f64;
+    Family::new(
+        &mut out,
+        "vl_audio_device_up",
+        "gauge",
+        "Whether the audio input/output stream is running.",
+    )
+    .sample("vl_audio_device_up", &[("direction", "input")], input)
+    .sample("vl_audio_device_up", &[("direction", "output")], output);
+    counter(
+        &mut out,
+        "vl_audio_underruns_total",
+        "Times a peer's playback ran dry mid-stream.",
+        &pipeline.underruns,
+    );
+    counter(
+        &mut out,
+        "vl_encode_errors_total",
+        "Opus encode failures.",
+        &pipeline.encode_errors,
+    );
+    counter(
+        &mut out,
+        "vl_decode_errors_total",
+        "Opus decode failures.",
+        &pipeline.decode_errors,
+    );
+
+    let peers: Vec<_> = state
+        .stats
+        .snapshot()
+        .into_iter()
+        .map(|stats| (stats.peer.to_string(), state.peer_name(&stats.peer), stats))
+        .collect();
+    for (name, kind, help, value) in PEER_METRICS {
+        let mut family = Family::new(&mut out, name, kind, help);
+        for (address, peer_name, stats) in &peers {
+            if let Some(value) = value(stats) {
+                family.sample(name, &[("peer", address), ("name", peer_name)], value);
+            }
+        }
+    }
+
+    out
+}
@@ -1 +1,4 @@
+pub mod hotkey;
+pub mod metrics;
 pub mod tui;
+pub mod web;
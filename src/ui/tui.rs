@@ -1,7 +1,27 @@
+use crate::audio::codec::BITRATE_STEP;
+use crate::audio::meter::{LevelMeter, meter_fraction, to_dbfs};
+use crate::audio::sad::OPUS_SAMPLE_RATE;
+use crate::audio::volume::GAIN_STEP;
+use crate::event::{Category, Event, PttSource, Record, Severity};
+use crate::network::chat::{Delivery, MAX_CHAT_LEN};
+use crate::network::mdns::peer_identity;
+use crate::network::stats::{PeerStats, StatsSample};
+use crate::network::transfer::{
+    Direction as TransferDirection, TransferState, TransferStatus, format_bytes,
+};
+use crate::network::udp::dscp_name;
+use crate::session::{Mute, Ptt, Session, Volume};
+use crate::state::AppState;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event as TermEvent, KeyCode, KeyEventKind,
+        KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
     execute,
-    terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
+    terminal::{
+        EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode,
+        supports_keyboard_enhancement,
+    },
 };
 use ratatui::{
     Frame, Terminal,
@@ -9,75 +29,97 @@ use ratatui::{
     layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Gauge, List, ListItem, Paragraph, Wrap},
+    widgets::{
+        Block, Borders, Cell, List, ListItem, ListState, Paragraph, Row, Sparkline, Table,
+        TableState, Wrap,
+    },
 };
 use std::{
+    collections::VecDeque,
     io,
     net::SocketAddr,
-    sync::{
-        Arc, Mutex,
-        atomic::{AtomicBool, Ordering},
-    },
+    path::PathBuf,
+    sync::{Arc, atomic::Ordering},
     time::{Duration, Instant},
 };
+use tokio::sync::broadcast;
 
-pub struct AppState {
-    pub instance_name: String,
-    pub local_ip: String,
-    pub port: u16,
-    pub peers: Arc<Mutex<Vec<SocketAddr>>>,
-    pub buffer_size: Arc<Mutex<usize>>,
-    pub ptt_active: Arc<AtomicBool>,
-    pub events: Arc<Mutex<Vec<String>>>,
-    pub running: Arc<AtomicBool>,
+/// How the spacebar drives push-to-talk.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PttMode {
+    /// Hold to talk, using real key release events where the terminal supports them
+    #[default]
+    Auto,
+    /// Hold to talk, guessing the release from a gap in key repeats
+    Heuristic,
+    /// Press once to start talking, press again to stop
+    Toggle,
 }
 
-impl AppState {
-    pub fn new(
-        instance_name: String,
-        local_ip: String,
-        port: u16,
-        peers: Arc<Mutex<Vec<SocketAddr>>>,
-        buffer_size: Arc<Mutex<usize>>,
-    ) -> Self {
-        Self {
-            instance_name,
-            local_ip,
-            port,
-            peers,
-            buffer_size,
-            ptt_active: Arc::new(AtomicBool::new(false)),
-            events: Arc::new(Mutex::new(Vec::new())),
-            running: Arc::new(AtomicBool::new(true)),
-        }
-    }
-
-    pub fn add_event(&self, event: String) {
-        let mut events = self.events.lock().unwrap();
-        events.push(format!(
-            "[{}] {}",
-            chrono::Local::now().format("%H:%M:%S"),
-            event
-        ));
-        if events.len() > 100 {
-            events.remove(0);
-        }
-    }
+/// The PTT behaviour actually in effect once the terminal has been probed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+enum PttInput {
+    /// The terminal reports key release events (keyboard enhancement protocol, Windows)
+    ReleaseEvents,
+    /// Release is assumed when key repeats stop arriving
+    #[default]
+    Heuristic,
+    /// Latching on/off
+    Toggle,
 }
 
-pub fn run_tui(state: Arc<AppState>) -> Result<(), io::Error> {
+/// Without release events, PTT turns off this long after the last key repeat.
+const RELEASE_HEURISTIC: Duration = Duration::from_millis(200);
+
+pub fn run_tui(session: &Session, ptt_mode: PttMode) -> Result<(), io::Error> {
+    let state = session.state().clone();
+
     // Setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+
+    // Windows always reports releases; elsewhere they need the keyboard enhancement protocol
+    let ptt_input = match ptt_mode {
+        PttMode::Toggle => PttInput::Toggle,
+        PttMode::Heuristic => PttInput::Heuristic,
+        PttMode::Auto if cfg!(windows) => PttInput::ReleaseEvents,
+        PttMode::Auto if matches!(supports_keyboard_enhancement(), Ok(true)) => {
+            execute!(
+                stdout,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+            PttInput::ReleaseEvents
+        }
+        PttMode::Auto => PttInput::Heuristic,
+    };
+    let enhanced = ptt_input == PttInput::ReleaseEvents && !cfg!(windows);
+
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    state.add_event("TUI started".to_string());
+    state.events.publish(Event::notice("TUI started"));
+    state.events.publish(Event::notice(match ptt_input {
+        PttInput::ReleaseEvents => "⌨ PTT: hold SPACE (key release events)",
+        PttInput::Heuristic => "⌨ PTT: hold SPACE (release guessed from key repeat)",
+        PttInput::Toggle => "⌨ PTT: SPACE toggles transmit on/off",
+    }));
 
-    let res = run_app(&mut terminal, state);
+    let controls = Controls {
+        ptt: session.ptt().with_source(if ptt_input == PttInput::Toggle {
+            PttSource::Latch
+        } else {
+            PttSource::Spacebar
+        }),
+        mute: session.mute(),
+        volume: session.volume(),
+    };
+    let res = run_app(&mut terminal, state, controls, ptt_input);
 
     // Restore terminal
+    if enhanced {
+        execute!(terminal.backend_mut(), PopKeyboardEnhancementFlags)?;
+    }
     disable_raw_mode()?;
     execute!(
         terminal.backend_mut(),
@@ -93,56 +135,118 @@ pub fn run_tui(state: Arc<AppState>) -> Result<(), io::Error> {
     Ok(())
 }
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, state: Arc<AppState>) -> io::Result<()> {
+/// The session handles the keys drive.
+struct Controls {
+    ptt: Ptt,
+    mute: Mute,
+    volume: Volume,
+}
+
+fn run_app<B: Backend>(
+    terminal: &mut Terminal<B>,
+    state: Arc<AppState>,
+    controls: Controls,
+    ptt_input: PttInput,
+) -> io::Result<()> {
+    let ptt = &controls.ptt;
     let tick_rate = Duration::from_millis(50);
     let mut last_tick = Instant::now();
-    
+
     // Track if spacebar is currently being held down
     let mut spacebar_held = false;
     let mut last_spacebar_press = Instant::now();
 
+    let (recent, mut feed) = state.events.subscribe_with_recent();
+    let mut view = ViewState {
+        ptt_input,
+        events: recent.into(),
+        ..Default::default()
+    };
+
     loop {
-        terminal.draw(|f| ui(f, &state))?;
+        view.receive_events(&mut feed);
+        terminal.draw(|f| ui(f, &state, &mut view))?;
 
         let timeout = tick_rate
             .checked_sub(last_tick.elapsed())
             .unwrap_or_else(|| Duration::from_secs(0));
 
-        if event::poll(timeout)? {
-            if let Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Char('q') | KeyCode::Char('Q') | KeyCode::Esc => {
-                        state.running.store(false, Ordering::Relaxed);
-                        return Ok(());
-                    }
-                    KeyCode::Char(' ') => {
-                        // Only respond to Press events (Release is unreliable on Linux/macOS)
-                        if key.kind == KeyEventKind::Press {
-                            spacebar_held = true;
-                            last_spacebar_press = Instant::now();
-                            
-                            if !state.ptt_active.load(Ordering::Relaxed) {
-                                state.ptt_active.store(true, Ordering::Relaxed);
-                                state.add_event("🔴 PTT ACTIVE - Transmitting (hold spacebar)".to_string());
-                            }
-                        } else if key.kind == KeyEventKind::Release {
-                            // This will work on Windows, but not Linux/macOS
-                            spacebar_held = false;
-                            state.ptt_active.store(false, Ordering::Relaxed);
-                            state.add_event("⚫ PTT OFF - Not transmitting".to_string());
+        if event::poll(timeout)?
+            && let TermEvent::Key(key) = event::read()?
+        {
+            match key.code {
+                // While typing every key goes to the input line
+                _ if view.input.is_some() => {
+                    handle_input_key(&state, &mut view, key.code, key.kind)
+                }
+                // With release events enabled every key also reports Release - ignore those
+                _ if key.kind == KeyEventKind::Release && key.code != KeyCode::Char(' ') => {}
+                KeyCode::Char('q') | KeyCode::Char('Q') | KeyCode::Esc => {
+                    state.running.store(false, Ordering::Relaxed);
+                    return Ok(());
+                }
+                KeyCode::Char('p') | KeyCode::Char('P') if key.kind == KeyEventKind::Press => {
+                    request_file_playback(&state);
+                }
+                KeyCode::Enter if key.kind == KeyEventKind::Press => {
+                    view.input = Some(LineInput {
+                        target: InputTarget::Chat,
+                        text: String::new(),
+                    });
+                }
+                KeyCode::Char('F') if key.kind == KeyEventKind::Press => {
+                    match state.peers.lock().unwrap().get(view.selected_peer) {
+                        Some(&peer) => {
+                            view.input = Some(LineInput {
+                                target: InputTarget::SendFile(peer),
+                                text: String::new(),
+                            })
                         }
+                        None => state
+                            .events
+                            .publish(Event::failure("No peer selected to send a file to")),
                     }
-                    _ => {}
                 }
+                KeyCode::Tab if key.kind == KeyEventKind::Press => {
+                    view.show_stats = !view.show_stats;
+                }
+                KeyCode::Char('E') if key.kind == KeyEventKind::Press => state.export_stats(),
+                KeyCode::Char('L') if key.kind == KeyEventKind::Press => view.cycle_severity(),
+                KeyCode::Char('C') if key.kind == KeyEventKind::Press => view.cycle_category(),
+                KeyCode::Up if view.show_stats => {
+                    view.selected_stats = view.selected_stats.saturating_sub(1);
+                }
+                KeyCode::Down if view.show_stats => view.selected_stats += 1,
+                KeyCode::Char('y') | KeyCode::Char('n') | KeyCode::Char('X')
+                    if key.kind == KeyEventKind::Press =>
+                {
+                    handle_transfer_key(&state, key.code)
+                }
+                KeyCode::Char(' ') if ptt_input == PttInput::Toggle => toggle_ptt(ptt, key.kind),
+                KeyCode::Char(' ') => {
+                    // Repeats count as "still held" for the heuristic
+                    if key.kind == KeyEventKind::Press || key.kind == KeyEventKind::Repeat {
+                        spacebar_held = ptt_input == PttInput::Heuristic;
+                        last_spacebar_press = Instant::now();
+
+                        ptt.press();
+                    } else if key.kind == KeyEventKind::Release {
+                        spacebar_held = false;
+                        ptt.release();
+                    }
+                }
+                // Toggles act once per press; volume and selection keys auto-repeat
+                KeyCode::Char('m') | KeyCode::Char('s') | KeyCode::Char('M')
+                    if key.kind != KeyEventKind::Press => {}
+                code => handle_mixer_key(&state, &controls, &mut view, code),
             }
         }
 
-        // Auto-deactivate PTT if no spacebar press in the last 200ms
-        // This simulates "release" detection for Linux/macOS
-        if spacebar_held && last_spacebar_press.elapsed() > Duration::from_millis(200) {
+        // Fallback when the terminal can't report releases: auto-deactivate PTT
+        // if no spacebar press/repeat arrived in the last 200ms
+        if spacebar_held && last_spacebar_press.elapsed() > RELEASE_HEURISTIC {
             spacebar_held = false;
-            state.ptt_active.store(false, Ordering::Relaxed);
-            state.add_event("⚫ PTT OFF - Auto-deactivated".to_string());
+            ptt.clone().with_source(PttSource::KeyRepeat).release();
         }
 
         if last_tick.elapsed() >= tick_rate {
@@ -151,7 +255,258 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, state: Arc<AppState>) -> io::
     }
 }
 
-fn ui(f: &mut Frame, state: &AppState) {
+/// Editing the input line; ENTER submits, ESC cancels.
+fn handle_input_key(state: &AppState, view: &mut ViewState, code: KeyCode, kind: KeyEventKind) {
+    let Some(input) = view.input.as_mut() else {
+        return;
+    };
+    if kind == KeyEventKind::Release {
+        return;
+    }
+
+    match code {
+        KeyCode::Enter => {
+            let text = input.text.trim().to_string();
+            match input.target {
+                _ if text.is_empty() => {}
+                InputTarget::Chat => state.chat.send(&text),
+                InputTarget::SendFile(peer) => {
+                    let path = PathBuf::from(&text);
+                    if path.is_file() {
+                        state.events.publish(Event::TransferSending {
+                            peer: state.peer_name(&peer),
+                            path: path.clone(),
+                        });
+                        state.transfers.offer(peer, path);
+                    } else {
+                        state
+                            .events
+                            .publish(Event::failure(format!("Not a file: {}", path.display())));
+                    }
+                }
+            }
+            view.input = None;
+        }
+        KeyCode::Esc => view.input = None,
+        KeyCode::Backspace => {
+            input.text.pop();
+        }
+        KeyCode::Char(c) if input.text.chars().count() < MAX_CHAT_LEN => input.text.push(c),
+        _ => {}
+    }
+}
+
+/// Answer the pending file offer (y/n) or cancel every transfer (X).
+fn handle_transfer_key(state: &AppState, code: KeyCode) {
+    if code == KeyCode::Char('X') {
+        state.transfers.cancel_all();
+        state.events.publish(Event::TransfersCancelled);
+        return;
+    }
+
+    let Some(offer) = state.transfers.pending_offer() else {
+        return;
+    };
+    if code == KeyCode::Char('y') {
+        state.transfers.accept(offer.id);
+        if let Some(dir) = state.transfers.download_dir() {
+            state.events.publish(Event::TransferAccepted {
+                file: offer.name,
+                dir: dir.to_path_buf(),
+            });
+        }
+    } else {
+        state.transfers.reject(offer.id);
+        state
+            .events
+            .publish(Event::TransferRejected { file: offer.name });
+    }
+}
+
+/// Latching PTT: each press flips transmit on/off, repeats and releases are ignored.
+fn toggle_ptt(ptt: &Ptt, kind: KeyEventKind) {
+    if kind == KeyEventKind::Press {
+        ptt.toggle();
+    }
+}
+
+/// Peer selection and volume/mute/solo keys; changes are saved to the config.
+fn handle_mixer_key(state: &AppState, controls: &Controls, view: &mut ViewState, code: KeyCode) {
+    let peer_count = state.peers.lock().unwrap().len();
+    let volume = &controls.volume;
+
+    match code {
+        KeyCode::Up => {
+            view.selected_peer = view.selected_peer.saturating_sub(1);
+        }
+        KeyCode::Down => {
+            view.selected_peer = (view.selected_peer + 1).min(peer_count.saturating_sub(1));
+        }
+        KeyCode::Char('+') | KeyCode::Char('=') => volume.set_master(volume.master() + GAIN_STEP),
+        KeyCode::Char('-') => volume.set_master(volume.master() - GAIN_STEP),
+        KeyCode::Char(']') => volume.set_mic_gain(volume.mic_gain() + GAIN_STEP),
+        KeyCode::Char('[') => volume.set_mic_gain(volume.mic_gain() - GAIN_STEP),
+        KeyCode::Char('<') | KeyCode::Char('>') => {
+            let step = if code == KeyCode::Char('>') {
+                BITRATE_STEP
+            } else {
+                -BITRATE_STEP
+            };
+            state
+                .encoder
+                .set_target_bitrate(state.encoder.target_bitrate() + step);
+            state.events.publish(Event::Bitrate {
+                kbps: state.encoder.target_bitrate() / 1000,
+            });
+        }
+        KeyCode::Char('M') => {
+            controls.mute.toggle();
+        }
+        KeyCode::Left | KeyCode::Right | KeyCode::Char('m') | KeyCode::Char('s') => {
+            let Some(identity) = state.peer_identity_at(view.selected_peer) else {
+                return;
+            };
+            volume.update_peer(&identity, |peer| match code {
+                KeyCode::Left => peer.volume -= GAIN_STEP,
+                KeyCode::Right => peer.volume += GAIN_STEP,
+                KeyCode::Char('m') => peer.muted = !peer.muted,
+                _ => peer.solo = !peer.solo,
+            });
+        }
+        _ => {}
+    }
+}
+
+fn request_file_playback(state: &AppState) {
+    match &state.play_file {
+        None => state.events.publish(Event::failure(
+            "No file to play (start with `vl <name> <port> play <file>`)",
+        )),
+        Some(_) if state.file_playing.load(Ordering::Relaxed) => state
+            .events
+            .publish(Event::notice("📢 File playback already in progress")),
+        Some(path) => {
+            state.play_requested.store(true, Ordering::Relaxed);
+            state
+                .events
+                .publish(Event::PlaybackQueued { path: path.clone() });
+        }
+    }
+}
+
+/// Events the log keeps for scrolling back through filters.
+const EVENT_LOG_LEN: usize = 500;
+
+/// How long the peak-hold marker stays before falling back to the live peak.
+const PEAK_HOLD: Duration = Duration::from_millis(1500);
+/// How long the clip light stays lit after a clipped block.
+const CLIP_HOLD: Duration = Duration::from_secs(1);
+
+/// UI-side peak-hold and clip latch for one VU meter.
+#[derive(Default)]
+struct MeterHold {
+    peak: f32,
+    peak_at: Option<Instant>,
+    clip_at: Option<Instant>,
+}
+
+impl MeterHold {
+    fn update(&mut self, meter: &LevelMeter) {
+        let peak = meter.peak();
+        let expired = self.peak_at.is_none_or(|at| at.elapsed() > PEAK_HOLD);
+        if peak >= self.peak || expired {
+            self.peak = peak;
+            self.peak_at = Some(Instant::now());
+        }
+        if meter.take_clipped() {
+            self.clip_at = Some(Instant::now());
+        }
+    }
+
+    fn clipping(&self) -> bool {
+        self.clip_at.is_some_and(|at| at.elapsed() < CLIP_HOLD)
+    }
+}
+
+/// TUI-only state that lives across redraws.
+#[derive(Default)]
+struct ViewState {
+    mic: MeterHold,
+    speaker: MeterHold,
+    /// Index into the peer list for the volume/mute/solo keys.
+    selected_peer: usize,
+    ptt_input: PttInput,
+    /// Line being typed; `None` when not typing.
+    input: Option<LineInput>,
+    /// Statistics panel instead of the normal view.
+    show_stats: bool,
+    /// Row of the statistics table whose history is graphed.
+    selected_stats: usize,
+    /// Events log, oldest first.
+    events: VecDeque<Record>,
+    /// Least severe event shown.
+    min_severity: Severity,
+    /// Only events of this kind, or all of them.
+    category: Option<Category>,
+}
+
+impl ViewState {
+    /// Take in everything published since the last redraw.
+    fn receive_events(&mut self, feed: &mut broadcast::Receiver<Record>) {
+        loop {
+            match feed.try_recv() {
+                Ok(record) => {
+                    self.events.push_back(record);
+                    if self.events.len() > EVENT_LOG_LEN {
+                        self.events.pop_front();
+                    }
+                }
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// 'L': all events, then warnings and errors, then errors only.
+    fn cycle_severity(&mut self) {
+        self.min_severity = match self.min_severity {
+            Severity::Info => Severity::Warning,
+            Severity::Warning => Severity::Error,
+            Severity::Error => Severity::Info,
+        };
+    }
+
+    /// 'C': every category in turn, then all of them again.
+    fn cycle_category(&mut self) {
+        let next = match self.category {
+            None => 0,
+            Some(current) => Category::ALL.iter().position(|c| *c == current).unwrap() + 1,
+        };
+        self.category = Category::ALL.get(next).copied();
+    }
+
+    fn shows(&self, event: &Event) -> bool {
+        event.severity() >= self.min_severity
+            && self
+                .category
+                .is_none_or(|category| event.category() == category)
+    }
+}
+
+/// What the input line is for.
+#[derive(Clone, Copy)]
+enum InputTarget {
+    Chat,
+    /// Path of a file to offer to this peer.
+    SendFile(SocketAddr),
+}
+
+struct LineInput {
+    target: InputTarget,
+    text: String,
+}
+
+fn ui(f: &mut Frame, state: &AppState, view: &mut ViewState) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
@@ -164,6 +519,12 @@ fn ui(f: &mut Frame, state: &AppState) {
     // Header
     render_header(f, chunks[0], state);
 
+    if view.show_stats {
+        render_stats(f, chunks[1], state, view);
+        render_footer(f, chunks[2], view);
+        return;
+    }
+
     // Main content area
     let main_chunks = Layout::default()
         .direction(Direction::Horizontal)
@@ -171,24 +532,39 @@ fn ui(f: &mut Frame, state: &AppState) {
         .split(chunks[1]);
 
     // Left side: Status and Peers
+    let transfers = state.transfers.statuses();
+    let transfers_height = match transfers.len() {
+        0 => 0,
+        n => n as u16 + 3, // borders + offer prompt line
+    };
     let left_chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
-            Constraint::Length(10), // Connection status
-            Constraint::Length(8),  // PTT status
-            Constraint::Min(5),     // Peers
+            Constraint::Length(10),               // Connection status
+            Constraint::Length(7),                // PTT status + level meters
+            Constraint::Min(5),                   // Peers
+            Constraint::Length(transfers_height), // File transfers
         ])
         .split(main_chunks[0]);
 
     render_connection_status(f, left_chunks[0], state);
-    render_ptt_status(f, left_chunks[1], state);
-    render_peers(f, left_chunks[2], state);
+    render_ptt_status(f, left_chunks[1], state, view);
+    render_peers(f, left_chunks[2], state, view);
+    if !transfers.is_empty() {
+        render_transfers(f, left_chunks[3], state, &transfers);
+    }
+
+    // Right side: Chat and events log
+    let right_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(55), Constraint::Percentage(45)])
+        .split(main_chunks[1]);
 
-    // Right side: Events log
-    render_events(f, main_chunks[1], state);
+    render_chat(f, right_chunks[0], state, view);
+    render_events(f, right_chunks[1], view);
 
     // Footer
-    render_footer(f, chunks[2]);
+    render_footer(f, chunks[2], view);
 }
 
 fn render_header(f: &mut Frame, area: Rect, state: &AppState) {
@@ -213,6 +589,15 @@ fn render_header(f: &mut Frame, area: Rect, state: &AppState) {
 fn render_connection_status(f: &mut Frame, area: Rect, state: &AppState) {
     let peers_count = state.peers.lock().unwrap().len();
     let buffer_size = *state.buffer_size.lock().unwrap();
+    let reports = state.rate.reports();
+    let worst_loss = reports
+        .iter()
+        .map(|(_, report)| report.loss())
+        .fold(0.0, f32::max);
+    let worst_jitter = reports
+        .iter()
+        .map(|(_, report)| report.jitter_ms())
+        .fold(0.0, f32::max);
 
     let status_text = vec![
         Line::from(vec![
@@ -245,9 +630,42 @@ fn render_connection_status(f: &mut Frame, area: Rect, state: &AppState) {
             ),
         ]),
         Line::from(vec![
-            Span::styled("Buffer Size: ", Style::default().fg(Color::Gray)),
+            Span::styled("Codec: ", Style::default().fg(Color::Gray)),
             Span::styled(
-                format!("{} samples", buffer_size),
+                format!("Opus {}", state.codec.describe()),
+                Style::default().fg(Color::Cyan),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled("Sending: ", Style::default().fg(Color::Gray)),
+            Span::styled(
+                state.encoder.settings().describe(),
+                Style::default().fg(if worst_loss >= 0.05 {
+                    Color::Red
+                } else {
+                    Color::Cyan
+                }),
+            ),
+            Span::styled(
+                match reports.len() {
+                    0 => String::new(),
+                    _ => format!(
+                        " (peer loss up to {:.1}%, jitter {:.1}ms)",
+                        worst_loss * 100.0,
+                        worst_jitter
+                    ),
+                },
+                Style::default().fg(Color::Gray),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled("Jitter Buffer: ", Style::default().fg(Color::Gray)),
+            Span::styled(
+                format!(
+                    "{:.0} ms ({} samples)",
+                    buffer_size as f32 * 1000.0 / OPUS_SAMPLE_RATE as f32,
+                    buffer_size
+                ),
                 Style::default().fg(if buffer_size > 0 {
                     Color::Green
                 } else {
@@ -268,31 +686,62 @@ fn render_connection_status(f: &mut Frame, area: Rect, state: &AppState) {
     f.render_widget(paragraph, area);
 }
 
-fn render_ptt_status(f: &mut Frame, area: Rect, state: &AppState) {
+fn render_ptt_status(f: &mut Frame, area: Rect, state: &AppState, view: &mut ViewState) {
     let ptt_active = state.ptt_active.load(Ordering::Relaxed);
+    let file_playing = state.file_playing.load(Ordering::Relaxed);
 
     let status_chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Length(3), Constraint::Length(3)])
+        .constraints([Constraint::Length(3), Constraint::Length(4)])
         .split(area);
 
     // PTT indicator
-    let ptt_text = if ptt_active {
-        "🔴 TRANSMITTING"
+    let floor_busy = state.floor.busy();
+    let ptt_text = if file_playing {
+        "📢 PLAYING FILE".to_string()
+    } else if floor_busy {
+        match state.floor.remote_holder() {
+            Some(holder) => format!(
+                "⛔ CHANNEL BUSY - {} has the floor",
+                state.peer_name(&holder)
+            ),
+            None => "⛔ CHANNEL BUSY".to_string(),
+        }
+    } else if ptt_active {
+        "🔴 TRANSMITTING".to_string()
     } else {
-        "⚫ STANDBY"
+        "⚫ STANDBY".to_string()
     };
+    let ptt_title = format!(
+        "🎤 Push-to-Talk ({}{})",
+        if view.ptt_input == PttInput::Toggle {
+            "SPACE toggles"
+        } else {
+            "Hold SPACE"
+        },
+        if state.floor.enabled() {
+            ", half-duplex"
+        } else {
+            ""
+        }
+    );
 
     let ptt_paragraph = Paragraph::new(ptt_text)
         .style(
             Style::default()
-                .fg(if ptt_active { Color::Red } else { Color::Gray })
+                .fg(if floor_busy {
+                    Color::Yellow
+                } else if ptt_active {
+                    Color::Red
+                } else {
+                    Color::Gray
+                })
                 .add_modifier(Modifier::BOLD),
         )
         .alignment(Alignment::Center)
         .block(
             Block::default()
-                .title("🎤 Push-to-Talk (Hold SPACE)")
+                .title(ptt_title)
                 .borders(Borders::ALL)
                 .border_style(Style::default().fg(if ptt_active {
                     Color::Red
@@ -302,68 +751,546 @@ fn render_ptt_status(f: &mut Frame, area: Rect, state: &AppState) {
         );
     f.render_widget(ptt_paragraph, status_chunks[0]);
 
-    // Audio level gauge (simulated)
-    let audio_level = if ptt_active { 100 } else { 0 };
-    let gauge = Gauge::default()
-        .block(
-            Block::default()
-                .title("🔊 Audio Level")
-                .borders(Borders::ALL),
-        )
-        .gauge_style(Style::default().fg(if ptt_active {
-            Color::Green
+    // Live VU meters from the capture callback and the playback mixer
+    view.mic.update(&state.mic_level);
+    view.speaker.update(&state.speaker_level);
+
+    let bar_width = status_chunks[1].width.saturating_sub(2 + 5 + 9 + 5) as usize;
+    let levels = Paragraph::new(vec![
+        meter_line("MIC", bar_width, &state.mic_level, &view.mic),
+        meter_line("SPK", bar_width, &state.speaker_level, &view.speaker),
+    ])
+    .block(
+        Block::default()
+            .title(format!(
+                "🔊 Audio Levels - out {:.0}% | mic {:.0}%{}",
+                state.volume.master.load() * 100.0,
+                state.volume.mic_gain.load() * 100.0,
+                if state.volume.mic_muted.load(Ordering::Relaxed) {
+                    " 🔇 MUTED"
+                } else {
+                    ""
+                }
+            ))
+            .borders(Borders::ALL),
+    );
+    f.render_widget(levels, status_chunks[1]);
+}
+
+fn level_color(fraction: f32) -> Color {
+    if fraction >= 0.9 {
+        Color::Red
+    } else if fraction >= 0.7 {
+        Color::Yellow
+    } else {
+        Color::Green
+    }
+}
+
+/// `MIC ████▒▒··│···  -12.3 dB CLIP` - RMS solid, peak shaded, held peak as a marker.
+fn meter_line(label: &str, width: usize, meter: &LevelMeter, hold: &MeterHold) -> Line<'static> {
+    let cells = |amplitude: f32| (meter_fraction(amplitude) * width as f32).round() as usize;
+    let rms_cells = cells(meter.rms());
+    let peak_cells = cells(meter.peak());
+    let hold_cell = cells(hold.peak);
+
+    let mut spans = vec![Span::styled(
+        format!("{:<4} ", label),
+        Style::default().fg(Color::Gray),
+    )];
+
+    for i in 0..width {
+        let color = level_color(i as f32 / width.max(1) as f32);
+        let (symbol, style) = if i < rms_cells {
+            ("█", Style::default().fg(color))
+        } else if i < peak_cells {
+            ("▒", Style::default().fg(color))
+        } else if hold_cell > 0 && i == hold_cell - 1 {
+            ("│", Style::default().fg(color))
         } else {
-            Color::Gray
-        }))
-        .percent(audio_level);
-    f.render_widget(gauge, status_chunks[1]);
+            ("·", Style::default().fg(Color::DarkGray))
+        };
+        spans.push(Span::styled(symbol, style));
+    }
+
+    spans.push(Span::styled(
+        format!(" {:>5.1} dB", to_dbfs(hold.peak)),
+        Style::default().fg(Color::Gray),
+    ));
+    if hold.clipping() {
+        spans.push(Span::styled(
+            " CLIP",
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ));
+    }
+
+    Line::from(spans)
 }
 
-fn render_peers(f: &mut Frame, area: Rect, state: &AppState) {
+fn render_peers(f: &mut Frame, area: Rect, state: &AppState, view: &mut ViewState) {
     let peers = state.peers.lock().unwrap();
+    let peer_levels = state.peer_levels.lock().unwrap();
+    let peer_names = state.peer_names.lock().unwrap();
     let items: Vec<ListItem> = peers
         .iter()
         .enumerate()
         .map(|(i, peer)| {
+            let identity = peer_identity(&peer_names, peer);
+            let settings = state.volume.peer(&identity);
+            let rms = peer_levels.get(peer).map_or(0.0, |m| m.rms());
+            let talking = state.talkers.is_talking(peer);
+            let bar_cells = (meter_fraction(rms) * 8.0).round() as usize;
+
             ListItem::new(Line::from(vec![
                 Span::styled(format!("{}. ", i + 1), Style::default().fg(Color::Gray)),
-                Span::styled(format!("📱 {}", peer), Style::default().fg(Color::Green)),
+                Span::styled(
+                    format!(
+                        "{} {} ({})",
+                        if talking { "🗣" } else { "📱" },
+                        identity,
+                        peer
+                    ),
+                    if talking {
+                        Style::default()
+                            .fg(Color::Yellow)
+                            .add_modifier(Modifier::BOLD)
+                    } else {
+                        Style::default().fg(Color::Green)
+                    },
+                ),
+                Span::styled(
+                    format!(" {:<8}", "▮".repeat(bar_cells)),
+                    Style::default().fg(level_color(meter_fraction(rms))),
+                ),
+                Span::styled(
+                    format!(" {:>3.0}%", settings.volume * 100.0),
+                    Style::default().fg(Color::Gray),
+                ),
+                Span::styled(
+                    if settings.muted { " [M]" } else { "" },
+                    Style::default().fg(Color::Red),
+                ),
+                Span::styled(
+                    if settings.solo { " [S]" } else { "" },
+                    Style::default().fg(Color::Yellow),
+                ),
+                Span::styled(
+                    if talking { " TALKING" } else { "" },
+                    Style::default()
+                        .fg(Color::Yellow)
+                        .add_modifier(Modifier::BOLD),
+                ),
+            ]))
+        })
+        .collect();
+
+    view.selected_peer = view.selected_peer.min(peers.len().saturating_sub(1));
+    let mut list_state =
+        ListState::default().with_selected((!peers.is_empty()).then_some(view.selected_peer));
+
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title(format!(
+                    "👥 Connected Peers ({}) - ↑↓ select, ←→ volume, m mute, s solo",
+                    peers.len()
+                ))
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::White)),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_stateful_widget(list, area, &mut list_state);
+}
+
+fn render_chat(f: &mut Frame, area: Rect, state: &AppState, view: &ViewState) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(3)])
+        .split(area);
+
+    let entries = state.chat.entries();
+    let items: Vec<ListItem> = entries
+        .iter()
+        .rev()
+        .take(chunks[0].height.saturating_sub(2) as usize)
+        .rev()
+        .map(|entry| {
+            let (mark, mark_color) = match &entry.delivery {
+                Delivery::Done => ("", Color::Gray),
+                Delivery::Sending => (" …", Color::Yellow),
+                Delivery::Delivered => (" ✓", Color::Green),
+                Delivery::Failed(_) => (" ✗", Color::Red),
+            };
+            let sender_color = if entry.outgoing {
+                Color::Green
+            } else {
+                Color::Cyan
+            };
+            ListItem::new(Line::from(vec![
+                Span::styled(
+                    format!("[{}] ", entry.message.time()),
+                    Style::default().fg(Color::Gray),
+                ),
+                Span::styled(
+                    format!("{}: ", entry.message.sender),
+                    Style::default()
+                        .fg(sender_color)
+                        .add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(entry.message.text.clone()),
+                Span::styled(mark, Style::default().fg(mark_color)),
             ]))
         })
         .collect();
 
     let list = List::new(items).block(
         Block::default()
-            .title(format!("👥 Connected Peers ({})", peers.len()))
+            .title(format!("💬 Chat #{}", state.channel))
             .borders(Borders::ALL)
             .border_style(Style::default().fg(Color::White)),
     );
-    f.render_widget(list, area);
+    f.render_widget(list, chunks[0]);
+
+    let (text, style) = match &view.input {
+        Some(LineInput {
+            target: InputTarget::Chat,
+            text,
+        }) => (format!("> {text}█"), Style::default().fg(Color::White)),
+        Some(LineInput {
+            target: InputTarget::SendFile(peer),
+            text,
+        }) => (
+            format!("📁 File for {}: {text}█", state.peer_name(peer)),
+            Style::default().fg(Color::White),
+        ),
+        None => (
+            "ENTER to type a message".to_string(),
+            Style::default().fg(Color::DarkGray),
+        ),
+    };
+    let input = Paragraph::new(text).style(style).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(if view.input.is_some() {
+                Color::Yellow
+            } else {
+                Color::White
+            })),
+    );
+    f.render_widget(input, chunks[1]);
 }
 
-fn render_events(f: &mut Frame, area: Rect, state: &AppState) {
-    let events = state.events.lock().unwrap();
-    let items: Vec<ListItem> = events
+fn render_transfers(f: &mut Frame, area: Rect, state: &AppState, transfers: &[TransferStatus]) {
+    const BAR_WIDTH: usize = 20;
+
+    let prompt = match transfers
         .iter()
-        .rev()
-        .take(area.height.saturating_sub(2) as usize)
-        .rev()
-        .map(|event| ListItem::new(event.as_str()))
+        .find(|t| t.direction == TransferDirection::Download && t.state == TransferState::Offered)
+    {
+        Some(offer) => Line::from(Span::styled(
+            format!(
+                "📁 {} offers {} ({}) - 'y' accept / 'n' reject",
+                state.peer_name(&offer.peer),
+                offer.name,
+                format_bytes(offer.size)
+            ),
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        )),
+        None => Line::from(Span::styled(
+            "'F' send file to selected peer | 'X' cancel all",
+            Style::default().fg(Color::DarkGray),
+        )),
+    };
+
+    let mut lines = vec![prompt];
+    for transfer in transfers {
+        let filled = (transfer.fraction() * BAR_WIDTH as f64).round() as usize;
+        let (status, color) = match &transfer.state {
+            TransferState::Offered => ("waiting".to_string(), Color::Gray),
+            TransferState::Transferring => (
+                format!("{}/s", format_bytes(transfer.rate as u64)),
+                Color::Cyan,
+            ),
+            TransferState::Verifying => ("verifying".to_string(), Color::Cyan),
+            TransferState::Done => ("done ✓".to_string(), Color::Green),
+            TransferState::Failed(reason) => (format!("✗ {reason}"), Color::Red),
+        };
+        let arrow = match transfer.direction {
+            TransferDirection::Upload => "⬆ to",
+            TransferDirection::Download => "⬇ from",
+        };
+        lines.push(Line::from(vec![
+            Span::styled(
+                format!(
+                    "{} {} {} ",
+                    arrow,
+                    state.peer_name(&transfer.peer),
+                    transfer.name
+                ),
+                Style::default().fg(Color::White),
+            ),
+            Span::styled(
+                format!(
+                    "[{}{}] {:>3.0}% ",
+                    "█".repeat(filled),
+                    "░".repeat(BAR_WIDTH - filled),
+                    transfer.fraction() * 100.0
+                ),
+                Style::default().fg(color),
+            ),
+            Span::styled(status, Style::default().fg(color)),
+        ]));
+    }
+
+    let paragraph = Paragraph::new(lines).block(
+        Block::default()
+            .title("📁 File Transfers")
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::White)),
+    );
+    f.render_widget(paragraph, area);
+}
+
+/// Per-peer statistics table, with history sparklines for the selected peer.
+fn render_stats(f: &mut Frame, area: Rect, state: &AppState, view: &mut ViewState) {
+    let stats = state.stats.snapshot();
+    let pipeline = &state.stats.pipeline;
+    let marking = match state.marking.effective() {
+        Some((audio, data)) => format!(
+            "DSCP: audio {} ({}), chat/files {} ({})",
+            dscp_name(audio),
+            audio,
+            dscp_name(data),
+            data
+        ),
+        None => "DSCP: unmarked (refused by the platform)".to_string(),
+    };
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(5), Constraint::Length(10)])
+        .split(area);
+
+    let header = Row::new([
+        "Peer",
+        "Pkts in",
+        "Bytes in",
+        "Pkts out",
+        "Bytes out",
+        "Loss",
+        "Late",
+        "Dup",
+        "Jitter",
+        "RTT",
+        "kbps in",
+        "kbps out",
+        "Buffer",
+    ])
+    .style(
+        Style::default()
+            .fg(Color::Cyan)
+            .add_modifier(Modifier::BOLD),
+    );
+    let rows: Vec<Row> = stats
+        .iter()
+        .map(|peer| {
+            Row::new([
+                Cell::from(state.peer_name(&peer.peer)),
+                Cell::from(peer.packets_received.to_string()),
+                Cell::from(format_bytes(peer.bytes_received)),
+                Cell::from(peer.packets_sent.to_string()),
+                Cell::from(format_bytes(peer.bytes_sent)),
+                Cell::from(format!("{:.1}%", peer.loss_pct)).style(Style::default().fg(
+                    if peer.loss_pct >= 5.0 {
+                        Color::Red
+                    } else {
+                        Color::Green
+                    },
+                )),
+                Cell::from(peer.late.to_string()),
+                Cell::from(peer.duplicates.to_string()),
+                Cell::from(format!("{:.1} ms", peer.jitter_ms)),
+                Cell::from(
+                    peer.rtt_ms
+                        .map_or("-".to_string(), |rtt| format!("{:.1} ms", rtt)),
+                ),
+                Cell::from(format!("{:.1}", peer.rx_kbps)),
+                Cell::from(format!("{:.1}", peer.tx_kbps)),
+                Cell::from(format!("{:.0} ms", peer.buffer_ms)),
+            ])
+        })
         .collect();
 
+    view.selected_stats = view.selected_stats.min(stats.len().saturating_sub(1));
+    let mut table_state =
+        TableState::default().with_selected((!stats.is_empty()).then_some(view.selected_stats));
+    let widths = [
+        Constraint::Min(12),
+        Constraint::Length(8),
+        Constraint::Length(10),
+        Constraint::Length(8),
+        Constraint::Length(10),
+        Constraint::Length(6),
+        Constraint::Length(5),
+        Constraint::Length(5),
+        Constraint::Length(9),
+        Constraint::Length(9),
+        Constraint::Length(8),
+        Constraint::Length(8),
+        Constraint::Length(7),
+    ];
+    let table = Table::new(rows, widths)
+        .header(header)
+        .block(
+            Block::default()
+                .title(format!(
+                    "📊 Session Statistics - underruns {}, encode errors {}, decode errors {} - ↑↓ select, 'E' export, TAB back",
+                    pipeline.underruns.load(Ordering::Relaxed),
+                    pipeline.encode_errors.load(Ordering::Relaxed),
+                    pipeline.decode_errors.load(Ordering::Relaxed)
+                ))
+                .title_bottom(marking)
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::White)),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_stateful_widget(table, chunks[0], &mut table_state);
+
+    let Some(selected) = stats.get(view.selected_stats) else {
+        return;
+    };
+    render_stats_history(f, chunks[1], state, selected);
+}
+
+/// Picks the graphed value out of a history sample.
+type SampleValue = fn(&StatsSample) -> f32;
+
+/// Bitrate, loss, jitter and RTT sparklines for one peer.
+fn render_stats_history(f: &mut Frame, area: Rect, state: &AppState, peer: &PeerStats) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(5), Constraint::Length(5)])
+        .split(area);
+    let cells: Vec<Rect> = rows
+        .iter()
+        .flat_map(|row| {
+            Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .split(*row)
+                .to_vec()
+        })
+        .collect();
+
+    let name = state.peer_name(&peer.peer);
+    let graphs: [(String, Color, SampleValue); 4] = [
+        (
+            format!("{} bitrate in: {:.1} kbps", name, peer.rx_kbps),
+            Color::Green,
+            |sample| sample.rx_kbps,
+        ),
+        (
+            format!("Loss: {:.1}% ({} total)", peer.loss_pct, peer.lost),
+            Color::Red,
+            |sample| sample.loss_pct,
+        ),
+        (
+            format!("Jitter: {:.1} ms", peer.jitter_ms),
+            Color::Yellow,
+            |sample| sample.jitter_ms,
+        ),
+        (
+            format!(
+                "RTT: {}",
+                peer.rtt_ms
+                    .map_or("-".to_string(), |rtt| format!("{:.1} ms", rtt))
+            ),
+            Color::Cyan,
+            |sample| sample.rtt_ms.unwrap_or(0.0),
+        ),
+    ];
+
+    for ((title, color, value), cell) in graphs.into_iter().zip(cells) {
+        // Newest samples on the right; tenths keep small values visible
+        let width = cell.width.saturating_sub(2) as usize;
+        let data: Vec<u64> = peer
+            .history
+            .iter()
+            .skip(peer.history.len().saturating_sub(width))
+            .map(|sample| (value(sample) * 10.0).round() as u64)
+            .collect();
+        let sparkline = Sparkline::default()
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .data(&data)
+            .style(Style::default().fg(color));
+        f.render_widget(sparkline, cell);
+    }
+}
+
+fn render_events(f: &mut Frame, area: Rect, view: &ViewState) {
+    let shown: Vec<&Record> = view
+        .events
+        .iter()
+        .filter(|record| view.shows(&record.event))
+        .collect();
+    let rows = area.height.saturating_sub(2) as usize;
+    let items: Vec<ListItem> = shown[shown.len().saturating_sub(rows)..]
+        .iter()
+        .map(|record| {
+            let style = match record.event.severity() {
+                Severity::Error => Style::default().fg(Color::Red),
+                Severity::Warning => Style::default().fg(Color::Yellow),
+                Severity::Info => Style::default(),
+            };
+            ListItem::new(format!(
+                "[{}] {}",
+                record.at.format("%H:%M:%S"),
+                record.event
+            ))
+            .style(style)
+        })
+        .collect();
+
+    let severity = match view.min_severity {
+        Severity::Info => "all",
+        Severity::Warning => "warnings+",
+        Severity::Error => "errors",
+    };
+    let category = view
+        .category
+        .map_or("everything".to_string(), |category| category.to_string());
     let list = List::new(items).block(
         Block::default()
-            .title("📋 Events Log")
+            .title(format!("📋 Events Log - {}, {}", severity, category))
+            .title_bottom("'L' level | 'C' category")
             .borders(Borders::ALL)
             .border_style(Style::default().fg(Color::White)),
     );
     f.render_widget(list, area);
 }
 
-fn render_footer(f: &mut Frame, area: Rect) {
-    let footer_text = Paragraph::new("HOLD SPACEBAR to transmit | 'Q' or ESC to quit")
-        .style(Style::default().fg(Color::Gray))
-        .alignment(Alignment::Center)
-        .block(Block::default().borders(Borders::ALL));
+fn render_footer(f: &mut Frame, area: Rect, view: &ViewState) {
+    if view.input.is_some() {
+        let footer_text = Paragraph::new("ENTER send | ESC cancel")
+            .style(Style::default().fg(Color::Gray))
+            .alignment(Alignment::Center)
+            .block(Block::default().borders(Borders::ALL));
+        f.render_widget(footer_text, area);
+        return;
+    }
+
+    let ptt_hint = if view.ptt_input == PttInput::Toggle {
+        "SPACE to start/stop transmitting"
+    } else {
+        "HOLD SPACEBAR to transmit"
+    };
+    let footer_text = Paragraph::new(format!(
+        "{} | ENTER chat | 'F' send file | TAB stats | 'P' play file | +/- volume | [/] mic gain | </> bitrate | 'M' mute mic | 'Q' quit",
+        ptt_hint
+    ))
+    .style(Style::default().fg(Color::Gray))
+    .alignment(Alignment::Center)
+    .block(Block::default().borders(Borders::ALL));
     f.render_widget(footer_text, area);
-}
\ No newline at end of file
+}
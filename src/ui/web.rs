@@ -0,0 +1,184 @@
+//! Browser client: an HTTPS page that joins the channel over WebRTC with
+//! push-to-talk. Browsers only allow microphone access on secure origins, so
+//! the server uses a self-signed certificate generated at startup.
+//!
+//! Each browser is bridged in both directions: its audio plays here and is
+//! relayed to native peers from a socket of its own, and it hears a mix of
+//! everyone else (see `audio::bridge`).
+
+use crate::audio::bridge::BrowserBridge;
+use crate::event::Event;
+use crate::network::http::{Request, read_request, write_response};
+use crate::network::protocol;
+use crate::network::udp::{AudioBuffer, RecvHandlers};
+use crate::network::webrtc::{OnAudio, WebRtcTransport};
+use crate::state::AppState;
+use anyhow::{Context, Result, bail};
+use log::{debug, info, warn};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+const CLIENT_PAGE: &str = include_str!("web_client.html");
+
+/// Longest display name a browser may pick.
+const MAX_NAME_LEN: usize = 32;
+
+/// Everything a joining browser gets wired into.
+#[derive(Clone)]
+pub struct WebClients {
+    pub transport: Arc<WebRtcTransport>,
+    pub bridge: Arc<BrowserBridge>,
+    pub handlers: RecvHandlers,
+    pub audio_buffer: AudioBuffer,
+    pub peers: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+/// Serve the browser client on `addr`; returns the bound address.
+pub fn start_web_server(
+    addr: SocketAddr,
+    clients: WebClients,
+    state: Arc<AppState>,
+) -> Result<SocketAddr> {
+    let tls = Arc::new(tls_config(&state.local_ip)?);
+    let listener = TcpListener::bind(addr).with_context(|| format!("Cannot listen on {addr}"))?;
+    let bound = listener.local_addr()?;
+    info!("🌐 Browser client on https://{}", bound);
+
+    let state_for_server = state.clone();
+    let clients_for_server = clients.clone();
+    spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let tls = tls.clone();
+            let clients = clients_for_server.clone();
+            let state = state_for_server.clone();
+            spawn(move || {
+                if let Err(e) = handle_connection(stream, tls, &clients, &state) {
+                    debug!("Web request failed: {:#}", e);
+                }
+            });
+        }
+    });
+
+    // Forget browsers whose tab closed or whose connection failed
+    spawn(move || {
+        loop {
+            sleep(Duration::from_secs(1));
+            for (key, name) in clients.bridge.prune(&clients.transport) {
+                state.peer_names.lock().unwrap().remove(&key);
+                state.events.publish(Event::BrowserLeft { name });
+            }
+        }
+    });
+
+    Ok(bound)
+}
+
+fn tls_config(local_ip: &str) -> Result<ServerConfig> {
+    let names = vec![local_ip.to_string(), "localhost".to_string()];
+    let certified =
+        rcgen::generate_simple_self_signed(names).context("Failed to generate a certificate")?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(vec![certified.cert.der().clone()], key)?;
+    Ok(config)
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    tls: Arc<ServerConfig>,
+    clients: &WebClients,
+    state: &AppState,
+) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut stream = StreamOwned::new(ServerConnection::new(tls)?, stream);
+    let request = read_request(&mut stream)?;
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => write_response(
+            &mut stream,
+            "200 OK",
+            "text/html; charset=utf-8",
+            CLIENT_PAGE.as_bytes(),
+        )?,
+        ("POST", "/offer") => match join(&request, clients, state) {
+            Ok(answer) => {
+                write_response(&mut stream, "200 OK", "application/sdp", answer.as_bytes())?
+            }
+            Err(e) => {
+                warn!("Browser join failed: {:#}", e);
+                let message = format!("{e:#}");
+                write_response(
+                    &mut stream,
+                    "500 Internal Server Error",
+                    "text/plain",
+                    message.as_bytes(),
+                )?
+            }
+        },
+        _ => write_response(&mut stream, "404 Not Found", "text/plain", b"Not found")?,
+    }
+
+    stream.conn.send_close_notify();
+    stream.flush()?;
+    Ok(())
+}
+
+/// Answer a browser's offer and wire it into the channel.
+fn join(request: &Request, clients: &WebClients, state: &AppState) -> Result<String> {
+    let name: String = request
+        .query_param("name")
+        .unwrap_or_default()
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect();
+    if name.is_empty() {
+        bail!("A name is required");
+    }
+    let offer = String::from_utf8(request.body.clone()).context("Offer is not UTF-8")?;
+
+    // The relay socket's address is the browser's identity everywhere: in our
+    // playback mixer, on native peers and in the bridge
+    let ip: IpAddr = state.local_ip.parse()?;
+    let relay = UdpSocket::bind((ip, 0)).context("Failed to bind relay socket")?;
+    let key = relay.local_addr()?;
+
+    let handlers = clients.handlers.clone();
+    let audio_buffer = clients.audio_buffer.clone();
+    let peers = clients.peers.clone();
+    let on_audio: OnAudio = Arc::new(move |header, payload| {
+        handlers.on_audio(&audio_buffer, key, header, payload);
+        // Native peers hear the browser as one more stream
+        let datagram = protocol::encode_audio(header, payload);
+        for peer in peers.lock().unwrap().iter() {
+            let _ = relay.send_to(&datagram, peer);
+        }
+    });
+
+    let track = WebRtcTransport::audio_track();
+    let (answer, pc) = clients
+        .transport
+        .accept_browser(key, offer, track.clone(), on_audio)?;
+    clients.bridge.add_client(key, name.clone(), track, pc)?;
+
+    state
+        .peer_names
+        .lock()
+        .unwrap()
+        .insert(key, format!("web:{name}"));
+    state.events.publish(Event::BrowserJoined { name });
+    Ok(answer)
+}
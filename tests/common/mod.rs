@@ -0,0 +1,123 @@
+//! Virtual peers for end-to-end tests: real nodes on localhost sockets,
+//! found through an in-process registry, with memory sound cards that are
+//! fed tones and whose playback is checked for them.
+
+use std::f32::consts::PI;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use videolan::audio::backend::MemoryBackend;
+use videolan::audio::sad::OPUS_SAMPLE_RATE;
+use videolan::network::discovery::LocalRegistry;
+use videolan::node::NodeOptions;
+use videolan::session::Session;
+
+/// Playback examined when listening for a tone.
+const LISTEN_WINDOW: Duration = Duration::from_millis(500);
+
+/// Normalised level above which a tone counts as heard. A clean tone at
+/// amplitude `a` measures close to `a`; codec noise stays well below this.
+pub const HEARD: f32 = 0.05;
+
+/// Peers that can find each other.
+#[derive(Default)]
+pub struct Network {
+    registry: LocalRegistry,
+}
+
+impl Network {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A peer called `name` on `channel` with default settings.
+    pub fn peer(&self, name: &str, channel: &str) -> Peer {
+        self.peer_with(name, channel, |_| {})
+    }
+
+    pub fn peer_with(
+        &self,
+        name: &str,
+        channel: &str,
+        configure: impl FnOnce(&mut NodeOptions),
+    ) -> Peer {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("Failed to bind test socket");
+        let addr = socket.local_addr().unwrap();
+        let audio = Arc::new(MemoryBackend::new(1));
+        let session = Session::builder(name)
+            .with_channel(channel)
+            .with_socket(socket)
+            .with_discovery(Box::new(self.registry.member(name, channel, addr)))
+            .with_audio(audio.clone())
+            .with_options(configure)
+            .start()
+            .expect("Failed to start session");
+        Peer { session, audio }
+    }
+}
+
+pub struct Peer {
+    pub session: Session,
+    pub audio: Arc<MemoryBackend>,
+}
+
+impl Peer {
+    pub fn addr(&self) -> SocketAddr {
+        self.session.local_addr()
+    }
+
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.session.peers()
+    }
+
+    /// Hold PTT and speak `samples` into the microphone.
+    pub fn talk(&self, samples: &[f32]) {
+        self.audio.push_input(samples);
+        self.session.ptt().press();
+    }
+
+    /// Level of `frequency` in the most recent playback.
+    pub fn hears(&self, frequency: f32) -> f32 {
+        let output = self.audio.output();
+        let window = (OPUS_SAMPLE_RATE as f32 * LISTEN_WINDOW.as_secs_f32()) as usize;
+        tone_level(&output[output.len().saturating_sub(window)..], frequency)
+    }
+}
+
+/// Poll `condition` until it holds or `timeout` passes.
+pub fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        sleep(Duration::from_millis(20));
+    }
+    condition()
+}
+
+/// A mono sine tone at amplitude 0.5.
+pub fn tone(frequency: f32, duration: Duration) -> Vec<f32> {
+    let rate = OPUS_SAMPLE_RATE as f32;
+    let samples = (rate * duration.as_secs_f32()) as usize;
+    (0..samples)
+        .map(|n| 0.5 * (2.0 * PI * frequency * n as f32 / rate).sin())
+        .collect()
+}
+
+/// Amplitude of `frequency` in `samples` (Goertzel).
+pub fn tone_level(samples: &[f32], frequency: f32) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let coeff = 2.0 * (2.0 * PI * frequency / OPUS_SAMPLE_RATE as f32).cos();
+    let (mut s1, mut s2) = (0.0f32, 0.0f32);
+    for &x in samples {
+        let s = x + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s;
+    }
+    let power = (s1 * s1 + s2 * s2 - coeff * s1 * s2).max(0.0);
+    2.0 * power.sqrt() / samples.len() as f32
+}
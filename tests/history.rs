@@ -0,0 +1,68 @@
+//! The channel history as written from session events and read back.
+
+use chrono::{Local, TimeDelta};
+use std::net::SocketAddr;
+use std::thread::sleep;
+use std::time::Duration;
+use videolan::event::{Event, EventBus, PttSource};
+use videolan::history::{Entry, presence, read_history, record_history};
+
+#[test]
+fn talk_spurts_and_peers_are_recorded() {
+    let dir = std::env::temp_dir().join(format!("vl-history-{}", std::process::id()));
+    let bus = EventBus::new();
+    let bravo: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+
+    // Published before recording starts, like a session's first events
+    bus.publish(Event::SessionStarted {
+        name: "alpha".to_string(),
+        channel: "ops".to_string(),
+    });
+    let recording = record_history(&bus, dir.clone());
+
+    bus.publish(Event::PeerJoined {
+        peer: bravo,
+        name: "bravo".to_string(),
+    });
+    bus.publish(Event::TalkStarted {
+        peer: bravo,
+        name: "bravo".to_string(),
+    });
+    sleep(Duration::from_millis(200));
+    bus.publish(Event::TalkStopped {
+        peer: bravo,
+        name: "bravo".to_string(),
+    });
+    bus.publish(Event::Ptt {
+        active: true,
+        source: PttSource::Api,
+    });
+    bus.publish(Event::failure("Speaker unplugged"));
+    // Still talking when the session ends
+    bus.publish(Event::SessionEnded);
+    recording.join().unwrap();
+
+    let today = Local::now().date_naive();
+    let records = read_history(&dir, today, today).unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let kinds: Vec<_> = records.iter().map(|record| &record.entry).collect();
+    assert!(matches!(kinds[0], Entry::SessionStarted { name, .. } if name == "alpha"));
+    assert!(matches!(kinds[1], Entry::PeerJoined { name, .. } if name == "bravo"));
+    assert!(matches!(kinds[2], Entry::Talk { talker, peer: Some(_), .. } if talker == "bravo"));
+    assert!(matches!(kinds[3], Entry::Error { .. }));
+    assert!(matches!(kinds[4], Entry::Talk { talker, peer: None, .. } if talker == "alpha"));
+    assert_eq!(kinds[5], &Entry::SessionEnded);
+
+    let start = records[0].at - TimeDelta::hours(1);
+    let people = presence(&records, start, start + TimeDelta::hours(2));
+    let names: Vec<_> = people.iter().map(|person| person.name.as_str()).collect();
+    assert_eq!(names, ["alpha", "bravo"]);
+    assert_eq!(people[1].peer, Some(bravo));
+    assert_eq!(people[1].spurts, 1);
+    assert!(people[1].talking >= TimeDelta::milliseconds(200));
+    assert!(people[1].is("BRAVO") && people[1].is("127.0.0.1"));
+
+    // Nobody was there before the session
+    assert!(presence(&records, start, start + TimeDelta::minutes(30)).is_empty());
+}
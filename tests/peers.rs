@@ -0,0 +1,141 @@
+//! Several nodes talking to each other on localhost.
+
+mod common;
+
+use common::{HEARD, Network, tone, wait_until};
+use std::net::UdpSocket;
+use std::thread::sleep;
+use std::time::Duration;
+use videolan::event::Event;
+use videolan::network::capture::{Capture, read_capture};
+use videolan::network::protocol::{self, Packet};
+
+const SETTLE: Duration = Duration::from_secs(5);
+const TALK: Duration = Duration::from_secs(10);
+
+#[test]
+fn peers_find_each_other_on_their_channel_only() {
+    let network = Network::new();
+    let a = network.peer("alpha", "ops");
+    let b = network.peer("bravo", "ops");
+    let c = network.peer("charlie", "ops");
+    let d = network.peer("delta", "logistics");
+
+    assert!(wait_until(SETTLE, || a.peers().len() == 2
+        && b.peers().len() == 2
+        && c.peers().len() == 2));
+    assert!(a.peers().contains(&b.addr()) && a.peers().contains(&c.addr()));
+    assert!(d.peers().is_empty());
+    assert_eq!(a.session.state().peer_name(&b.addr()), "bravo");
+}
+
+#[test]
+fn speech_reaches_the_channel_and_nobody_else() {
+    let network = Network::new();
+    let a = network.peer("alpha", "ops");
+    let b = network.peer("bravo", "ops");
+    let c = network.peer("charlie", "ops");
+    let d = network.peer("delta", "logistics");
+    assert!(wait_until(SETTLE, || a.peers().len() == 2));
+
+    a.talk(&tone(440.0, TALK));
+    assert!(wait_until(SETTLE, || b.hears(440.0) > HEARD));
+    assert!(wait_until(SETTLE, || c.hears(440.0) > HEARD));
+    assert!(d.hears(440.0) < HEARD / 5.0);
+    // Nobody hears themselves
+    assert!(a.hears(440.0) < HEARD / 5.0);
+}
+
+#[test]
+fn simultaneous_talkers_are_mixed() {
+    let network = Network::new();
+    let a = network.peer("alpha", "ops");
+    let b = network.peer("bravo", "ops");
+    let c = network.peer("charlie", "ops");
+    assert!(wait_until(SETTLE, || c.peers().len() == 2));
+
+    a.talk(&tone(440.0, TALK));
+    b.talk(&tone(1000.0, TALK));
+    assert!(wait_until(SETTLE, || c.hears(440.0) > HEARD && c.hears(1000.0) > HEARD));
+    assert!(wait_until(SETTLE, || c
+        .session
+        .state()
+        .talkers
+        .active()
+        .len()
+        == 2));
+}
+
+#[test]
+fn webrtc_keeps_audio_off_the_plain_socket() {
+    let capture_path = std::env::temp_dir().join(format!("vl-test-{}.jsonl", std::process::id()));
+
+    let network = Network::new();
+    let a = network.peer_with("alpha", "ops", |options| options.webrtc = true);
+    let capture = Capture::create(&capture_path, 0).unwrap();
+    let b = network.peer_with("bravo", "ops", |options| {
+        options.webrtc = true;
+        options.capture = Some(capture.into());
+    });
+
+    let transport = a.session.node().webrtc.clone().unwrap();
+    assert!(wait_until(Duration::from_secs(20), || transport
+        .connected_peers()
+        .contains(&b.addr())));
+    assert!(transport.udp_peers(&[b.addr()]).is_empty());
+
+    a.talk(&tone(440.0, TALK));
+    assert!(wait_until(SETTLE, || b.hears(440.0) > HEARD));
+
+    // Signalling went over the node's socket, the audio itself did not
+    let (_, records) = read_capture(&capture_path).unwrap();
+    let _ = std::fs::remove_file(&capture_path);
+    assert!(!records.is_empty());
+    assert!(records.iter().all(|record| !matches!(
+        protocol::decode(&record.datagram),
+        Some(Packet::Audio { .. })
+    )));
+}
+
+#[test]
+fn shutdown_releases_the_socket_and_leaves_the_channel() {
+    let network = Network::new();
+    let a = network.peer("alpha", "ops");
+    let b = network.peer("bravo", "ops");
+    assert!(wait_until(SETTLE, || b.peers() == vec![a.addr()]));
+
+    let addr = a.addr();
+    a.session.shutdown();
+
+    UdpSocket::bind(addr).expect("Socket still held after shutdown");
+    assert!(wait_until(SETTLE, || b.peers().is_empty()));
+}
+
+#[test]
+fn session_handles_and_events() {
+    let network = Network::new();
+    let a = network.peer("alpha", "ops");
+    let mut events = a.session.events();
+    let b = network.peer("bravo", "ops");
+
+    assert!(wait_until(SETTLE, || {
+        events.try_recv().is_ok_and(
+            |record| matches!(record.event, Event::PeerJoined { ref name, .. } if name == "bravo"),
+        )
+    }));
+
+    // A muted mic sends silence even with PTT held
+    let mute = a.session.mute();
+    mute.set(true);
+    a.talk(&tone(440.0, TALK));
+    sleep(Duration::from_secs(2));
+    assert!(a.session.ptt().is_active());
+    assert!(b.hears(440.0) < HEARD / 5.0);
+
+    mute.set(false);
+    assert!(wait_until(SETTLE, || b.hears(440.0) > HEARD));
+
+    a.session.ptt().release();
+    b.session.volume().set_master(0.0);
+    assert!(wait_until(SETTLE, || b.hears(440.0) < HEARD / 5.0));
+}